@@ -3,22 +3,36 @@
 
 -- Calculate score for given proxy server and delay
 -- proxy: a table describes the proxy server
--- delay: time in seconds in float
+-- delay: median delay of probes in this round, time in seconds in float
+-- stats: min, median, jitter (in secs), loss (0-1) and samples of probes
+--        in this round; nil if all probes failed
 -- Return a score in signed number or nil
-function calc_score(proxy, delay)
+function calc_score(proxy, delay, stats)
   -- proxy.addr, proxy.proto, proxy.tag:
   --   Basic information about the proxy.
   -- proxy.config:
   --   Proxy's configs, 
-  --   includes test_dns, max_wait, and score_base.
+  --   includes test_dns, max_wait, score_base, tier, probes, probe_aggregate
+  --   and probe_samples.
+  -- proxy.probes:
+  --   Median delay of each probe target in this round, by name.
   -- proxy.traffic:
   --   tx_bytes: total amount of traffics, upload to proxy server
   --   rx_bytes: download from proxy server
   -- proxy.status:
   --   delay: the delay before this update, in secs in float.
   --          nil = initial value; -1 = timed out.
+  --   delay_min, jitter, loss: statistics of probes before this update.
   --   score: the score before this update, may be nil.
   --   conn_alive, conn_total, conn_error: connection counters
+  --   failures: number of consecutive connect or handshake failures
+  --   connect_timeouts, handshake_timeouts, response_timeouts:
+  --     total number of timeouts in each phase of connecting
+  --   timeout_closes, idle_closes, lifetime_closes:
+  --     total number of connections timed out by TCP (keepalive or
+  --     retransmission), closed on idle timeout and max lifetime
+  --     respectively
+  --   breaker: state of circuit breaker, "closed", "open" or "half-open"
   --   close_history:
   --     History of the 64 most recent closed connections, stored as
   --     bitmap in a 64-bit int. 0 for closed without any error, 1 for
@@ -32,7 +46,9 @@ function calc_score(proxy, delay)
     -- disable proxy if delay probing failed
     return nil
   else
-    -- simply use delay in microseconds plus score_base as score
-    return math.floor(delay * 1000 + proxy.config.score_base)
+    -- use delay plus jitter in microseconds plus score_base as score,
+    -- doubled if half of probes were lost
+    local score = (delay + stats.jitter) * 1000 + proxy.config.score_base
+    return math.floor(score * (1 + stats.loss * 2))
   end
 end
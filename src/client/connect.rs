@@ -5,84 +5,174 @@ use std::{
     collections::VecDeque,
     future::Future,
     io::{self, ErrorKind},
-    iter::FromIterator,
     pin::Pin,
+    str::FromStr,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::{net::TcpStream, time::timeout};
+use tokio::time::{delay_for, timeout, Delay, Instant};
 
-use crate::proxy::{Destination, ProxyServer};
+use crate::monitor::ServerList;
+use crate::proxy::{
+    is_server_fault, not_server_fault,
+    stream::{self, AsyncStream, ServerStream},
+    Destination, ProxyServer, TimeoutPhase,
+};
 
 async fn try_connect(
     dest: Destination,
     server: Arc<ProxyServer>,
     pending_data: Option<Bytes>,
     wait_response: bool,
-) -> io::Result<TcpStream> {
-    let max_wait = server.max_wait();
-    // waiting for proxy server connected
-    let mut stream = timeout(max_wait, server.connect(&dest, pending_data)).await??;
+) -> io::Result<ServerStream> {
+    // waiting for proxy server connected & handshaking done
+    let start = Instant::now();
+    let mut stream = server.connect_within(&dest, pending_data).await?;
+    server.record_handshake(start.elapsed());
 
-    // waiting for response data
-    if wait_response {
+    // waiting for response data, the server has done its part so a
+    // failure here is up to the destination
+    if wait_response && !stream.supports_peek() {
+        debug!("skip waiting response from {}: peek unsupported", server);
+    } else if wait_response {
         let mut buf = [0u8; 8];
-        let len = timeout(max_wait, stream.peek(&mut buf)).await??;
+        let len = match timeout(
+            server.response_timeout(),
+            stream::peek(&mut stream, &mut buf),
+        )
+        .await
+        {
+            Ok(result) => result.map_err(|err| not_server_fault(err.kind(), err))?,
+            Err(err) => {
+                server.update_stats_timeout(TimeoutPhase::Response);
+                return Err(not_server_fault(ErrorKind::TimedOut, err));
+            }
+        };
         if len == 0 {
-            return Err(io::Error::new(ErrorKind::UnexpectedEof, "no response data"));
+            return Err(not_server_fault(
+                ErrorKind::UnexpectedEof,
+                "no response data",
+            ));
         }
     }
     Ok(stream)
 }
 
-type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+/// Hedging delays are at least this long, to avoid connecting twice on
+/// every tiny variance of handshake time.
+const MIN_HEDGE_DELAY: Duration = Duration::from_millis(20);
+
+/// When to start connecting the next server while the previous one is
+/// still handshaking.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hedge {
+    /// After a fixed delay.
+    After(Duration),
+    /// After the 90th percentile of recent handshake times of the server,
+    /// or half of its `max_wait` if unknown.
+    Auto,
+}
+
+impl Hedge {
+    fn delay(self, server: &ProxyServer) -> Duration {
+        let delay = match self {
+            Hedge::After(delay) => delay,
+            Hedge::Auto => server
+                .handshake_p90()
+                .unwrap_or_else(|| server.max_wait() / 2),
+        };
+        cmp::max(delay, MIN_HEDGE_DELAY)
+    }
+}
+
+impl FromStr for Hedge {
+    type Err = &'static str;
+
+    /// Either "auto" or milliseconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Hedge::Auto);
+        }
+        let millis = s.parse().or(Err("hedge must be auto or milliseconds"))?;
+        Ok(Hedge::After(Duration::from_millis(millis)))
+    }
+}
+
+type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<ServerStream>> + Send>>;
 
 /// Try to connect one of the proxy servers.
-/// Pick `parallel_n` servers from `queue` to `connecting` and wait for
+/// Pick `parallel_n` servers from `servers` to `connecting` and wait for
 /// connect. Once any of them connected, move that to `reading` and wait
 /// for read respone. Once any of handshakings done, return it and cancel
 /// others.
+/// If `hedge` is set, one more server is picked each time the last picked
+/// one has not done its handshake within the hedging delay.
 pub struct TryConnectAll<'a> {
     dest: &'a Destination,
     pending_data: Option<Bytes>,
     parallel_n: usize,
     wait_response: bool,
-    standby: VecDeque<Arc<ProxyServer>>,
+    hedge: Option<Hedge>,
+    hedge_timer: Option<Delay>,
+    servers: Arc<ServerList>,
+    /// Index of the next server in `servers` to pick.
+    next: usize,
     connects: VecDeque<(Arc<ProxyServer>, PinnedConnectFuture)>,
 }
 
+/// Hedging is disabled if `pending_data` is given, since it would be sent
+/// more than once.
 pub fn try_connect_all(
     dest: &Destination,
-    servers: Vec<Arc<ProxyServer>>,
+    servers: Arc<ServerList>,
     parallel_n: usize,
     wait_response: bool,
     pending_data: Option<Bytes>,
-) -> TryConnectAll {
+    hedge: Option<Hedge>,
+) -> TryConnectAll<'_> {
     let parallel_n = cmp::max(1, parallel_n);
-    let servers = VecDeque::from_iter(servers.into_iter());
+    let hedge = hedge.filter(|_| pending_data.is_none());
     TryConnectAll {
         dest,
         parallel_n,
         pending_data,
         wait_response,
-        standby: servers,
+        hedge,
+        hedge_timer: None,
+        servers,
+        next: 0,
         connects: VecDeque::with_capacity(parallel_n),
     }
 }
 
+impl TryConnectAll<'_> {
+    fn has_standby(&self) -> bool {
+        self.next < self.servers.len()
+    }
+}
+
 impl<'a> Future for TryConnectAll<'a> {
-    type Output = Option<(Arc<ProxyServer>, TcpStream)>;
+    type Output = Option<(Arc<ProxyServer>, ServerStream)>;
 
     fn poll(
         mut self: Pin<&mut Self>,
         cx: &mut Context,
-    ) -> Poll<Option<(Arc<ProxyServer>, TcpStream)>> {
+    ) -> Poll<Option<(Arc<ProxyServer>, ServerStream)>> {
         loop {
             let dest = self.dest.clone();
             // if current connections less than parallel_n,
             // pick servers from queue to connect.
-            while !self.standby.is_empty() && self.connects.len() < self.parallel_n {
-                let server = self.standby.pop_front().unwrap();
+            while self.has_standby() && self.connects.len() < self.parallel_n {
+                let server = self.servers[self.next].clone();
+                self.next += 1;
+                if !server.breaker_allow() {
+                    debug!("skip {}: circuit breaker open", server);
+                    continue;
+                }
+                if let Some(hedge) = self.hedge {
+                    self.hedge_timer = Some(delay_for(hedge.delay(&server)));
+                }
                 let data = self.pending_data.clone();
                 let conn = try_connect(dest.clone(), server.clone(), data, self.wait_response);
                 self.connects.push_back((server, Box::pin(conn)));
@@ -96,23 +186,39 @@ impl<'a> Future for TryConnectAll<'a> {
                     // error, stop trying, drop it.
                     Poll::Ready(Err(e)) => {
                         debug!("connect {} via {} error: {}", dest, server, e);
+                        if is_server_fault(&e) {
+                            server.update_stats_conn_fail();
+                        }
                         drop(self.connects.remove(i));
                     }
                     // not ready, keep here, poll next one.
                     Poll::Pending => i += 1,
                     // ready, return it.
-                    Poll::Ready(Ok(conn)) => return Poll::Ready(Some((server.clone(), conn))),
+                    Poll::Ready(Ok(conn)) => {
+                        server.update_stats_conn_ok();
+                        return Poll::Ready(Some((server.clone(), conn)));
+                    }
                 }
             }
 
             // if all servers failed, return error
-            if self.connects.is_empty() && self.standby.is_empty() {
+            if self.connects.is_empty() && !self.has_standby() {
                 return Poll::Ready(None);
             }
 
             // if not need to connect standby server, wait for events.
-            if self.connects.len() >= self.parallel_n || self.standby.is_empty() {
-                return Poll::Pending;
+            if self.connects.len() >= self.parallel_n || !self.has_standby() {
+                // hedging: pick one more once the timer fired
+                let fired = match self.hedge_timer {
+                    Some(ref mut timer) => Pin::new(timer).poll(cx).is_ready(),
+                    None => false,
+                };
+                if !fired || !self.has_standby() {
+                    return Poll::Pending;
+                }
+                debug!("hedging {} with one more server", dest);
+                self.hedge_timer = None;
+                self.parallel_n += 1;
             }
         }
     }
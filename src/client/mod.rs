@@ -1,13 +1,24 @@
 mod connect;
 mod tls;
+pub use self::connect::Hedge;
 use bytes::{Bytes, BytesMut};
 use log::{debug, info, warn};
 use std::{
-    borrow::Cow, cmp, future::Future, io, net::SocketAddr, pin::Pin, sync::Arc, time::Duration,
+    borrow::Cow,
+    cmp,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{lookup_host, TcpStream},
     time::timeout,
 };
 
@@ -16,46 +27,58 @@ use crate::tcp::{get_original_dest, get_original_dest6};
 use crate::{
     client::connect::try_connect_all,
     client::tls::parse_client_hello,
-    monitor::ServerList,
+    monitor::{ServerList, Snapshot},
     proxy::copy::pipe,
-    proxy::{Address, Destination, ProxyServer},
+    proxy::happy_eyeballs,
+    proxy::loop_guard,
+    proxy::socks5::read_address,
+    proxy::{
+        stream::{AsyncStream, ServerStream},
+        Address, Destination, ProxyServer, TimeoutPhase,
+    },
 };
 
+static INBOUND_TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
-pub struct NewClient {
-    left: TcpStream,
+pub struct NewClient<L = TcpStream> {
+    left: L,
     src: SocketAddr,
     pub dest: Destination,
-    list: ServerList,
+    /// Servers serving `from_port`.
+    list: Arc<ServerList>,
     from_port: u16,
+    hedge: Option<Hedge>,
 }
 
 #[derive(Debug)]
-pub struct NewClientWithData {
-    client: NewClient,
+pub struct NewClientWithData<L = TcpStream> {
+    client: NewClient<L>,
     pending_data: Option<Bytes>,
     has_full_tls_hello: bool,
 }
 
 #[derive(Debug)]
-pub struct ConnectedClient {
-    left: TcpStream,
-    right: TcpStream,
+pub struct ConnectedClient<L = TcpStream, R = ServerStream> {
+    left: L,
+    right: R,
     dest: Destination,
     server: Arc<ProxyServer>,
 }
 
 #[derive(Debug)]
-pub struct FailedClient {
-    left: TcpStream,
+pub struct FailedClient<L = TcpStream> {
+    left: L,
+    src: SocketAddr,
     dest: Destination,
     pending_data: Option<Bytes>,
 }
 
-type ConnectServer = Pin<Box<dyn Future<Output = Result<ConnectedClient, FailedClient>> + Send>>;
+type ConnectServer<L> =
+    Pin<Box<dyn Future<Output = Result<ConnectedClient<L>, FailedClient<L>>> + Send>>;
 
-pub trait Connectable {
-    fn connect_server(self, n_parallel: usize) -> ConnectServer;
+pub trait Connectable<L = TcpStream> {
+    fn connect_server(self, n_parallel: usize) -> ConnectServer<L>;
 }
 
 fn error_invalid_input<T>(msg: &'static str) -> io::Result<T> {
@@ -73,16 +96,91 @@ fn normalize_socket_addr(socket: &SocketAddr) -> Cow<SocketAddr> {
     }
 }
 
+/// Reject connections made by ourselves or to our own listeners.
+/// `target` is the address `src` connected to before any NAT.
+fn check_loop(src: &SocketAddr, target: &SocketAddr, dest: &Destination) -> io::Result<()> {
+    if loop_guard::is_outgoing(src, target) {
+        return loop_detected(src, dest);
+    }
+    check_dest_loop(src, dest)
+}
+
+/// Reject connections from `src` to our own listeners.
+pub(crate) fn check_dest_loop(src: &SocketAddr, dest: &Destination) -> io::Result<()> {
+    match dest.host {
+        Address::Ip(ip) if loop_guard::is_listen_addr(&SocketAddr::new(ip, dest.port)) => {
+            loop_detected(src, dest)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn loop_detected(src: &SocketAddr, dest: &Destination) -> io::Result<()> {
+    if loop_guard::count_loop() {
+        warn!(
+            "{} => {} redirect loop detected, check firewall rules",
+            src, dest
+        );
+    }
+    error_invalid_input("redirect loop detected")
+}
+
+/// Accept a SOCKSv5 CONNECT request, return its destination.
+async fn socks5_handshake(left: &mut TcpStream) -> io::Result<Destination> {
+    // Parse version
+    // TODO: use buffered reader
+    let ver = left.read_u8().await?;
+    if ver != 0x05 {
+        return error_invalid_input("Neither a NATed or SOCKSv5 connection");
+    }
+    // Parse auth methods
+    let n_methods = left.read_u8().await?;
+    let mut buf = vec![0u8; n_methods as usize];
+    left.read_exact(&mut buf).await?;
+    if buf.iter().find(|&&m| m == 0).is_none() {
+        return error_invalid_input("SOCKSv5: No auth is required");
+    }
+    // Select no auth
+    left.write_all(&[0x05, 0x00]).await?;
+    // Parse request
+    buf.resize(3, 0);
+    left.read_exact(&mut buf).await?;
+    if buf[0..2] != [0x05, 0x01] {
+        return error_invalid_input("SOCKSv5: CONNECT is required");
+    }
+    let dest = read_address(left).await?;
+    // Send response
+    left.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(dest)
+}
+
+/// Number of SOCKSv5 clients timed out on handshaking.
+pub fn inbound_timeouts() -> usize {
+    INBOUND_TIMEOUTS.load(Ordering::Relaxed)
+}
+
 impl NewClient {
-    pub async fn from_socket(mut left: TcpStream, list: ServerList) -> io::Result<Self> {
+    /// Accept a NATed or SOCKSv5 client. SOCKSv5 handshaking must be
+    /// done within `inbound_timeout`.
+    pub async fn from_socket(
+        mut left: TcpStream,
+        servers: &Snapshot,
+        inbound_timeout: Duration,
+    ) -> io::Result<Self> {
         let src = left.peer_addr()?;
         let from_port = left.local_addr()?.port();
+        let list = servers.for_port(from_port);
 
-        // Try to get original destination before NAT
+        // Try to get original destination before NAT, it's unknown if
+        // the connection is not tracked.
         #[cfg(target_os = "linux")]
         let dest = get_original_dest(&left)
             .map(SocketAddr::V4)
-            .or_else(|_| get_original_dest6(&left).map(SocketAddr::V6))?;
+            .or_else(|_| get_original_dest6(&left).map(SocketAddr::V6))
+            .or_else(|err| {
+                debug!("no original dest: {}", err);
+                left.local_addr()
+            })?;
 
         // No NAT supported, always be our local address
         #[cfg(not(target_os = "linux"))]
@@ -90,82 +188,80 @@ impl NewClient {
 
         let is_nated = normalize_socket_addr(&dest) != normalize_socket_addr(&left.local_addr()?);
         debug!("local {} dest {}", left.local_addr()?, dest);
+        let target = dest;
         let dest = if cfg!(target_os = "linux") && is_nated {
             dest.into()
         } else {
             // Not a NATed connection, treated as SOCKSv5
-            // Parse version
-            // TODO: add timeout
-            // TODO: use buffered reader
-            let ver = left.read_u8().await?;
-            if ver != 0x05 {
-                return error_invalid_input("Neither a NATed or SOCKSv5 connection");
-            }
-            // Parse auth methods
-            let n_methods = left.read_u8().await?;
-            let mut buf = vec![0u8; n_methods as usize];
-            left.read_exact(&mut buf).await?;
-            if buf.iter().find(|&&m| m == 0).is_none() {
-                return error_invalid_input("SOCKSv5: No auth is required");
-            }
-            // Select no auth
-            left.write_all(&[0x05, 0x00]).await?;
-            // Parse request
-            buf.resize(4, 0);
-            left.read_exact(&mut buf).await?;
-            if buf[0..2] != [0x05, 0x01] {
-                return error_invalid_input("SOCKSv5: CONNECT is required");
-            }
-            let addr: Address = match buf[3] {
-                0x01 => {
-                    // IPv4
-                    let mut buf = [0u8; 4];
-                    left.read_exact(&mut buf).await?;
-                    buf.into()
-                }
-                0x03 => {
-                    // Domain name
-                    let len = left.read_u8().await? as usize;
-                    buf.resize(len, 0);
-                    left.read_exact(&mut buf).await?;
-                    let domain = String::from_utf8(buf).map_err(|_| {
-                        io::Error::new(io::ErrorKind::InvalidInput, "SOCKSv5: Invalid domain name")
-                    })?;
-                    domain.into()
-                }
-                0x04 => {
-                    // IPv6
-                    let mut buf = [0u8; 16];
-                    left.read_exact(&mut buf).await?;
-                    buf.into()
+            match timeout(inbound_timeout, socks5_handshake(&mut left)).await {
+                Ok(dest) => dest?,
+                Err(err) => {
+                    INBOUND_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                    debug!("SOCKSv5 handshake with {} timed out", src);
+                    return Err(err.into());
                 }
-                _ => return error_invalid_input("SOCKSv5: unknown address type"),
-            };
-            let port = left.read_u16().await?;
-            // Send response
-            left.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
-
-            (addr, port).into()
+            }
         };
         debug!("dest {:?}", dest);
+        check_loop(&src, &target, &dest)?;
         Ok(NewClient {
             left,
             src,
             dest,
             list,
             from_port,
+            hedge: None,
         })
     }
 }
 
-impl NewClient {
-    pub async fn retrive_dest(self) -> io::Result<NewClientWithData> {
+impl<L> NewClient<L>
+where
+    L: AsyncStream + Send + 'static,
+{
+    /// Create a client from a stream accepted by other means than
+    /// `from_socket()`, e.g. a custom transport. `list` is used as is,
+    /// except servers not serving `from_port`; take it from
+    /// `Snapshot::for_port()` to have tiers selected and servers pointing
+    /// to moproxy itself skipped.
+    pub fn new<T>(left: L, src: SocketAddr, dest: Destination, list: T, from_port: u16) -> Self
+    where
+        T: Into<Arc<ServerList>>,
+    {
+        let mut list = list.into();
+        if !list.iter().all(|s| s.serve_port(from_port)) {
+            list = Arc::new(
+                list.iter()
+                    .filter(|s| s.serve_port(from_port))
+                    .cloned()
+                    .collect(),
+            );
+        }
+        NewClient {
+            left,
+            src,
+            dest,
+            list,
+            from_port,
+            hedge: None,
+        }
+    }
+
+    /// Start connecting the next server if the current one has not done
+    /// its handshake within the delay, instead of waiting for it to fail.
+    /// Only for clients without pending data.
+    pub fn set_hedge(&mut self, hedge: Option<Hedge>) {
+        self.hedge = hedge;
+    }
+
+    pub async fn retrive_dest(self) -> io::Result<NewClientWithData<L>> {
         let NewClient {
             mut left,
             src,
             mut dest,
             list,
             from_port,
+            hedge,
         } = self;
         let wait = Duration::from_millis(500);
         // try to read TLS ClientHello for
@@ -202,6 +298,7 @@ impl NewClient {
                 dest,
                 list,
                 from_port,
+                hedge,
             },
             has_full_tls_hello,
             pending_data,
@@ -213,20 +310,17 @@ impl NewClient {
         n_parallel: usize,
         wait_response: bool,
         pending_data: Option<Bytes>,
-    ) -> Result<ConnectedClient, FailedClient> {
+    ) -> Result<ConnectedClient<L>, FailedClient<L>> {
         let NewClient {
             left,
             src,
             dest,
             list,
             from_port,
+            hedge,
         } = self;
-        let list = list
-            .iter()
-            .filter(|s| s.serve_port(from_port))
-            .cloned()
-            .collect();
-        let result = try_connect_all(&dest, list, n_parallel, wait_response, pending_data).await;
+        let result =
+            try_connect_all(&dest, list, n_parallel, wait_response, pending_data, hedge).await;
         if let Some((server, right)) = result {
             info!("[:{}] {} => {} via {}", from_port, src, dest, server);
             Ok(ConnectedClient {
@@ -239,6 +333,7 @@ impl NewClient {
             warn!("[:{}] {} => {} no avaiable proxy", from_port, src, dest);
             Err(FailedClient {
                 left,
+                src,
                 dest,
                 pending_data: None,
             })
@@ -246,14 +341,20 @@ impl NewClient {
     }
 }
 
-impl Connectable for NewClient {
-    fn connect_server(self, _n_parallel: usize) -> ConnectServer {
+impl<L> Connectable<L> for NewClient<L>
+where
+    L: AsyncStream + Send + 'static,
+{
+    fn connect_server(self, _n_parallel: usize) -> ConnectServer<L> {
         Box::pin(self.connect_server(1, false, None))
     }
 }
 
-impl Connectable for NewClientWithData {
-    fn connect_server(self, n_parallel: usize) -> ConnectServer {
+impl<L> Connectable<L> for NewClientWithData<L>
+where
+    L: AsyncStream + Send + 'static,
+{
+    fn connect_server(self, n_parallel: usize) -> ConnectServer<L> {
         let NewClientWithData {
             client,
             pending_data,
@@ -268,19 +369,29 @@ impl Connectable for NewClientWithData {
     }
 }
 
-impl FailedClient {
+impl<L: AsyncStream> FailedClient<L> {
     pub async fn direct_connect(
         self,
         pseudo_server: Arc<ProxyServer>,
-    ) -> io::Result<ConnectedClient> {
+    ) -> io::Result<ConnectedClient<L>> {
         let Self {
             left,
+            src,
             dest,
             pending_data,
         } = self;
-        let mut right = match dest.host {
-            Address::Ip(addr) => TcpStream::connect((addr, dest.port)).await?,
-            Address::Domain(ref name) => TcpStream::connect((name.as_ref(), dest.port)).await?,
+        let addrs: Vec<_> = match dest.host {
+            Address::Ip(addr) => vec![SocketAddr::new(addr, dest.port)],
+            Address::Domain(ref name) => lookup_host((name.as_ref(), dest.port)).await?.collect(),
+        };
+        let opts = pseudo_server.socket_options();
+        let connect = happy_eyeballs::connect(&addrs, &opts);
+        let mut right = match timeout(pseudo_server.connect_timeout(), connect).await {
+            Ok(result) => result?,
+            Err(err) => {
+                pseudo_server.update_stats_timeout(TimeoutPhase::Connect);
+                return Err(err.into());
+            }
         };
         debug!("connected with {:?}", right.peer_addr());
         right.set_nodelay(true)?;
@@ -288,13 +399,9 @@ impl FailedClient {
         if let Some(data) = pending_data {
             right.write_all(&data).await?;
         }
+        let right = right.into();
 
-        info!(
-            "{} => {} via {}",
-            left.peer_addr()?,
-            dest,
-            pseudo_server.tag
-        );
+        info!("{} => {} via {}", src, dest, pseudo_server.tag);
         Ok(ConnectedClient {
             left,
             right,
@@ -304,7 +411,22 @@ impl FailedClient {
     }
 }
 
-impl ConnectedClient {
+impl<L, R> ConnectedClient<L, R>
+where
+    L: AsyncStream,
+    R: AsyncStream,
+{
+    /// Create a client that its connection to the proxy server is
+    /// established by other means than `Connectable`.
+    pub fn new(left: L, right: R, dest: Destination, server: Arc<ProxyServer>) -> Self {
+        ConnectedClient {
+            left,
+            right,
+            dest,
+            server,
+        }
+    }
+
     pub async fn serve(self) -> io::Result<()> {
         let ConnectedClient {
             left,
@@ -312,18 +434,23 @@ impl ConnectedClient {
             dest,
             server,
         } = self;
-        // TODO: make keepalive configurable
-        let timeout = Some(Duration::from_secs(180));
-        if let Err(e) = left
-            .set_keepalive(timeout)
-            .and(right.set_keepalive(timeout))
-        {
+        // the server side one is set on connecting
+        if let Err(e) = left.set_keepalive(server.pipe_options().keepalive) {
             warn!("fail to set keepalive: {}", e);
         }
         server.update_stats_conn_open();
-        match pipe(left, right, server.clone()).await {
+        let mut pipe = pipe(left, right, server.clone());
+        let result = (&mut pipe).await;
+        if let Some(reason) = pipe.closed_by() {
+            debug!("{} (=> {}) closed by {:?}", server, dest, reason);
+            server.update_stats_close_reason(reason);
+        }
+        match result {
             Ok(amt) => {
                 server.update_stats_conn_close(false);
+                if amt.rx_bytes > 0 {
+                    server.update_stats_conn_ok();
+                }
                 debug!(
                     "tx {}, rx {} bytes ({} => {})",
                     amt.tx_bytes, amt.rx_bytes, server, dest
@@ -1,5 +1,7 @@
 pub mod client;
 pub mod monitor;
+#[cfg(feature = "mux")]
+pub mod mux;
 pub mod proxy;
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 pub mod systemd;
@@ -1,10 +1,12 @@
 use clap::{load_yaml, AppSettings};
 use futures::{stream, StreamExt};
-use ini::Ini;
+use ini::{ini::Properties, Ini};
 use log::{debug, error, info, warn, LevelFilter};
 use parking_lot::deadlock;
+#[cfg(feature = "mux")]
+use std::fs;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
     io::{self, Write},
     net::SocketAddr,
@@ -21,6 +23,16 @@ use tokio::{
     net::{TcpListener, TcpStream},
 };
 
+#[cfg(feature = "mux")]
+use moproxy::mux::{self, pool::MuxTlsConfig};
+#[cfg(feature = "http2")]
+use moproxy::proxy::http2::Http2Config;
+#[cfg(feature = "ssh")]
+use moproxy::proxy::ssh::SshConfig;
+#[cfg(all(feature = "mux", feature = "tls"))]
+use moproxy::proxy::tls;
+#[cfg(feature = "websocket")]
+use moproxy::proxy::websocket::WebSocketConfig;
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use moproxy::systemd;
 #[cfg(target_os = "linux")]
@@ -28,9 +40,17 @@ use moproxy::tcp::set_congestion;
 #[cfg(feature = "web_console")]
 use moproxy::web;
 use moproxy::{
-    client::{Connectable, NewClient},
-    monitor::{Monitor, ServerList},
-    proxy::{ProxyProto, ProxyServer},
+    client::{Connectable, Hedge, NewClient},
+    monitor::{
+        probe::{Aggregate, ProbeTarget},
+        Monitor, Snapshot,
+    },
+    proxy::{
+        connector::build_connector,
+        loop_guard,
+        sockopt::{Keepalive, SocketOptions},
+        PipeOptions, ProxyProto, ProxyServer, ServerAddr, Timeouts,
+    },
 };
 
 trait FromOptionStr<E, T: FromStr<Err = E>> {
@@ -88,9 +108,12 @@ async fn main() {
         .expect("invalid address");
     let ports: HashSet<u16> = args
         .values_of("port")
-        .expect("missing port number")
-        .map(|p| p.parse().expect("invalid port number"))
-        .collect();
+        .map(|ports| {
+            ports
+                .map(|p| p.parse().expect("invalid port number"))
+                .collect()
+        })
+        .unwrap_or_default();
     let probe = args
         .value_of("probe-secs")
         .expect("missing probe secs")
@@ -102,13 +125,26 @@ async fn main() {
         .parse()
         .expect("not a valid number")
         .unwrap_or(0 as usize);
+    let hedge: Option<Hedge> = args.value_of("hedge").parse().expect("invalid hedge");
     let cong_local = args.value_of("cong-local");
     let allow_direct = args.is_present("allow-direct");
     let graphite = args
         .value_of("graphite")
         .parse()
         .expect("not a valid address");
+    let resolve = args
+        .value_of("resolve-secs")
+        .expect("missing resolve secs")
+        .parse()
+        .expect("not a valid resolve secs");
     let servers_cfg = ServerListCfg::new(&args);
+    let socket_opts = servers_cfg.default_socket.clone();
+    let timeouts = servers_cfg.default_timeouts;
+    let pipe_opts = servers_cfg.default_pipe;
+    let inbound_timeout = args
+        .value_of("inbound-timeout")
+        .map(|s| parse_secs(s).expect("not a valid inbound timeout"))
+        .expect("missing inbound timeout");
     let servers = servers_cfg.load().expect("fail to load servers from file");
 
     #[cfg(feature = "score_script")]
@@ -116,6 +152,18 @@ async fn main() {
     #[cfg(not(feature = "score_script"))]
     let monitor = Monitor::new(servers, graphite);
 
+    let switch_margin = args
+        .value_of("switch-margin")
+        .expect("missing switch margin")
+        .parse()
+        .expect("not a valid percent");
+    let switch_hold = args
+        .value_of("switch-hold")
+        .expect("missing switch hold")
+        .parse()
+        .expect("not a valid switch hold secs");
+    monitor.set_switch_damping(switch_margin, Duration::from_secs(switch_hold));
+
     // Setup score script
     if !cfg!(feature = "score_script") && args.is_present("score-script") {
         panic!("score script has been disabled during compiling");
@@ -163,6 +211,8 @@ async fn main() {
     }
 
     // Setup monitor
+    tokio::spawn(monitor.clone().monitor_resolve(resolve));
+    tokio::spawn(monitor.clone().monitor_warm_pool());
     if probe > 0 {
         tokio::spawn(monitor.clone().monitor_delay(probe));
     }
@@ -206,12 +256,17 @@ async fn main() {
 
     // Optional direct connect
     let direct_server = if allow_direct {
-        Some(Arc::new(ProxyServer::direct(parse_max_wait(&args))))
+        let server = ProxyServer::direct(parse_max_wait(&args));
+        server.set_socket_options(socket_opts.clone());
+        server.set_timeouts(timeouts);
+        server.set_pipe_options(pipe_opts);
+        Some(Arc::new(server))
     } else {
         None
     };
 
     // Setup proxy server
+    monitor.set_listen_ports(ports.iter().cloned().collect());
     let mut listeners = Vec::with_capacity(ports.len());
     if cong_local.is_some() && cfg!(not(target_os = "linux")) {
         panic!("--cong-local can only be used on Linux");
@@ -228,9 +283,77 @@ async fn main() {
                 check tcp_allowed_congestion_control?",
             );
         }
+        loop_guard::add_listen_addr(addr);
         listeners.push(listener);
     }
 
+    // Setup mux server
+    if !cfg!(feature = "mux") && args.is_present("mux-bind") {
+        panic!("mux server has been disabled during compiling");
+    };
+    #[cfg(feature = "mux")]
+    {
+        if let Some(addr) = args.value_of("mux-bind") {
+            let listener = TcpListener::bind(addr)
+                .await
+                .expect("fail to bind mux server");
+            let local_addr = listener.local_addr().expect("fail to get address");
+            let secret = args.value_of("mux-secret").unwrap_or_default();
+            if secret.len() > u8::MAX as usize {
+                panic!("mux secret must be shorter than 256 bytes");
+            }
+            if secret.is_empty()
+                && !local_addr.ip().is_loopback()
+                && !args.is_present("mux-no-secret")
+            {
+                panic!(
+                    "refuse to run mux server on {} without secret, \
+                     set --mux-secret or --mux-no-secret",
+                    local_addr
+                );
+            }
+            if !cfg!(feature = "tls") && args.is_present("mux-tls-cert") {
+                panic!("TLS support disabled during compiling");
+            }
+            if !secret.is_empty()
+                && !local_addr.ip().is_loopback()
+                && !args.is_present("mux-tls-cert")
+            {
+                panic!(
+                    "refuse to accept mux secret in clear text on {}, \
+                     set --mux-tls-cert and --mux-tls-key",
+                    local_addr
+                );
+            }
+            info!("mux server listen on {}", addr);
+            loop_guard::add_listen_addr(local_addr);
+            let server = ProxyServer::direct(parse_max_wait(&args));
+            server.set_socket_options(socket_opts.clone());
+            server.set_timeouts(timeouts);
+            server.set_pipe_options(pipe_opts);
+            let server = Arc::new(server);
+            let mut policy = mux::policy::DestPolicy::default();
+            for net in args.values_of("mux-allow").into_iter().flatten() {
+                policy.allow(net.parse().expect("invalid network to allow"));
+            }
+            for net in args.values_of("mux-deny").into_iter().flatten() {
+                policy.deny(net.parse().expect("invalid network to deny"));
+            }
+            let config = mux::server::MuxServerConfig {
+                secret: secret.as_bytes().into(),
+                #[cfg(feature = "tls")]
+                tls: args.value_of("mux-tls-cert").map(|cert| {
+                    let key = args.value_of("mux-tls-key").expect("missing mux TLS key");
+                    let cert = fs::read(cert).expect("fail to read mux TLS certificate");
+                    let key = fs::read(key).expect("fail to read mux TLS key");
+                    tls::acceptor(&cert, &key).expect("invalid mux TLS certificate or key")
+                }),
+                policy,
+            };
+            tokio::spawn(mux::server::run_server(listener, server, config));
+        }
+    }
+
     // Watchdog
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     {
@@ -244,14 +367,27 @@ async fn main() {
     systemd::notify_ready(); // TODO: ready after first probe?
 
     // The proxy server
+    if listeners.is_empty() {
+        // mux server only
+        futures::future::pending::<()>().await;
+    }
     let mut clients = stream::select_all(listeners.iter_mut().map(|l| l.incoming()));
     while let Some(sock) = clients.next().await {
         let direct = direct_server.clone();
-        let servers = monitor.servers();
+        let servers = monitor.snapshot();
         match sock {
             Ok(sock) => {
                 tokio::spawn(async move {
-                    let result = handle_client(sock, servers, remote_dns, n_parallel, direct).await;
+                    let result = handle_client(
+                        sock,
+                        servers,
+                        inbound_timeout,
+                        remote_dns,
+                        n_parallel,
+                        hedge,
+                        direct,
+                    )
+                    .await;
                     if let Err(e) = result {
                         info!("error on hanle client: {}", e);
                     }
@@ -269,12 +405,15 @@ async fn main() {
 
 async fn handle_client(
     sock: TcpStream,
-    servers: ServerList,
+    servers: Arc<Snapshot>,
+    inbound_timeout: Duration,
     remote_dns: bool,
     n_parallel: usize,
+    hedge: Option<Hedge>,
     direct_server: Option<Arc<ProxyServer>>,
 ) -> io::Result<()> {
-    let client = NewClient::from_socket(sock, servers).await?;
+    let mut client = NewClient::from_socket(sock, &servers, inbound_timeout).await?;
+    client.set_hedge(hedge);
     let client = if remote_dns && client.dest.port == 443 {
         client
             .retrive_dest()
@@ -298,6 +437,9 @@ async fn handle_client(
 struct ServerListCfg {
     default_test_dns: SocketAddr,
     default_max_wait: Duration,
+    default_socket: SocketOptions,
+    default_timeouts: Timeouts,
+    default_pipe: PipeOptions,
     cli_servers: Vec<Arc<ProxyServer>>,
     path: Option<String>,
     listen_ports: HashSet<u16>,
@@ -311,11 +453,14 @@ impl ServerListCfg {
             .parse()
             .expect("not a valid socket address");
         let default_max_wait = parse_max_wait(args);
+        let default_socket = parse_default_socket_options(args);
+        let default_timeouts = parse_default_timeouts(args);
+        let default_pipe = parse_default_pipe_options(args);
 
         let mut cli_servers = vec![];
         if let Some(s) = args.values_of("socks5-servers") {
             for s in s.map(parse_server) {
-                cli_servers.push(Arc::new(ProxyServer::new(
+                let server = ProxyServer::new(
                     s.expect("not a valid SOCKSv5 server"),
                     ProxyProto::socks5(false),
                     default_test_dns,
@@ -323,12 +468,16 @@ impl ServerListCfg {
                     None,
                     None,
                     None,
-                )));
+                );
+                server.set_socket_options(default_socket.clone());
+                server.set_timeouts(default_timeouts);
+                server.set_pipe_options(default_pipe);
+                cli_servers.push(Arc::new(server));
             }
         }
         if let Some(s) = args.values_of("http-servers") {
             for s in s.map(parse_server) {
-                cli_servers.push(Arc::new(ProxyServer::new(
+                let server = ProxyServer::new(
                     s.expect("not a valid HTTP server"),
                     ProxyProto::http(false),
                     default_test_dns,
@@ -336,19 +485,29 @@ impl ServerListCfg {
                     None,
                     None,
                     None,
-                )));
+                );
+                server.set_socket_options(default_socket.clone());
+                server.set_timeouts(default_timeouts);
+                server.set_pipe_options(default_pipe);
+                cli_servers.push(Arc::new(server));
             }
         }
         let path = args.value_of("server-list").map(|s| s.to_string());
         let listen_ports = args
             .values_of("port")
-            .expect("missing port number")
-            .map(|p| p.parse().expect("invalid port number"))
-            .collect();
+            .map(|ports| {
+                ports
+                    .map(|p| p.parse().expect("invalid port number"))
+                    .collect()
+            })
+            .unwrap_or_default();
 
         ServerListCfg {
             default_test_dns,
             default_max_wait,
+            default_socket,
+            default_timeouts,
+            default_pipe,
             cli_servers,
             path,
             listen_ports,
@@ -361,11 +520,11 @@ impl ServerListCfg {
             let ini = Ini::load_from_file(path).or(Err("cannot read server list file"))?;
             for (tag, props) in ini.iter() {
                 let tag = props.get("tag").or(tag);
-                let addr: SocketAddr = props
+                let addr: ServerAddr = props
                     .get("address")
                     .ok_or("address not specified")?
                     .parse()
-                    .or(Err("not a valid socket address"))?;
+                    .or(Err("not a valid server address"))?;
                 let base = props
                     .get("score base")
                     .parse()
@@ -398,12 +557,11 @@ impl ServerListCfg {
                 } else {
                     None
                 };
-                let proto = match props
+                let proto_name = props
                     .get("protocol")
                     .ok_or("protocol not specified")?
-                    .to_lowercase()
-                    .as_str()
-                {
+                    .to_lowercase();
+                let proto = match proto_name.as_str() {
                     "socks5" | "socksv5" => {
                         let fake_hs = props
                             .get("socks fake handshaking")
@@ -432,14 +590,181 @@ impl ServerListCfg {
                             .unwrap_or(false);
                         ProxyProto::http(cwp)
                     }
-                    _ => return Err("unknown proxy protocol"),
+                    #[cfg(feature = "http2")]
+                    "http2" | "h2" => {
+                        let tls = props
+                            .get("http2 tls")
+                            .parse()
+                            .or(Err("not a boolean value"))?
+                            .unwrap_or(false);
+                        if tls && cfg!(not(feature = "tls")) {
+                            return Err("TLS support disabled during compiling");
+                        }
+                        ProxyProto::http2(Http2Config {
+                            tls,
+                            host: props.get("http2 host").map(Into::into),
+                        })
+                    }
+                    #[cfg(feature = "ssh")]
+                    "ssh" => ProxyProto::ssh(SshConfig {
+                        user: props
+                            .get("ssh user")
+                            .ok_or("ssh user not specified")?
+                            .into(),
+                        key: props.get("ssh key").ok_or("ssh key not specified")?.into(),
+                        known_hosts: props
+                            .get("ssh known hosts")
+                            .ok_or("ssh known hosts not specified")?
+                            .into(),
+                    }),
+                    #[cfg(feature = "websocket")]
+                    "websocket" | "ws" => {
+                        let tls = props
+                            .get("websocket tls")
+                            .parse()
+                            .or(Err("not a boolean value"))?
+                            .unwrap_or(false);
+                        if tls && cfg!(not(feature = "tls")) {
+                            return Err("TLS support disabled during compiling");
+                        }
+                        let headers = props
+                            .iter()
+                            .filter_map(|(k, v)| {
+                                let name = k.strip_prefix("websocket header ")?.trim();
+                                Some((name.into(), v.into()))
+                            })
+                            .collect();
+                        ProxyProto::websocket(WebSocketConfig {
+                            path: props.get("websocket path").unwrap_or("/").into(),
+                            host: props.get("websocket host").map(Into::into),
+                            tls,
+                            headers,
+                            dest_header: props.get("websocket dest header").map(Into::into),
+                        })
+                    }
+                    #[cfg(feature = "mux")]
+                    "moproxy" => {
+                        let pool_size = props
+                            .get("mux pool size")
+                            .parse()
+                            .or(Err("not a valid number"))?
+                            .unwrap_or(2);
+                        let secret = props.get("moproxy secret");
+                        if secret.map(str::len).unwrap_or_default() > u8::MAX as usize {
+                            return Err("moproxy secret too long");
+                        }
+                        let tls = props
+                            .get("moproxy tls")
+                            .parse()
+                            .or(Err("not a boolean value"))?
+                            .unwrap_or(false);
+                        if tls && cfg!(not(feature = "tls")) {
+                            return Err("TLS support disabled during compiling");
+                        }
+                        let loopback = match addr {
+                            ServerAddr::Inet(ref dest) => {
+                                matches!(dest.host, moproxy::proxy::Address::Ip(ip) if ip.is_loopback())
+                            }
+                            #[cfg(unix)]
+                            ServerAddr::Unix(_) => true,
+                        };
+                        if secret.is_some() && !tls && !loopback {
+                            return Err("moproxy secret requires moproxy tls");
+                        }
+                        let tls = if tls {
+                            let ca = match props.get("moproxy tls ca") {
+                                Some(path) => {
+                                    let ca =
+                                        fs::read(path).or(Err("cannot read moproxy tls ca"))?;
+                                    #[cfg(feature = "tls")]
+                                    tls::certificate(&ca).or(Err("invalid moproxy tls ca"))?;
+                                    Some(ca.into())
+                                }
+                                None => None,
+                            };
+                            let host = props.get("moproxy tls host").map(Into::into);
+                            Some(MuxTlsConfig { host, ca })
+                        } else {
+                            None
+                        };
+                        ProxyProto::moproxy(pool_size, secret, tls)
+                    }
+                    name => {
+                        let props: HashMap<_, _> = props
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .collect();
+                        let connector = build_connector(name, &addr, &props)
+                            .ok_or("unknown proxy protocol")??;
+                        ProxyProto::custom(name, connector)
+                    }
                 };
+                let pool_size = props
+                    .get("pool size")
+                    .parse()
+                    .or(Err("not a valid number"))?
+                    .unwrap_or(0);
+                if pool_size > 0 && !proto.supports_warm_pool() {
+                    return Err("pool size not supported by the protocol");
+                }
+                let pool_max_idle = props
+                    .get("pool max idle")
+                    .parse()
+                    .or(Err("not a valid number"))?
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| Duration::from_secs(30));
                 let server =
                     ProxyServer::new(addr, proto, test_dns, max_wait, listen_ports, tag, base);
+                let socket = parse_socket_options(|key| props.get(key), &self.default_socket)?;
+                if pool_size > 0 && socket.fast_open {
+                    return Err("pool size cannot be used with tcp fast open");
+                }
+                server.set_warm_pool(pool_size, pool_max_idle);
+                server.set_socket_options(socket);
+                server.set_timeouts(parse_timeouts(props, &self.default_timeouts)?);
+                server.set_pipe_options(parse_pipe_options(props, &self.default_pipe)?);
+                if let Some((targets, aggregate)) = parse_probes(props)? {
+                    server.set_probes(targets, aggregate);
+                }
+                let samples = props
+                    .get("probe samples")
+                    .parse()
+                    .or(Err("not a valid number"))?;
+                match samples {
+                    Some(0) => return Err("probe samples must be positive"),
+                    Some(n) => server.set_probe_samples(n),
+                    None => (),
+                }
+                if let Some(threshold) = props
+                    .get("fail threshold")
+                    .parse()
+                    .or(Err("not a valid number"))?
+                {
+                    server.set_fail_threshold(threshold);
+                }
+                let breaker_cooldown = props
+                    .get("breaker cooldown")
+                    .parse()
+                    .or(Err("not a valid number"))?
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| Duration::from_secs(30));
+                let breaker_trials = props
+                    .get("breaker trials")
+                    .parse()
+                    .or(Err("not a valid number"))?
+                    .unwrap_or(1);
+                if breaker_trials == 0 {
+                    return Err("breaker trials must be positive");
+                }
+                server.set_breaker(breaker_cooldown, breaker_trials);
+                if let Some(tier) = props.get("tier").parse().or(Err("not a valid tier"))? {
+                    server.set_tier(tier);
+                }
                 servers.push(Arc::new(server));
             }
         }
-        if servers.is_empty() {
+        // nothing to proxy for if running as a mux server only
+        if servers.is_empty() && !self.listen_ports.is_empty() {
             return Err("missing server list");
         }
         info!("total {} server(s) loaded", servers.len());
@@ -447,8 +772,230 @@ impl ServerListCfg {
     }
 }
 
-fn parse_server(addr: &str) -> Result<SocketAddr, &'static str> {
-    if addr.contains(':') {
+/// Parse probe targets of a server. `probe` is the target named "default",
+/// while `probe NAME` adds more. Return `None` if nothing is configured.
+fn parse_probes(props: &Properties) -> Result<Option<(Vec<ProbeTarget>, Aggregate)>, &'static str> {
+    let weight = |name: &str| -> Result<f32, &'static str> {
+        let weight: f32 = props
+            .get(format!("probe weight {}", name))
+            .parse()
+            .or(Err("not a valid number"))?
+            .unwrap_or(1.0);
+        if weight.is_finite() && weight >= 0.0 {
+            Ok(weight)
+        } else {
+            Err("probe weight must be a non-negative number")
+        }
+    };
+    let aggregate = props.get("probe aggregate").parse()?;
+    let mut targets = vec![];
+    if let Some(probe) = props.get("probe").parse()? {
+        targets.push(ProbeTarget {
+            probe,
+            weight: weight("default")?,
+            ..Default::default()
+        });
+    }
+    for (key, value) in props.iter() {
+        // keys reserved for options of probes, not names of them
+        let name = match key.strip_prefix("probe ") {
+            Some(name) if !name.starts_with("weight ") => name.trim(),
+            _ => continue,
+        };
+        if name == "aggregate" || name == "samples" {
+            continue;
+        }
+        targets.push(ProbeTarget {
+            name: name.into(),
+            probe: value.parse()?,
+            weight: weight(name)?,
+        });
+    }
+    if targets.is_empty() {
+        if aggregate.is_some() {
+            return Err("probe aggregate without any probe");
+        }
+        return Ok(None);
+    }
+    Ok(Some((targets, aggregate.unwrap_or_default())))
+}
+
+/// Parse socket options from values that `get` returns for keys in
+/// SERVER-LIST. Options not given fall back to those in `defaults`.
+fn parse_socket_options<'a, F>(
+    get: F,
+    defaults: &SocketOptions,
+) -> Result<SocketOptions, &'static str>
+where
+    F: Fn(&str) -> Option<&'a str>,
+{
+    let secs = |key| -> Result<Option<Duration>, &'static str> {
+        Ok(get(key)
+            .parse()
+            .or(Err("not a valid number"))?
+            .map(Duration::from_secs))
+    };
+    let tos = match (get("ip tos"), get("ip dscp")) {
+        (Some(_), Some(_)) => return Err("ip tos and ip dscp cannot be both set"),
+        (None, None) => defaults.tos,
+        (tos, None) => tos.parse().or(Err("ip tos not a valid number"))?,
+        (None, dscp) => {
+            let dscp: Option<u8> = dscp.parse().or(Err("ip dscp not a valid number"))?;
+            match dscp {
+                Some(dscp) if dscp > 63 => return Err("ip dscp out of range"),
+                dscp => dscp.map(|dscp| dscp << 2),
+            }
+        }
+    };
+    let default_keepalive = defaults.keepalive;
+    let idle = secs("tcp keepalive")?.or_else(|| default_keepalive.map(|ka| ka.idle));
+    let keepalive = match idle {
+        Some(idle) if idle > Duration::from_secs(0) => Some(Keepalive {
+            idle,
+            interval: secs("tcp keepalive interval")?
+                .or_else(|| default_keepalive.and_then(|ka| ka.interval)),
+            count: get("tcp keepalive count")
+                .parse()
+                .or(Err("not a valid number"))?
+                .or_else(|| default_keepalive.and_then(|ka| ka.count)),
+        }),
+        _ => None,
+    };
+    let opts = SocketOptions {
+        fast_open: get("tcp fast open")
+            .parse()
+            .or(Err("not a boolean value"))?
+            .unwrap_or(defaults.fast_open),
+        congestion: get("tcp congestion")
+            .map(Into::into)
+            .or_else(|| defaults.congestion.clone()),
+        notsent_lowat: get("tcp notsent lowat")
+            .parse()
+            .or(Err("not a valid number"))?
+            .or(defaults.notsent_lowat),
+        tos,
+        keepalive,
+        mark: match get("so mark") {
+            Some(mark) => Some(parse_mark(mark).ok_or("not a valid mark")?),
+            None => defaults.mark,
+        },
+        bind_device: get("bind device")
+            .map(Into::into)
+            .or_else(|| defaults.bind_device.clone()),
+        bind_addr: get("bind address")
+            .parse()
+            .or(Err("not a valid IP address"))?
+            .or(defaults.bind_addr),
+    };
+    if opts.is_linux_only() && cfg!(not(target_os = "linux")) {
+        return Err("socket options are only supported on Linux");
+    }
+    Ok(opts)
+}
+
+/// Parse a mark in decimal, or hexadecimal with `0x` prefix.
+fn parse_mark(mark: &str) -> Option<u32> {
+    match mark.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => mark.parse().ok(),
+    }
+}
+
+/// Socket options given by CLI arguments, apply to all servers and
+/// direct connections.
+fn parse_default_socket_options(args: &clap::ArgMatches) -> SocketOptions {
+    let get = |key: &str| {
+        let name = match key {
+            "tcp fast open" => return Some("true").filter(|_| args.is_present("tcp-fast-open")),
+            "tcp congestion" => "congestion",
+            "tcp notsent lowat" => "notsent-lowat",
+            "ip tos" => "ip-tos",
+            "ip dscp" => "ip-dscp",
+            "so mark" => "mark",
+            "bind device" => "bind-device",
+            "bind address" => "bind-address",
+            "tcp keepalive" => "keepalive",
+            "tcp keepalive interval" => "keepalive-interval",
+            "tcp keepalive count" => "keepalive-count",
+            _ => return None,
+        };
+        args.value_of(name)
+    };
+    parse_socket_options(get, &SocketOptions::default()).expect("invalid socket options")
+}
+
+/// Parse seconds in decimal.
+fn parse_secs(secs: &str) -> Result<Duration, &'static str> {
+    let secs: f32 = secs.parse().or(Err("not a valid number"))?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err("not a valid number of seconds");
+    }
+    Ok(Duration::from_secs_f32(secs))
+}
+
+/// Timeouts given by CLI arguments, apply to all servers and direct
+/// connections.
+fn parse_default_timeouts(args: &clap::ArgMatches) -> Timeouts {
+    let secs = |name| {
+        args.value_of(name)
+            .map(|s| parse_secs(s).expect("not a valid timeout"))
+    };
+    Timeouts {
+        connect: secs("connect-timeout"),
+        handshake: secs("handshake-timeout"),
+        response: secs("response-timeout"),
+    }
+}
+
+/// Parse timeouts of a server, fall back to those in `defaults`.
+fn parse_timeouts(props: &Properties, defaults: &Timeouts) -> Result<Timeouts, &'static str> {
+    let secs = |key| props.get(key).map(parse_secs).transpose();
+    Ok(Timeouts {
+        connect: secs("connect timeout")?.or(defaults.connect),
+        handshake: secs("handshake timeout")?.or(defaults.handshake),
+        response: secs("response timeout")?.or(defaults.response),
+    })
+}
+
+/// Options for piping connections given by CLI arguments, apply to all
+/// servers and direct connections.
+fn parse_default_pipe_options(args: &clap::ArgMatches) -> PipeOptions {
+    let secs = |name| {
+        args.value_of(name)
+            .map(|s| parse_secs(s).expect("not a valid number of seconds"))
+    };
+    let keepalive = match secs("keepalive") {
+        Some(idle) if idle > Duration::from_secs(0) => Some(Keepalive {
+            idle,
+            interval: secs("keepalive-interval"),
+            count: args
+                .value_of("keepalive-count")
+                .map(|n| n.parse().expect("not a valid keepalive count")),
+        }),
+        _ => None,
+    };
+    PipeOptions {
+        keepalive,
+        idle_timeout: secs("idle-timeout"),
+        max_lifetime: secs("max-lifetime"),
+    }
+}
+
+/// Parse pipe options of a server, fall back to those in `defaults`.
+fn parse_pipe_options(
+    props: &Properties,
+    defaults: &PipeOptions,
+) -> Result<PipeOptions, &'static str> {
+    let secs = |key| props.get(key).map(parse_secs).transpose();
+    Ok(PipeOptions {
+        idle_timeout: secs("idle timeout")?.or(defaults.idle_timeout),
+        max_lifetime: secs("max lifetime")?.or(defaults.max_lifetime),
+        ..*defaults
+    })
+}
+
+fn parse_server(addr: &str) -> Result<ServerAddr, &'static str> {
+    if addr.contains(':') || addr.starts_with('/') {
         addr.parse()
     } else {
         format!("127.0.0.1:{}", addr).parse()
@@ -463,3 +1010,44 @@ fn parse_max_wait(args: &clap::ArgMatches) -> Duration {
         .map(Duration::from_secs)
         .unwrap_or_else(|| Duration::from_secs(4))
 }
+
+#[test]
+fn test_parse_probes() {
+    let ini = Ini::load_from_str(
+        "[server]\n\
+         probe samples = 3\n\
+         probe aggregate = min\n\
+         probe web = http http://example.com/\n\
+         probe weight web = 2\n",
+    )
+    .unwrap();
+    let props = ini.section(Some("server")).unwrap();
+    let (targets, _) = parse_probes(props).unwrap().unwrap();
+    assert_eq!(targets.len(), 1);
+    assert_eq!(&*targets[0].name, "web");
+    assert_eq!(targets[0].weight, 2.0);
+}
+
+#[test]
+fn test_parse_socket_options() {
+    let defaults = SocketOptions {
+        congestion: Some("bbr".into()),
+        tos: Some(0x10),
+        keepalive: Some(Keepalive {
+            idle: Duration::from_secs(180),
+            interval: None,
+            count: None,
+        }),
+        ..Default::default()
+    };
+    let ini = Ini::load_from_str(
+        "[server]\nip dscp = 46\ntcp notsent lowat = 16384\ntcp keepalive = 0\n",
+    )
+    .unwrap();
+    let props = ini.section(Some("server")).unwrap();
+    let opts = parse_socket_options(|key| props.get(key), &defaults).unwrap();
+    assert_eq!(opts.congestion, Some("bbr".into()));
+    assert_eq!(opts.tos, Some(46 << 2));
+    assert_eq!(opts.notsent_lowat, Some(16384));
+    assert_eq!(opts.keepalive, None);
+}
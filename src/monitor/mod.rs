@@ -1,41 +1,240 @@
 mod graphite;
+pub mod probe;
 #[cfg(feature = "score_script")]
 use rlua::prelude::*;
 mod traffic;
-use futures::future::join_all;
-use log::{debug, warn};
-use parking_lot::Mutex;
+use arc_swap::ArcSwap;
+use futures::{
+    future::{join_all, pending, select_all},
+    stream::{FuturesUnordered, StreamExt},
+};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, MutexGuard};
 use rand::{self, Rng};
 use std::{
     self,
     collections::{HashMap, HashSet},
     io,
     iter::FromIterator,
-    net::{Shutdown, SocketAddr},
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, SystemTime},
 };
 #[cfg(feature = "score_script")]
 use std::{error::Error, fs::File, io::Read};
-use tokio::{
-    io::AsyncReadExt,
-    time::{interval_at, timeout, Instant},
-};
+use tokio::time::{delay_for, delay_until, interval_at, timeout, Instant};
 
 use self::graphite::{Graphite, Record};
+use self::probe::{Aggregate, ProbeTarget};
 use self::traffic::Meter;
 pub use self::traffic::Throughput;
-use crate::proxy::ProxyServer;
+use crate::proxy::{breaker::BreakerState, loop_guard, ProbeDelay, ProbeStats, ProxyServer};
 
 static THROUGHPUT_INTERVAL_SECS: u64 = 1;
+static WARM_POOL_CHECK_SECS: u64 = 5;
+/// Max number of servers being probed at the same time.
+static MAX_CONCURRENT_PROBES: usize = 16;
+/// Probe interval of failed servers doubles each round, up to
+/// 2^MAX_PROBE_BACKOFF_SHIFT times.
+static MAX_PROBE_BACKOFF_SHIFT: u32 = 4;
 
 pub type ServerList = Vec<Arc<ProxyServer>>;
 
+/// An immutable, sorted list of servers. Replaced as a whole on resort or
+/// reload, so that reading it never blocks.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    servers: Arc<ServerList>,
+    /// Servers not pointing to moproxy itself, in the same order.
+    usable: ServerList,
+    /// Servers to connect for each listening port, in the same order.
+    by_port: HashMap<u16, Arc<ServerList>>,
+}
+
+impl Snapshot {
+    fn new(servers: ServerList, ports: &[u16]) -> Self {
+        let usable: ServerList = servers
+            .iter()
+            .filter(|server| !points_to_self(server))
+            .cloned()
+            .collect();
+        let by_port = ports
+            .iter()
+            .map(|&port| (port, Arc::new(servers_for_port(&usable, port))))
+            .collect();
+        Snapshot {
+            servers: Arc::new(servers),
+            usable,
+            by_port,
+        }
+    }
+
+    pub fn servers(&self) -> &ServerList {
+        &self.servers
+    }
+
+    /// Servers to connect for clients accepted on `port`. Servers
+    /// pointing to moproxy itself are skipped, and tiers selected as
+    /// `select_tier()` does.
+    pub fn for_port(&self, port: u16) -> Arc<ServerList> {
+        match self.by_port.get(&port) {
+            Some(servers) => servers.clone(),
+            None => Arc::new(servers_for_port(&self.usable, port)),
+        }
+    }
+}
+
+/// Whether connecting to `server` reaches one of our listeners.
+fn points_to_self(server: &ProxyServer) -> bool {
+    let looped = server
+        .resolved_addrs()
+        .iter()
+        .any(loop_guard::is_listen_addr);
+    if looped && loop_guard::count_loop() {
+        warn!("{} points to moproxy itself, skipped", server);
+    }
+    looped
+}
+
+fn servers_for_port(servers: &[Arc<ProxyServer>], port: u16) -> ServerList {
+    let servers = servers
+        .iter()
+        .filter(|server| server.serve_port(port))
+        .cloned()
+        .collect();
+    select_tier(servers)
+}
+
+/// Keep servers in the highest tier that has any server up. Servers in
+/// higher tiers whose circuit breakers are not closed are kept as well,
+/// ahead of others, so that they get trial connections to recover.
+/// Keep all if no server is up.
+fn select_tier(servers: ServerList) -> ServerList {
+    let tier = match servers.iter().filter(|s| s.is_up()).map(|s| s.tier()).min() {
+        Some(tier) => tier,
+        None => return servers,
+    };
+    let mut recovering = vec![];
+    let mut selected = vec![];
+    for server in servers {
+        if server.tier() == tier {
+            selected.push(server);
+        } else if server.tier() < tier && server.breaker_state() != BreakerState::Closed {
+            recovering.push(server);
+        }
+    }
+    recovering.extend(selected);
+    recovering
+}
+
+/// The server on top of the list. It stays there for at least `hold`,
+/// and until another server in the same tier is better by `margin`
+/// percent of its score.
+struct TopServer {
+    server: Option<Arc<ProxyServer>>,
+    since: Instant,
+    margin: u8,
+    hold: Duration,
+    /// Number of times the top server changed.
+    switches: usize,
+}
+
+impl TopServer {
+    fn new() -> Self {
+        TopServer {
+            server: None,
+            since: Instant::now(),
+            margin: 0,
+            hold: Duration::from_secs(0),
+            switches: 0,
+        }
+    }
+
+    /// Keep the current top server on top of the sorted `servers` if the
+    /// new one is not clearly better, otherwise switch to the new one.
+    fn update(&mut self, servers: &mut ServerList) {
+        let new = match servers.first() {
+            Some(server) if server.score().is_some() => server.clone(),
+            _ => {
+                self.server = None;
+                return;
+            }
+        };
+        let current = match self.server {
+            Some(ref server) if *server == new => return,
+            Some(ref server) => server.clone(),
+            None => {
+                self.server = Some(new);
+                self.since = Instant::now();
+                return;
+            }
+        };
+        let pos = servers.iter().position(|server| *server == current);
+        let same_tier = new.tier() == current.tier();
+        if let (Some(pos), Some(score), true) = (pos, current.score(), same_tier) {
+            let score = score as i64;
+            let margin = score.abs() * self.margin as i64 / 100;
+            let better = (new.score().unwrap() as i64) < score - margin;
+            if !better || self.since.elapsed() < self.hold {
+                let server = servers.remove(pos);
+                servers.insert(0, server);
+                return;
+            }
+        }
+        info!("top server changed: {} => {}", current.tag, new.tag);
+        self.switches += 1;
+        self.server = Some(new);
+        self.since = Instant::now();
+    }
+}
+
+/// When to probe a server next.
+struct ProbeSchedule {
+    next: Instant,
+    /// Number of consecutive failed rounds.
+    failed: u32,
+    running: bool,
+}
+
+impl ProbeSchedule {
+    /// Schedule the first probe within the first tenth of `interval`.
+    fn new(interval: Duration) -> Self {
+        let spread = rand::thread_rng().gen_range(0.0, 0.1);
+        ProbeSchedule {
+            next: Instant::now() + interval.mul_f32(spread),
+            failed: 0,
+            running: false,
+        }
+    }
+
+    /// Schedule the next probe after one done. Back off exponentially
+    /// if it failed, plus ±10% random spread.
+    fn done(&mut self, alive: bool, interval: Duration) {
+        self.running = false;
+        self.failed = if alive { 0 } else { self.failed + 1 };
+        let backoff = 1 << self.failed.min(MAX_PROBE_BACKOFF_SHIFT);
+        let spread = rand::thread_rng().gen_range(0.9, 1.1);
+        self.next = Instant::now() + (interval * backoff).mul_f32(spread);
+    }
+
+    /// Probe as soon as possible without backing off, e.g. the server
+    /// just got demoted or recovered. Any running probe is left as is.
+    fn reset(&mut self) {
+        self.failed = 0;
+        self.next = Instant::now();
+    }
+}
+
 #[derive(Clone)]
 pub struct Monitor {
-    servers: Arc<Mutex<ServerList>>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+    /// Ports that per-port lists are kept in the snapshot for.
+    listen_ports: Arc<Mutex<Vec<u16>>>,
     meters: Arc<Mutex<HashMap<Arc<ProxyServer>, Meter>>>,
     graphite: Option<SocketAddr>,
+    /// Also held while replacing the snapshot, so that resorts and
+    /// reloads do not overwrite each other.
+    top: Arc<Mutex<TopServer>>,
     #[cfg(feature = "score_script")]
     lua: Option<Arc<Mutex<Lua>>>,
 }
@@ -47,9 +246,11 @@ impl Monitor {
             .map(|server| (server.clone(), Meter::new()))
             .collect();
         Monitor {
-            servers: Arc::new(Mutex::new(servers)),
+            snapshot: Arc::new(ArcSwap::from_pointee(Snapshot::new(servers, &[]))),
+            listen_ports: Default::default(),
             meters: Arc::new(Mutex::new(meters)),
             graphite,
+            top: Arc::new(Mutex::new(TopServer::new())),
             #[cfg(feature = "score_script")]
             lua: None,
         }
@@ -80,13 +281,48 @@ impl Monitor {
         Ok(())
     }
 
+    /// Damp flapping of the top server: a server takes the top only if
+    /// its score is lower than the current one's by `margin` percent, and
+    /// the current one has been there for at least `hold`.
+    pub fn set_switch_damping(&self, margin: u8, hold: Duration) {
+        let mut top = self.top.lock();
+        top.margin = margin;
+        top.hold = hold;
+    }
+
+    /// Number of times the top server changed.
+    pub fn top_switches(&self) -> usize {
+        self.top.lock().switches
+    }
+
+    /// Keep lists of servers for each of `ports` in the snapshot, so that
+    /// clients accepted on them get their lists without filtering.
+    pub fn set_listen_ports(&self, ports: Vec<u16>) {
+        let top = self.top.lock();
+        *self.listen_ports.lock() = ports;
+        self.store(top, self.servers());
+    }
+
     /// Return an ordered list of servers.
     pub fn servers(&self) -> ServerList {
-        self.servers.lock().clone()
+        self.snapshot.load().servers().clone()
+    }
+
+    /// Return the current snapshot of servers, without blocking.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.load_full()
+    }
+
+    /// Replace the snapshot with `servers`. Take `top` to ensure there is
+    /// no concurrent replacement.
+    fn store(&self, _top: MutexGuard<TopServer>, servers: ServerList) {
+        let snapshot = Snapshot::new(servers, &self.listen_ports.lock());
+        self.snapshot.store(Arc::new(snapshot));
     }
 
     /// Replace internal servers with provided list.
     pub fn update_servers(&self, new_servers: Vec<Arc<ProxyServer>>) {
+        let top = self.top.lock();
         let oldset: HashSet<_> = self.servers().into_iter().collect();
         let newset = HashSet::from_iter(new_servers);
         let mut new_servers = Vec::with_capacity(newset.len());
@@ -110,37 +346,161 @@ impl Monitor {
             meters.insert(server.clone(), Meter::new());
         }
 
-        *self.servers.lock() = new_servers;
+        drop(meters);
+        self.store(top, new_servers);
         self.resort();
     }
 
     fn resort(&self) {
         let mut rng = rand::thread_rng();
-        let mut servers = self.servers.lock();
+        let mut top = self.top.lock();
+        let mut servers = self.servers();
         servers.sort_by_key(move |server| {
-            server.score().unwrap_or(std::i32::MAX) - (rng.gen::<u8>() % 30) as i32
+            let score = server.score();
+            let jitter = (rng.gen::<u8>() % 30) as i32;
+            (
+                score.is_none(),
+                server.tier(),
+                score.unwrap_or(std::i32::MAX) - jitter,
+            )
         });
+        top.update(&mut servers);
         debug!("scores:{}", info_stats(&servers));
+        self.store(top, servers);
     }
 
-    /// Start monitoring delays.
+    /// Start monitoring delays. Each server is probed every `probe`
+    /// seconds on its own schedule, less often if it keeps failing.
+    /// Servers demoted due to connection failures, or recovered by trial
+    /// connections after that, are probed as soon as possible, instead of
+    /// waiting for their next round.
     /// Returned Future won't return unless error on timer.
     pub async fn monitor_delay(self, probe: u64) {
         let mut graphite = self.graphite.map(Graphite::new);
         let interval = Duration::from_secs(probe);
+        let mut metrics = interval_at(Instant::now() + interval, interval);
+        // keyed by tag, servers are replaced on reload
+        let mut schedules: HashMap<Box<str>, ProbeSchedule> = HashMap::new();
+        let mut running = FuturesUnordered::new();
 
-        test_all(&self).await;
+        loop {
+            let servers = self.servers();
+            schedules.retain(|tag, _| servers.iter().any(|server| &server.tag == tag));
+            for server in servers.iter() {
+                schedules
+                    .entry(server.tag.clone())
+                    .or_insert_with(|| ProbeSchedule::new(interval));
+            }
+
+            // start due probes, the most overdue first
+            let now = Instant::now();
+            let mut due: Vec<_> = servers
+                .iter()
+                .filter_map(|server| {
+                    let schedule = &schedules[&server.tag];
+                    if !schedule.running && schedule.next <= now {
+                        Some((schedule.next, server.clone()))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            due.sort_by_key(|(next, _)| *next);
+            let n = MAX_CONCURRENT_PROBES.saturating_sub(running.len());
+            for (_, server) in due.into_iter().take(n) {
+                schedules.get_mut(&server.tag).unwrap().running = true;
+                let monitor = self.clone();
+                running.push(async move {
+                    let alive = test_one(&monitor, &server).await;
+                    (server, alive)
+                });
+            }
 
+            // wait for running ones if capped, otherwise the next due one
+            let wake = if running.len() >= MAX_CONCURRENT_PROBES {
+                None
+            } else {
+                schedules
+                    .values()
+                    .filter(|schedule| !schedule.running)
+                    .map(|schedule| schedule.next)
+                    .min()
+            }
+            .unwrap_or(now + interval);
+            let probe_needed = async {
+                if servers.is_empty() {
+                    pending().await
+                } else {
+                    let needed = servers.iter().map(|server| Box::pin(server.probe_needed()));
+                    select_all(needed).await.1
+                }
+            };
+            tokio::select! {
+                Some((server, alive)) = running.next() => {
+                    if let Some(schedule) = schedules.get_mut(&server.tag) {
+                        schedule.done(alive, interval);
+                    }
+                    self.resort();
+                }
+                index = probe_needed => {
+                    let server = &servers[index];
+                    debug!("[{}] probing server as needed", server.tag);
+                    if let Some(schedule) = schedules.get_mut(&server.tag) {
+                        schedule.reset();
+                    }
+                    self.resort();
+                }
+                _ = delay_until(wake) => (),
+                _ = metrics.tick(), if graphite.is_some() => {
+                    match send_metrics(&self, graphite.as_mut().unwrap()).await {
+                        Ok(_) => debug!("metrics sent"),
+                        Err(e) => warn!("fail to send metrics {:?}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolve host names of servers, then re-resolve them periodically
+    /// unless `interval` is zero.
+    /// Returned Future won't return unless error on timer or `interval`
+    /// is zero.
+    pub async fn monitor_resolve(self, interval: u64) {
+        resolve_all(&self).await;
+        self.resort();
+        if interval == 0 {
+            return;
+        }
+
+        let interval = Duration::from_secs(interval);
         let mut interval = interval_at(Instant::now() + interval, interval);
         loop {
             interval.tick().await;
-            test_all(&self).await;
-            if let Some(ref mut graphite) = graphite {
-                match send_metrics(&self, graphite).await {
-                    Ok(_) => debug!("metrics sent"),
-                    Err(e) => warn!("fail to send metrics {:?}", e),
-                }
+            resolve_all(&self).await;
+            self.resort();
+        }
+    }
+
+    /// Keep warm pools of servers filled. Idle connections are checked
+    /// periodically, and pools are refilled once connections are taken.
+    /// Returned Future won't return unless error on timer.
+    pub async fn monitor_warm_pool(self) {
+        let interval = Duration::from_secs(WARM_POOL_CHECK_SECS);
+        loop {
+            let servers: Vec<_> = self
+                .servers()
+                .into_iter()
+                .filter(|server| server.warm_pool_size() > 0)
+                .collect();
+            if servers.is_empty() {
+                delay_for(interval).await;
+                continue;
             }
+            join_all(servers.iter().map(|server| server.fill_warm_pool())).await;
+            let taken = servers
+                .iter()
+                .map(|server| Box::pin(server.warm_pool_taken()));
+            let _ = timeout(interval, select_all(taken)).await;
         }
     }
 
@@ -180,39 +540,41 @@ fn info_stats(infos: &[Arc<ProxyServer>]) -> String {
     stats
 }
 
-async fn test_all(monitor: &Monitor) {
-    debug!("testing all servers...");
-    let tests: Vec<_> = monitor
-        .servers()
-        .into_iter()
-        .map(move |server| {
-            Box::pin(async move {
-                let delay = alive_test(&server).await.ok();
-
-                #[cfg(feature = "score_script")]
-                {
-                    let mut caculated = false;
-                    if let Some(lua) = &monitor.lua {
-                        match lua
-                            .lock()
-                            .context(|ctx| server.update_delay_with_lua(delay, ctx))
-                        {
-                            Ok(()) => caculated = true,
-                            Err(err) => warn!("fail to update score w/ Lua script: {}", err),
-                        }
-                    }
-                    if !caculated {
-                        server.update_delay(delay);
-                    }
-                }
-                #[cfg(not(feature = "score_script"))]
-                server.update_delay(delay);
-            })
-        })
-        .collect();
+/// Probe the server and update its score. Return whether it's alive.
+#[cfg_attr(not(feature = "score_script"), allow(unused_variables))]
+async fn test_one(monitor: &Monitor, server: &ProxyServer) -> bool {
+    let stats = alive_test(server).await;
+    let alive = stats.is_some();
 
-    join_all(tests).await;
-    monitor.resort();
+    #[cfg(feature = "score_script")]
+    {
+        let mut caculated = false;
+        if let Some(lua) = &monitor.lua {
+            match lua
+                .lock()
+                .context(|ctx| server.update_delay_with_lua(stats, ctx))
+            {
+                Ok(()) => caculated = true,
+                Err(err) => warn!("fail to update score w/ Lua script: {}", err),
+            }
+        }
+        if !caculated {
+            server.update_delay(stats);
+        }
+    }
+    #[cfg(not(feature = "score_script"))]
+    server.update_delay(stats);
+    alive
+}
+
+async fn resolve_all(monitor: &Monitor) {
+    debug!("resolving all servers...");
+    let tasks = monitor.servers().into_iter().map(|server| async move {
+        if let Err(err) = server.resolve().await {
+            warn!("fail to resolve {}: {}", server.addr, err);
+        }
+    });
+    join_all(tasks).await;
 }
 
 // send graphite metrics if need
@@ -227,65 +589,259 @@ async fn send_metrics(monitor: &Monitor, graphite: &mut Graphite) -> io::Result<
             let traffic = server.traffic();
             vec![
                 status.delay.map(|t| r("delay", t.as_millis() as u64)),
+                status
+                    .probe
+                    .map(|p| r("jitter", p.jitter.as_millis() as u64)),
                 status.score.map(|s| r("score", s as u64)),
                 Some(r("tx_bytes", traffic.tx_bytes as u64)),
                 Some(r("rx_bytes", traffic.rx_bytes as u64)),
                 Some(r("conns.total", status.conn_total as u64)),
                 Some(r("conns.alive", status.conn_alive as u64)),
                 Some(r("conns.error", status.conn_error as u64)),
+                status.warm_pool.map(|p| r("pool.hits", p.hits as u64)),
+                status.warm_pool.map(|p| r("pool.misses", p.misses as u64)),
             ]
         })
-        .filter_map(|v| v)
+        .flatten()
         .collect(); // FIXME: avoid allocate large memory
     graphite.write_records(records).await
 }
 
-async fn alive_test(server: &ProxyServer) -> io::Result<Duration> {
-    let request = [
-        0,
-        17, // length
-        rand::random(),
-        rand::random(), // transaction ID
-        1,
-        32, // standard query
-        0,
-        1, // one query
-        0,
-        0, // answer
-        0,
-        0, // authority
-        0,
-        0, // addition
-        0, // query: root
-        0,
-        1, // query: type A
-        0,
-        1, // query: class IN
-    ];
-    let tid = |req: &[u8]| (req[2] as u16) << 8 | (req[3] as u16);
-    let req_tid = tid(&request);
-    let now = Instant::now();
-
-    let mut buf = [0u8; 12];
-    let test_dns = server.test_dns().into();
-    let result = timeout(server.max_wait(), async {
-        let mut stream = server.connect(&test_dns, Some(request)).await?;
-        stream.read_exact(&mut buf).await?;
-        stream.shutdown(Shutdown::Both)
-    })
+/// Run all probes of the server concurrently, return their aggregated
+/// delay. `None` if the probe failed.
+async fn probe_once(
+    server: &ProxyServer,
+    targets: &[ProbeTarget],
+    aggregate: Aggregate,
+) -> (Vec<Option<Duration>>, Option<Duration>) {
+    let max_wait = server.max_wait();
+    let delays = join_all(targets.iter().map(|target| async move {
+        let now = Instant::now();
+        match timeout(max_wait, target.probe.run(server)).await {
+            Err(_) => {
+                debug!("[{}] probe {} timed out", server.tag, target.name);
+                None
+            }
+            Ok(Err(e)) => {
+                debug!("[{}] probe {} failed: {}", server.tag, target.probe, e);
+                None
+            }
+            Ok(Ok(())) => Some(now.elapsed()),
+        }
+    }))
     .await;
+    let weighted: Vec<_> = delays
+        .iter()
+        .cloned()
+        .zip(targets.iter().map(|target| target.weight))
+        .collect();
+    let delay = aggregate.apply(&weighted, max_wait);
+    (delays, delay)
+}
+
+/// Probe the server for `probe_samples` times in sequence. Return `None`
+/// if all of them failed. Median delays of each target are recorded on
+/// the server.
+async fn alive_test(server: &ProxyServer) -> Option<ProbeStats> {
+    let (targets, aggregate) = server.probes();
+    let mut samples = vec![];
+    let mut target_samples = vec![vec![]; targets.len()];
+    for _ in 0..server.probe_samples() {
+        let (delays, delay) = probe_once(server, &targets, aggregate).await;
+        for (target, delay) in target_samples.iter_mut().zip(delays) {
+            target.push(delay);
+        }
+        samples.push(delay);
+    }
+    server.set_probe_delays(
+        targets
+            .iter()
+            .zip(&target_samples)
+            .map(|(target, samples)| ProbeDelay {
+                name: target.name.clone(),
+                delay: ProbeStats::from_samples(samples).map(|s| s.median).into(),
+            })
+            .collect(),
+    );
 
-    match result {
-        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "test timeout")),
-        Ok(Err(e)) => return Err(e),
-        Ok(Ok(_)) => (),
+    let stats = ProbeStats::from_samples(&samples);
+    match stats {
+        Some(stats) => debug!(
+            "[{}] delay {}ms, jitter {}ms, loss {}/{}",
+            server.tag,
+            stats.median.as_millis(),
+            stats.jitter.as_millis(),
+            stats.lost,
+            stats.samples
+        ),
+        None => debug!("[{}] all probes failed", server.tag),
     }
+    stats
+}
 
-    if req_tid == tid(&buf) {
-        let t = now.elapsed();
-        debug!("[{}] delay {}ms", server.tag, t.as_millis());
-        Ok(t)
-    } else {
-        Err(io::Error::new(io::ErrorKind::Other, "unknown response"))
+#[test]
+fn test_probe_backoff() {
+    let interval = Duration::from_secs(10);
+    let mut schedule = ProbeSchedule::new(interval);
+    assert!(schedule.next <= Instant::now() + Duration::from_secs(1));
+    let after = |schedule: &ProbeSchedule| schedule.next - Instant::now();
+
+    schedule.done(true, interval);
+    assert!(after(&schedule) > Duration::from_secs(8));
+    assert!(after(&schedule) <= Duration::from_secs(11));
+    for _ in 0..10 {
+        schedule.done(false, interval);
+    }
+    assert!(after(&schedule) > Duration::from_secs(140));
+    assert!(after(&schedule) <= Duration::from_secs(176));
+    schedule.done(true, interval);
+    assert!(after(&schedule) <= Duration::from_secs(11));
+    for _ in 0..10 {
+        schedule.done(false, interval);
     }
+    schedule.reset();
+    assert!(schedule.next <= Instant::now());
+    schedule.done(false, interval);
+    assert!(after(&schedule) <= Duration::from_secs(23));
+}
+
+#[test]
+fn test_top_server_damping() {
+    let server = |tag, delay| Arc::new(ProxyServer::new_for_test(tag, None, Some(delay)));
+    let (a, b) = (server("a", 100), server("b", 95));
+    let mut top = TopServer::new();
+    top.margin = 10;
+
+    let mut servers = vec![a.clone(), b.clone()];
+    top.update(&mut servers);
+    assert_eq!(top.server, Some(a.clone()));
+    // b is better but within the margin
+    let mut servers = vec![b.clone(), a.clone()];
+    top.update(&mut servers);
+    assert_eq!(servers[0], a);
+    assert_eq!(top.switches, 0);
+    // clearly better
+    let c = server("c", 50);
+    let mut servers = vec![c.clone(), b, a];
+    top.update(&mut servers);
+    assert_eq!(servers[0], c);
+    assert_eq!(top.switches, 1);
+    // held
+    top.hold = Duration::from_secs(60);
+    let d = server("d", 1);
+    let mut servers = vec![d.clone(), c.clone()];
+    top.update(&mut servers);
+    assert_eq!(servers[0], c);
+    // unless dead
+    c.update_delay(None);
+    let mut servers = vec![d.clone(), c];
+    top.update(&mut servers);
+    assert_eq!(servers[0], d);
+    assert_eq!(top.switches, 2);
+}
+
+#[test]
+fn test_snapshot() {
+    let server = |tag, ports: Option<Vec<u16>>, delay| {
+        let ports = ports.map(HashSet::from_iter);
+        Arc::new(ProxyServer::new_for_test(tag, ports, Some(delay)))
+    };
+    let (a, b) = (server("a", Some(vec![1080]), 100), server("b", None, 500));
+    let monitor = Monitor::new(vec![b.clone(), a.clone()], None);
+    monitor.set_listen_ports(vec![1080, 1081]);
+    let before = monitor.snapshot();
+    monitor.resort();
+    let snapshot = monitor.snapshot();
+    assert_eq!(*snapshot.servers(), vec![a.clone(), b.clone()]);
+    assert_eq!(*snapshot.for_port(1080), vec![a.clone(), b.clone()]);
+    assert_eq!(*snapshot.for_port(1081), vec![b.clone()]);
+    assert_eq!(*snapshot.for_port(1082), vec![b.clone()]);
+    // old snapshots are left untouched
+    assert_eq!(*before.servers(), vec![b.clone(), a.clone()]);
+
+    monitor.update_servers(vec![b.clone()]);
+    assert_eq!(*monitor.snapshot().for_port(1080), vec![b]);
+}
+
+#[test]
+fn test_select_tier() {
+    let server = |tag, tier, up| {
+        let server = ProxyServer::new_for_test(tag, None, if up { Some(100) } else { None });
+        server.set_tier(tier);
+        Arc::new(server)
+    };
+    let (a, b, c) = (
+        server("a", 0, false),
+        server("b", 1, true),
+        server("c", 1, true),
+    );
+    let tags = |list: ServerList| list.iter().map(|s| s.tag.clone()).collect::<Vec<_>>();
+    let list = vec![a.clone(), b.clone(), c.clone()];
+    assert_eq!(
+        tags(select_tier(list.clone())),
+        vec!["b".into(), "c".into()]
+    );
+
+    // a passed a probe after its circuit breaker opened
+    for _ in 0..3 {
+        a.update_stats_conn_fail();
+    }
+    a.update_delay(ProbeStats::from_samples(&[Some(Duration::from_millis(
+        100,
+    ))]));
+    assert_eq!(
+        tags(select_tier(list.clone())),
+        vec!["a".into(), "b".into(), "c".into()]
+    );
+    a.update_stats_conn_ok();
+    assert_eq!(tags(select_tier(list.clone())), vec!["a".into()]);
+
+    // and it's done before clients get their lists
+    let monitor = Monitor::new(list, None);
+    monitor.set_listen_ports(vec![1080]);
+    assert_eq!(*monitor.snapshot().for_port(1080), vec![a.clone()]);
+    assert_eq!(*monitor.snapshot().for_port(1081), vec![a]);
+
+    let list = vec![server("d", 0, false), server("e", 1, false)];
+    assert_eq!(select_tier(list).len(), 2);
+}
+
+#[test]
+fn test_breaker_recovery() {
+    use futures::FutureExt;
+    let server = |tag, tier| {
+        let server = ProxyServer::new_for_test(tag, None, Some(100));
+        server.set_tier(tier);
+        server.set_breaker(Duration::from_millis(50), 1);
+        Arc::new(server)
+    };
+    let (a, b) = (server("a", 0), server("b", 1));
+    let monitor = Monitor::new(vec![a.clone(), b.clone()], None);
+    monitor.set_listen_ports(vec![1080]);
+    monitor.resort();
+    assert_eq!(*monitor.snapshot().for_port(1080), vec![a.clone()]);
+
+    // clients move to b, but keep a ahead for trials
+    for _ in 0..3 {
+        a.update_stats_conn_fail();
+    }
+    assert!(a.probe_needed().now_or_never().is_some());
+    monitor.resort();
+    assert_eq!(
+        *monitor.snapshot().for_port(1080),
+        vec![a.clone(), b.clone()]
+    );
+    assert!(!a.breaker_allow());
+
+    // a trial after the cooldown closes the breaker, and asks for a probe
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(a.breaker_allow());
+    a.update_stats_conn_ok();
+    assert_eq!(a.breaker_state(), BreakerState::Closed);
+    assert!(a.probe_needed().now_or_never().is_some());
+    a.update_delay(ProbeStats::from_samples(&[Some(Duration::from_millis(
+        100,
+    ))]));
+    monitor.resort();
+    assert_eq!(*monitor.snapshot().for_port(1080), vec![a]);
 }
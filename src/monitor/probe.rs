@@ -0,0 +1,448 @@
+use http::Uri;
+use httparse::{Response, EMPTY_HEADER};
+use serde_derive::Serialize;
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Shutdown},
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(feature = "tls")]
+use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[cfg(feature = "tls")]
+use crate::proxy::tls;
+use crate::proxy::{Address, Destination, ProxyServer};
+
+const MAX_RESPONSE_LEN: usize = 4096;
+
+/// How to test a proxy server is working.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub enum Probe {
+    /// Query type A record of `name` from `test dns` via TCP.
+    Dns { name: Box<str> },
+    /// GET the URL and expect the status code.
+    Http {
+        dest: Destination,
+        /// TLS server name.
+        host: Box<str>,
+        path: Box<str>,
+        tls: bool,
+        status: u16,
+    },
+    /// Complete a TLS handshake with the destination.
+    Tls { dest: Destination, host: Box<str> },
+    /// Connect to the destination via the proxy.
+    Tcp { dest: Destination },
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe::Dns { name: ".".into() }
+    }
+}
+
+/// A probe and its weight in `Aggregate::Weighted`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ProbeTarget {
+    pub name: Box<str>,
+    pub probe: Probe,
+    pub weight: f32,
+}
+
+impl Default for ProbeTarget {
+    fn default() -> Self {
+        ProbeTarget {
+            name: "default".into(),
+            probe: Default::default(),
+            weight: 1.0,
+        }
+    }
+}
+
+/// How delays of multiple probe targets are combined into one.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum Aggregate {
+    /// Median of delays, failed targets are treated as the slowest ones.
+    #[default]
+    Median,
+    /// The fastest delay, fail only if all targets failed.
+    Min,
+    /// The slowest delay, fail if any target failed.
+    Max,
+    /// Weighted mean of delays, failed targets count as `max_wait`.
+    /// Fail only if all targets failed.
+    Weighted,
+}
+
+impl Aggregate {
+    /// Combine delays of targets, each with its weight. `None` for
+    /// failed targets.
+    pub fn apply(self, delays: &[(Option<Duration>, f32)], max_wait: Duration) -> Option<Duration> {
+        if delays.iter().all(|(delay, _)| delay.is_none()) {
+            return None;
+        }
+        match self {
+            Aggregate::Median => {
+                // `None` is smaller than any `Some`, put them at the end
+                let mut sorted: Vec<_> = delays.iter().map(|(delay, _)| *delay).collect();
+                sorted.sort_by_key(|delay| (delay.is_none(), *delay));
+                sorted[(sorted.len() - 1) / 2]
+            }
+            Aggregate::Min => delays.iter().filter_map(|(delay, _)| *delay).min(),
+            Aggregate::Max => delays
+                .iter()
+                .map(|(delay, _)| *delay)
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .max(),
+            Aggregate::Weighted => {
+                // weights from config are validated, but not those
+                // passed by others
+                let total: f32 = delays.iter().map(|(_, weight)| weight).sum();
+                if total <= 0.0 || !total.is_finite() {
+                    return None;
+                }
+                let sum: f32 = delays
+                    .iter()
+                    .map(|(delay, weight)| delay.unwrap_or(max_wait).as_secs_f32() * weight)
+                    .sum();
+                Some(Duration::try_from_secs_f32(sum / total).unwrap_or(max_wait))
+            }
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "median" => Ok(Aggregate::Median),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            "weighted" => Ok(Aggregate::Weighted),
+            _ => Err("unknown probe aggregate function"),
+        }
+    }
+}
+
+impl Probe {
+    /// Run the probe via `server`.
+    pub async fn run(&self, server: &ProxyServer) -> io::Result<()> {
+        match self {
+            Probe::Dns { name } => probe_dns(server, name).await,
+            Probe::Http {
+                dest,
+                host,
+                path,
+                tls,
+                status,
+            } => probe_http(server, dest, host, path, *tls, *status).await,
+            Probe::Tls { dest, host } => probe_tls(server, dest, host).await,
+            Probe::Tcp { dest } => {
+                let stream = server.connect_fresh(dest, None::<&[u8]>).await?;
+                stream.shutdown(Shutdown::Both)
+            }
+        }
+    }
+}
+
+fn build_dns_query(name: &str, tid: u16) -> io::Result<Vec<u8>> {
+    let mut query = vec![0, 0]; // length
+    query.extend_from_slice(&tid.to_be_bytes());
+    query.extend_from_slice(&[
+        1, 32, // standard query
+        0, 1, // one query
+        0, 0, // answer
+        0, 0, // authority
+        0, 0, // addition
+    ]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "label too long"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[
+        0, // root
+        0, 1, // query: type A
+        0, 1, // query: class IN
+    ]);
+    let len = (query.len() - 2) as u16;
+    query[..2].copy_from_slice(&len.to_be_bytes());
+    Ok(query)
+}
+
+async fn probe_dns(server: &ProxyServer, name: &str) -> io::Result<()> {
+    let tid = rand::random();
+    let request = build_dns_query(name, tid)?;
+    let mut buf = [0u8; 12];
+    let test_dns = server.test_dns().into();
+    let mut stream = server.connect_fresh(&test_dns, Some(request)).await?;
+    stream.read_exact(&mut buf).await?;
+    stream.shutdown(Shutdown::Both)?;
+    if buf[2..4] == tid.to_be_bytes() {
+        Ok(())
+    } else {
+        Err(io::Error::new(ErrorKind::Other, "unknown response"))
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn error_tls_disabled() -> io::Error {
+    io::Error::new(ErrorKind::Other, "TLS support disabled during compiling")
+}
+
+async fn probe_http(
+    server: &ProxyServer,
+    dest: &Destination,
+    host: &str,
+    path: &str,
+    tls: bool,
+    status: u16,
+) -> io::Result<()> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: moproxy/{}\r\nConnection: close\r\n\r\n",
+        path,
+        dest,
+        env!("CARGO_PKG_VERSION")
+    );
+    let code = if tls {
+        #[cfg(feature = "tls")]
+        {
+            let stream = server.connect_fresh(dest, None::<&[u8]>).await?;
+            let mut stream = tls::connect(host, stream, &["http/1.1"]).await?;
+            stream.write_all(request.as_bytes()).await?;
+            read_status(&mut stream).await?
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = host;
+            return Err(error_tls_disabled());
+        }
+    } else {
+        let mut stream = server
+            .connect_fresh(dest, Some(request.into_bytes()))
+            .await?;
+        let code = read_status(&mut stream).await?;
+        stream.shutdown(Shutdown::Both)?;
+        code
+    };
+    if code == status {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            format!("unexpected HTTP status {}", code),
+        ))
+    }
+}
+
+/// Read status code of a HTTP response.
+async fn read_status<S>(stream: &mut S) -> io::Result<u16>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.len() >= MAX_RESPONSE_LEN {
+            return Err(io::Error::new(ErrorKind::Other, "response too long"));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before response",
+            ));
+        }
+        let mut headers = [EMPTY_HEADER; 64];
+        let mut response = Response::new(&mut headers);
+        match response.parse(&buf) {
+            // status line is parsed even if headers are incomplete
+            Ok(_) if response.code.is_some() => return Ok(response.code.unwrap()),
+            Ok(_) | Err(httparse::Error::TooManyHeaders) => continue,
+            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+        }
+    }
+}
+
+async fn probe_tls(server: &ProxyServer, dest: &Destination, host: &str) -> io::Result<()> {
+    let stream = server.connect_fresh(dest, None::<&[u8]>).await?;
+    #[cfg(feature = "tls")]
+    {
+        let mut stream = tls::connect(host, stream, &[]).await?;
+        stream.shutdown().await
+    }
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = (stream, host);
+        Err(error_tls_disabled())
+    }
+}
+
+impl FromStr for Probe {
+    type Err = &'static str;
+
+    /// Parse `dns [NAME]`, `http URL [STATUS]`, `tls HOST:PORT`, or
+    /// `tcp HOST:PORT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let kind = args.next().ok_or("probe type not specified")?;
+        let arg = args.next();
+        let probe = match kind.to_lowercase().as_str() {
+            "dns" => Probe::Dns {
+                name: arg.unwrap_or(".").into(),
+            },
+            "http" => {
+                let uri: Uri = arg
+                    .ok_or("probe URL not specified")?
+                    .parse()
+                    .or(Err("not a valid probe URL"))?;
+                let tls = match uri.scheme_str() {
+                    Some("http") => false,
+                    Some("https") => true,
+                    _ => return Err("probe URL must be http or https"),
+                };
+                if tls && cfg!(not(feature = "tls")) {
+                    return Err("TLS support disabled during compiling");
+                }
+                let host = uri.host().ok_or("probe URL without host")?;
+                let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+                let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                let status = match args.next() {
+                    Some(status) => status.parse().or(Err("not a valid HTTP status"))?,
+                    None => 200,
+                };
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let addr = match host.parse::<IpAddr>() {
+                    Ok(ip) => Address::Ip(ip),
+                    Err(_) => Address::Domain(host.into()),
+                };
+                Probe::Http {
+                    dest: (addr, port).into(),
+                    host: host.into(),
+                    path: path.into(),
+                    tls,
+                    status,
+                }
+            }
+            "tls" => {
+                if cfg!(not(feature = "tls")) {
+                    return Err("TLS support disabled during compiling");
+                }
+                let dest: Destination = arg.ok_or("probe address not specified")?.parse()?;
+                Probe::Tls {
+                    host: dest.host.to_string().into(),
+                    dest,
+                }
+            }
+            "tcp" => Probe::Tcp {
+                dest: arg.ok_or("probe address not specified")?.parse()?,
+            },
+            _ => return Err("unknown probe type"),
+        };
+        if args.next().is_some() {
+            return Err("too many probe arguments");
+        }
+        Ok(probe)
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Probe::Dns { name } => write!(f, "dns {}", name),
+            Probe::Http {
+                dest,
+                path,
+                tls,
+                status,
+                ..
+            } => {
+                let scheme = if *tls { "https" } else { "http" };
+                write!(f, "http {}://{}{} {}", scheme, dest, path, status)
+            }
+            Probe::Tls { dest, .. } => write!(f, "tls {}", dest),
+            Probe::Tcp { dest } => write!(f, "tcp {}", dest),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Aggregate::Median => "median",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Weighted => "weighted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[test]
+fn test_parse_probe() {
+    let parse = |s: &str| s.parse::<Probe>();
+    assert_eq!(parse("dns").unwrap(), Probe::default());
+    assert_eq!(
+        parse("dns example.com").unwrap().to_string(),
+        "dns example.com"
+    );
+    assert_eq!(
+        parse("http http://example.com/generate_204 204").unwrap(),
+        Probe::Http {
+            dest: ("example.com", 80).into(),
+            host: "example.com".into(),
+            path: "/generate_204".into(),
+            tls: false,
+            status: 204,
+        }
+    );
+    assert_eq!(
+        parse("HTTP http://192.0.2.1:8080").unwrap().to_string(),
+        "http http://192.0.2.1:8080/ 200"
+    );
+    assert_eq!(
+        parse("tcp 192.0.2.1:443").unwrap().to_string(),
+        "tcp 192.0.2.1:443"
+    );
+    assert!(parse("").is_err());
+    assert!(parse("ftp example.com").is_err());
+    assert!(parse("tcp example.com").is_err());
+    assert!(parse("http ftp://example.com/").is_err());
+    assert!(parse("dns example.com extra").is_err());
+}
+
+#[test]
+fn test_dns_query() {
+    let query = build_dns_query("example.com", 0x1234).unwrap();
+    assert_eq!(&query[..4], &[0, 29, 0x12, 0x34]);
+    assert_eq!(&query[14..27], b"\x07example\x03com\x00");
+    let root = build_dns_query(".", 0).unwrap();
+    assert_eq!(root.len(), 19);
+    assert_eq!(root[1], 17);
+}
+
+#[test]
+fn test_aggregate() {
+    let ms = |n| Some(Duration::from_millis(n));
+    let max_wait = Duration::from_millis(1000);
+    let delays = [(ms(100), 1.0), (None, 1.0), (ms(300), 2.0), (ms(200), 1.0)];
+    assert_eq!(Aggregate::Median.apply(&delays, max_wait), ms(200));
+    assert_eq!(Aggregate::Min.apply(&delays, max_wait), ms(100));
+    assert_eq!(Aggregate::Max.apply(&delays, max_wait), None);
+    assert_eq!(Aggregate::Max.apply(&delays[2..], max_wait), ms(300));
+    let weighted = Aggregate::Weighted.apply(&delays, max_wait).unwrap();
+    assert_eq!((weighted.as_secs_f32() * 1000.0).round(), 380.0);
+    assert_eq!(Aggregate::Median.apply(&delays[..2], max_wait), ms(100));
+    assert_eq!(Aggregate::Min.apply(&[(None, 1.0)], max_wait), None);
+    let weighted = |delays: &[_]| Aggregate::Weighted.apply(delays, max_wait);
+    assert_eq!(weighted(&[(ms(100), f32::INFINITY)]), None);
+    assert_eq!(weighted(&[(ms(100), f32::NAN)]), None);
+    assert_eq!(weighted(&[(ms(100), 2.0), (ms(900), -1.0)]), Some(max_wait));
+    assert_eq!("Weighted".parse(), Ok(Aggregate::Weighted));
+    assert!("mean".parse::<Aggregate>().is_err());
+}
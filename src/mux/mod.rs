@@ -0,0 +1,615 @@
+//! Multiplexed tunnel between two moproxy instances.
+//!
+//! Framing follows yamux: a 12-byte header of version (0), type, flags,
+//! stream id and length, all in network byte order, followed by `length`
+//! bytes of payload for DATA frames.
+//!
+//! Before the first frame, client sends a preamble of magic `MOPX`,
+//! version (1), length of the shared secret (one byte) and the secret.
+//! Server closes the connection if the secret mismatches. The secret
+//! itself is sent in clear text, so the connection is wrapped in TLS
+//! unless it stays on loopback.
+//!
+//! Client opens a stream by sending its first DATA frame with SYN flag.
+//! The payload starts with the destination in SOCKSv5 address format
+//! (ATYP, DST.ADDR, DST.PORT), followed by application data, so opening
+//! a stream costs no extra round trip. Server connects to the destination
+//! and pipes it with the stream, or resets the stream on failure or if
+//! the destination is denied by its policy.
+pub mod policy;
+pub mod pool;
+pub mod server;
+
+use bytes::{Buf, BytesMut};
+use futures::future::poll_fn;
+use log::{debug, trace, warn};
+use parking_lot::Mutex;
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::proxy::{
+    session::{SessionState, Transport, WriteQueue},
+    stream::AsyncStream,
+    MuxStatus, SharedStatus,
+};
+
+const MAGIC: &[u8] = b"MOPX";
+const PREAMBLE_VERSION: u8 = 1;
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 0x1;
+const FLAG_ACK: u16 = 0x2;
+const FLAG_FIN: u16 = 0x4;
+const FLAG_RST: u16 = 0x8;
+
+/// Initial receive window of each stream.
+const INITIAL_WINDOW: u32 = 256 * 1024;
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+fn protocol_error(msg: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Preamble with `secret`, which must be shorter than 256 bytes.
+fn build_preamble(secret: &[u8]) -> Vec<u8> {
+    assert!(secret.len() <= u8::MAX as usize, "secret too long");
+    let mut buf = MAGIC.to_vec();
+    buf.push(PREAMBLE_VERSION);
+    buf.push(secret.len() as u8);
+    buf.extend_from_slice(secret);
+    buf
+}
+
+/// Read the preamble from client and check its secret.
+async fn read_preamble<S>(stream: &mut S, secret: &[u8]) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).await?;
+    if &header[..4] != MAGIC || header[4] != PREAMBLE_VERSION {
+        return Err(protocol_error("mux: bad preamble"));
+    }
+    let mut received = vec![0u8; header[5] as usize];
+    stream.read_exact(&mut received).await?;
+    // compare in constant time
+    let diff = received
+        .iter()
+        .zip(secret.iter())
+        .fold(received.len() ^ secret.len(), |diff, (a, b)| {
+            diff | (a ^ b) as usize
+        });
+    if diff != 0 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "mux: wrong secret",
+        ));
+    }
+    Ok(())
+}
+
+fn put_frame(buf: &mut Vec<u8>, kind: u8, flags: u16, stream_id: u32, length: u32) {
+    buf.push(VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&stream_id.to_be_bytes());
+    buf.extend_from_slice(&length.to_be_bytes());
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    recv_buf: BytesMut,
+    recv_eof: bool,
+    /// Remaining receive window announced to peer.
+    recv_window: u32,
+    /// Bytes read but not yet released to peer via WINDOW_UPDATE.
+    recv_released: u32,
+    send_window: u32,
+    send_closed: bool,
+    /// Set SYN on the next frame of this stream.
+    syn_pending: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            recv_window: INITIAL_WINDOW,
+            send_window: INITIAL_WINDOW,
+            ..Default::default()
+        }
+    }
+
+    fn wake(&mut self) {
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+}
+
+struct State {
+    is_client: bool,
+    streams: HashMap<u32, StreamState>,
+    next_stream_id: u32,
+    /// Streams opened by peer but not accepted yet.
+    incoming: VecDeque<u32>,
+    accept_waker: Option<Waker>,
+    /// Set if no more new streams are allowed.
+    going_away: bool,
+    /// Set once the `Session` is dropped.
+    detached: bool,
+    /// Set once the connection is closed.
+    closed: Option<String>,
+    /// Headers and payloads of frames, after the preamble if client.
+    queue: WriteQueue,
+    /// Status of the proxy server to update, client only.
+    status: Option<PoolStatus>,
+}
+
+/// Shared by sessions in the same pool.
+#[derive(Clone)]
+pub(crate) struct PoolStatus {
+    pub status: Arc<Mutex<SharedStatus>>,
+    /// Number of sessions not closed yet.
+    pub alive: Arc<AtomicUsize>,
+}
+
+impl SessionState for State {
+    fn queue(&mut self) -> &mut WriteQueue {
+        &mut self.queue
+    }
+
+    /// Closed once the `Session` and all its streams are dropped.
+    fn is_finished(&self) -> bool {
+        self.detached && self.streams.is_empty()
+    }
+}
+
+impl State {
+    fn queue_frame(&mut self, kind: u8, flags: u16, stream_id: u32, length: u32) {
+        put_frame(self.queue.buf(), kind, flags, stream_id, length);
+    }
+
+    fn update_status<F: FnOnce(&mut MuxStatus)>(&self, f: F) {
+        if let Some(ref pool) = self.status {
+            if let Some(ref mut mux) = pool.status.lock().mux {
+                f(mux);
+            }
+        }
+    }
+
+    fn close(&mut self, reason: String) {
+        if let Some(ref pool) = self.status {
+            let alive = pool.alive.fetch_sub(1, Ordering::Relaxed) - 1;
+            self.update_status(|mux| mux.connected = alive > 0);
+        }
+        self.going_away = true;
+        self.closed = Some(reason);
+        self.streams.values_mut().for_each(StreamState::wake);
+        self.queue.close();
+        wake(&mut self.accept_waker);
+    }
+
+    fn stream_error(&self, id: u32) -> Option<io::Error> {
+        if self.streams.get(&id).map(|s| s.reset).unwrap_or(true) {
+            return Some(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "mux: stream reset by peer",
+            ));
+        }
+        self.closed
+            .as_ref()
+            .map(|reason| io::Error::new(ErrorKind::ConnectionAborted, reason.clone()))
+    }
+
+    /// Release `n` read bytes to the receive window of stream `id`.
+    fn release_capacity(&mut self, id: u32, n: usize) {
+        let increment = match self.streams.get_mut(&id) {
+            Some(stream) if !stream.recv_eof => {
+                stream.recv_released += n as u32;
+                if stream.recv_released < INITIAL_WINDOW / 2 {
+                    return;
+                }
+                stream.recv_window += stream.recv_released;
+                mem::replace(&mut stream.recv_released, 0)
+            }
+            _ => return,
+        };
+        self.queue_frame(TYPE_WINDOW_UPDATE, 0, id, increment);
+    }
+
+    /// Stream id opened by peer is expected to be even if we are client.
+    fn is_peer_stream(&self, id: u32) -> bool {
+        id != 0 && id.is_multiple_of(2) == self.is_client
+    }
+}
+
+/// One multiplexed connection between two moproxy.
+pub struct Session {
+    state: Arc<Mutex<State>>,
+}
+
+impl Session {
+    /// Start a session on `stream`. The connection is driven by a new task.
+    /// Only the client side opens streams, only the server accepts them.
+    /// Client sends the preamble with `secret` first.
+    pub fn new<S>(stream: S, is_client: bool, secret: &[u8]) -> Self
+    where
+        S: AsyncStream + Send + 'static,
+    {
+        Session::with_status(stream, is_client, secret, None)
+    }
+
+    pub(crate) fn with_status<S>(
+        stream: S,
+        is_client: bool,
+        secret: &[u8],
+        status: Option<PoolStatus>,
+    ) -> Self
+    where
+        S: AsyncStream + Send + 'static,
+    {
+        if let Some(ref pool) = status {
+            pool.alive.fetch_add(1, Ordering::Relaxed);
+        }
+        let state = Arc::new(Mutex::new(State {
+            is_client,
+            streams: HashMap::new(),
+            next_stream_id: if is_client { 1 } else { 2 },
+            incoming: VecDeque::new(),
+            accept_waker: None,
+            going_away: false,
+            detached: false,
+            closed: None,
+            queue: if is_client {
+                WriteQueue::new(build_preamble(secret))
+            } else {
+                Default::default()
+            },
+            status,
+        }));
+        let task = SessionTask {
+            state: state.clone(),
+        };
+        tokio::spawn(task.run(stream));
+        Session { state }
+    }
+
+    /// Whether new streams can be opened.
+    pub fn is_usable(&self) -> bool {
+        let state = self.state.lock();
+        !state.going_away && state.next_stream_id < u32::MAX - 1
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed.is_some()
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.state.lock().streams.len()
+    }
+
+    /// Open a new stream. Nothing is sent until the first write.
+    pub fn open_stream(&self) -> io::Result<MuxStream> {
+        let mut state = self.state.lock();
+        if state.going_away {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "mux: session is closing",
+            ));
+        }
+        let id = state.next_stream_id;
+        state.next_stream_id += 2;
+        let stream = StreamState {
+            syn_pending: true,
+            ..StreamState::new()
+        };
+        state.streams.insert(id, stream);
+        state.update_status(|mux| mux.streams += 1);
+        trace!("mux: stream {} opened", id);
+        Ok(MuxStream {
+            state: self.state.clone(),
+            id,
+        })
+    }
+
+    /// Wait for a stream opened by peer. Return `None` once the session
+    /// is closed.
+    pub async fn accept(&self) -> Option<MuxStream> {
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if let Some(id) = state.incoming.pop_front() {
+                return Poll::Ready(Some(MuxStream {
+                    state: self.state.clone(),
+                    id,
+                }));
+            }
+            if state.closed.is_some() {
+                return Poll::Ready(None);
+            }
+            state.accept_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if !state.going_away {
+            state.going_away = true;
+            state.queue_frame(TYPE_GO_AWAY, 0, 0, 0);
+        }
+        state.detached = true;
+        state.queue.wake_io();
+    }
+}
+
+/// Handle frames from peer.
+struct SessionTask {
+    state: Arc<Mutex<State>>,
+}
+
+impl SessionTask {
+    async fn run<S: AsyncStream>(mut self, stream: S) {
+        let mut transport = Transport::new(stream);
+        let state = self.state.clone();
+        let poll = |cx: &mut Context| transport.poll(cx, &state, |buf| self.handle_frames(buf));
+        let reason = match poll_fn(poll).await {
+            Ok(()) => {
+                debug!("mux: session closed");
+                "mux: session closed".to_string()
+            }
+            Err(err) => {
+                warn!("mux: session closed: {}", err);
+                format!("mux: session closed: {}", err)
+            }
+        };
+        state.lock().close(reason);
+    }
+
+    fn handle_frames(&mut self, buf: &mut BytesMut) -> io::Result<()> {
+        while buf.len() >= HEADER_LEN {
+            let header = &buf[..HEADER_LEN];
+            if header[0] != VERSION {
+                return Err(protocol_error("mux: unsupported version"));
+            }
+            let kind = header[1];
+            let flags = u16::from_be_bytes([header[2], header[3]]);
+            let mut id = [0u8; 4];
+            id.copy_from_slice(&header[4..8]);
+            let id = u32::from_be_bytes(id);
+            let mut length = [0u8; 4];
+            length.copy_from_slice(&header[8..12]);
+            let length = u32::from_be_bytes(length);
+            let payload_len = if kind == TYPE_DATA {
+                length as usize
+            } else {
+                0
+            };
+            if payload_len > INITIAL_WINDOW as usize {
+                return Err(protocol_error("mux: frame too large"));
+            }
+            if buf.len() < HEADER_LEN + payload_len {
+                break;
+            }
+            buf.advance(HEADER_LEN);
+            let payload = buf.split_to(payload_len);
+            self.handle_frame(kind, flags, id, length, payload)?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame(
+        &mut self,
+        kind: u8,
+        flags: u16,
+        id: u32,
+        length: u32,
+        payload: BytesMut,
+    ) -> io::Result<()> {
+        trace!("mux: frame type {} flags {} stream {}", kind, flags, id);
+        let mut state = self.state.lock();
+        match kind {
+            TYPE_DATA | TYPE_WINDOW_UPDATE => {
+                if flags & FLAG_SYN != 0 {
+                    if !state.is_peer_stream(id) || state.streams.contains_key(&id) {
+                        return Err(protocol_error("mux: unexpected SYN"));
+                    }
+                    if state.going_away {
+                        state.queue_frame(TYPE_WINDOW_UPDATE, FLAG_RST, id, 0);
+                        return Ok(());
+                    }
+                    state.streams.insert(id, StreamState::new());
+                    state.incoming.push_back(id);
+                    state.queue_frame(TYPE_WINDOW_UPDATE, FLAG_ACK, id, 0);
+                    wake(&mut state.accept_waker);
+                }
+                let stream = match state.streams.get_mut(&id) {
+                    Some(stream) => stream,
+                    None => return Ok(()), // already closed
+                };
+                if kind == TYPE_DATA {
+                    if length > stream.recv_window {
+                        return Err(protocol_error("mux: receive window exceeded"));
+                    }
+                    stream.recv_window -= length;
+                    stream.recv_buf.extend_from_slice(&payload);
+                } else {
+                    stream.send_window = stream.send_window.saturating_add(length);
+                }
+                if flags & FLAG_FIN != 0 {
+                    stream.recv_eof = true;
+                }
+                if flags & FLAG_RST != 0 {
+                    stream.reset = true;
+                }
+                stream.wake();
+            }
+            TYPE_PING => {
+                if flags & FLAG_SYN != 0 {
+                    state.queue_frame(TYPE_PING, FLAG_ACK, 0, length);
+                }
+            }
+            TYPE_GO_AWAY => {
+                debug!("mux: GO_AWAY received, code {}", length);
+                state.going_away = true;
+            }
+            _ => return Err(protocol_error("mux: unknown frame type")),
+        }
+        Ok(())
+    }
+}
+
+/// A logical stream in a `Session`.
+pub struct MuxStream {
+    state: Arc<Mutex<State>>,
+    id: u32,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Reset the stream. Pending data may be discarded.
+    pub fn reset(self) {
+        let mut state = self.state.lock();
+        if let Some(stream) = state.streams.get_mut(&self.id) {
+            if !stream.reset {
+                stream.reset = true;
+                state.queue_frame(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0);
+            }
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if let Some(stream) = state.streams.remove(&self.id) {
+            if !stream.reset && state.closed.is_none() && !(stream.send_closed && stream.recv_eof) {
+                state.queue_frame(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0);
+            }
+            state.update_status(|mux| mux.streams -= 1);
+        }
+        state.queue.wake_io();
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+        let stream = match state.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Ok(0)),
+        };
+        if !stream.recv_buf.is_empty() {
+            let n = cmp::min(buf.len(), stream.recv_buf.len());
+            buf[..n].copy_from_slice(&stream.recv_buf.split_to(n));
+            state.release_capacity(self.id, n);
+            return Poll::Ready(Ok(n));
+        }
+        if stream.recv_eof {
+            return Poll::Ready(Ok(0));
+        }
+        stream.read_waker = Some(cx.waker().clone());
+        match state.stream_error(self.id) {
+            Some(err) => Poll::Ready(Err(err)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+        if let Some(err) = state.stream_error(self.id) {
+            return Poll::Ready(Err(err));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.queue.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let stream = state.streams.get_mut(&self.id).unwrap();
+        if stream.send_closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if stream.send_window == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = cmp::min(buf.len(), MAX_FRAME_PAYLOAD);
+        let n = cmp::min(n, stream.send_window as usize);
+        stream.send_window -= n as u32;
+        let flags = if mem::replace(&mut stream.syn_pending, false) {
+            FLAG_SYN
+        } else {
+            0
+        };
+        state.queue_frame(TYPE_DATA, flags, self.id, n as u32);
+        state.queue.buf().extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Data is written by the session task.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        if let Some(err) = state.stream_error(self.id) {
+            return Poll::Ready(Err(err));
+        }
+        let stream = state.streams.get_mut(&self.id).unwrap();
+        if !stream.send_closed {
+            stream.send_closed = true;
+            let flags = if mem::replace(&mut stream.syn_pending, false) {
+                FLAG_SYN | FLAG_FIN
+            } else {
+                FLAG_FIN
+            };
+            state.queue_frame(TYPE_DATA, flags, self.id, 0);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncStream for MuxStream {
+    /// FIN closes only the sending direction.
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,161 @@
+//! Which destinations the mux server may connect to on behalf of its
+//! clients.
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// Networks denied by default: unspecified, loopback, private,
+/// shared (carrier-grade NAT) and link-local addresses.
+const DEFAULT_DENY: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`. A bare address
+/// is a network of itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().or(Err("not a valid IP address"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().or(Err("not a valid prefix length"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err("prefix length too long");
+        }
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Convert IPv4-mapped IPv6 address to IPv4.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        _ => ip,
+    }
+}
+
+/// Destinations are allowed unless they are in a denied network.
+/// Allowed networks take precedence over denied ones, so that a part
+/// of a denied network can be opened.
+///
+/// Loopback and private networks are denied by default, as the mux
+/// server would otherwise expose the network behind it to its clients.
+#[derive(Clone, Debug)]
+pub struct DestPolicy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Default for DestPolicy {
+    fn default() -> Self {
+        DestPolicy {
+            allow: vec![],
+            deny: DEFAULT_DENY
+                .iter()
+                .map(|net| net.parse().unwrap())
+                .collect(),
+        }
+    }
+}
+
+impl DestPolicy {
+    /// A policy that allows all destinations.
+    pub fn allow_all() -> Self {
+        DestPolicy {
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+
+    pub fn allow(&mut self, net: IpNet) {
+        self.allow.push(net);
+    }
+
+    pub fn deny(&mut self, net: IpNet) {
+        self.deny.push(net);
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allow.iter().any(|net| net.contains(ip))
+            || !self.deny.iter().any(|net| net.contains(ip))
+    }
+}
+
+#[test]
+fn test_ip_net() {
+    let net: IpNet = "172.16.0.0/12".parse().unwrap();
+    assert!(net.contains("172.31.255.1".parse().unwrap()));
+    assert!(!net.contains("172.32.0.1".parse().unwrap()));
+    assert!(net.contains("::ffff:172.16.0.1".parse().unwrap()));
+    let net: IpNet = "2001:db8::1".parse().unwrap();
+    assert_eq!(net.to_string(), "2001:db8::1/128");
+    assert!(net.contains("2001:db8::1".parse().unwrap()));
+    assert!(!net.contains("2001:db8::2".parse().unwrap()));
+    let net: IpNet = "0.0.0.0/0".parse().unwrap();
+    assert!(net.contains("192.0.2.1".parse().unwrap()));
+    assert!(!net.contains("::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    assert!("10.0.0/8".parse::<IpNet>().is_err());
+}
+
+#[test]
+fn test_dest_policy() {
+    let mut policy = DestPolicy::default();
+    assert!(!policy.is_allowed("127.0.0.1".parse().unwrap()));
+    assert!(!policy.is_allowed("::ffff:127.0.0.1".parse().unwrap()));
+    assert!(!policy.is_allowed("192.168.1.1".parse().unwrap()));
+    assert!(!policy.is_allowed("fd00::1".parse().unwrap()));
+    assert!(policy.is_allowed("192.0.2.1".parse().unwrap()));
+    assert!(policy.is_allowed("2001:db8::1".parse().unwrap()));
+    policy.allow("192.168.1.0/24".parse().unwrap());
+    policy.deny("192.0.2.0/24".parse().unwrap());
+    assert!(policy.is_allowed("192.168.1.1".parse().unwrap()));
+    assert!(!policy.is_allowed("192.168.2.1".parse().unwrap()));
+    assert!(!policy.is_allowed("192.0.2.1".parse().unwrap()));
+}
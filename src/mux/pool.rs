@@ -0,0 +1,111 @@
+use log::{debug, info};
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    sync::{atomic::AtomicUsize, Arc},
+};
+use tokio::io::AsyncWriteExt;
+
+use super::{MuxStream, PoolStatus, Session};
+use crate::proxy::{
+    socks5::build_address, stream::AsyncStream, Destination, MuxStatus, SharedStatus,
+};
+
+/// TLS on connections to a moproxy server.
+#[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct MuxTlsConfig {
+    /// TLS server name. Default to the host of server address.
+    pub host: Option<Box<str>>,
+    /// PEM-encoded CA certificate to trust besides the system ones.
+    #[serde(skip_serializing)]
+    pub ca: Option<Box<[u8]>>,
+}
+
+/// A small pool of sessions to a moproxy server.
+///
+/// New streams go to the session with the fewest streams. A new session
+/// is created only if all existing ones are busy and the pool is not full.
+pub struct MuxPool {
+    size: usize,
+    secret: Box<[u8]>,
+    sessions: tokio::sync::Mutex<Vec<Session>>,
+    status: PoolStatus,
+}
+
+impl fmt::Debug for MuxPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxPool").field("size", &self.size).finish()
+    }
+}
+
+impl MuxPool {
+    pub fn new(size: usize, secret: &[u8], status: Arc<Mutex<SharedStatus>>) -> Self {
+        status.lock().mux = Some(Default::default());
+        MuxPool {
+            size: size.max(1),
+            secret: secret.into(),
+            sessions: Default::default(),
+            status: PoolStatus {
+                status,
+                alive: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+
+    /// Open a stream to `dest`. The destination and `data` are sent along
+    /// with the SYN, without waiting for the server.
+    pub async fn connect<F, Fut, S>(
+        &self,
+        connect: F,
+        dest: &Destination,
+        data: Option<&[u8]>,
+    ) -> io::Result<MuxStream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncStream + Send + 'static,
+    {
+        let mut stream = {
+            let mut sessions = self.sessions.lock().await;
+            sessions.retain(Session::is_usable);
+            let idlest = sessions
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, session)| session.num_streams())
+                .map(|(i, session)| (i, session.num_streams()));
+            let index = match idlest {
+                Some((i, n)) if n == 0 || sessions.len() >= self.size => i,
+                _ => {
+                    let transport = connect().await?;
+                    let status = Some(self.status.clone());
+                    let session = Session::with_status(transport, true, &self.secret, status);
+                    sessions.push(session);
+                    info!("mux: new session, {} in pool", sessions.len());
+                    self.update_status(|mux| {
+                        mux.connected = true;
+                        mux.connections += 1;
+                    });
+                    sessions.len() - 1
+                }
+            };
+            sessions[index].open_stream()?
+        };
+        let mut preamble = Vec::with_capacity(32);
+        build_address(&mut preamble, dest);
+        if let Some(data) = data {
+            preamble.extend_from_slice(data);
+        }
+        stream.write_all(&preamble).await?;
+        debug!("mux: stream {} to {} opened", stream.id(), dest);
+        Ok(stream)
+    }
+
+    fn update_status<F: FnOnce(&mut MuxStatus)>(&self, f: F) {
+        if let Some(ref mut mux) = self.status.status.lock().mux {
+            f(mux);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use futures::StreamExt;
+use log::{debug, info, warn};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{lookup_host, TcpListener, TcpStream},
+    time::timeout,
+};
+
+use super::{policy::DestPolicy, read_preamble, MuxStream, Session};
+#[cfg(feature = "tls")]
+use crate::proxy::tls::TlsAcceptor;
+use crate::{
+    client::{check_dest_loop, ConnectedClient},
+    proxy::{
+        happy_eyeballs, sockopt::SocketOptions, socks5::read_address, stream::AsyncStream, Address,
+        Destination, ProxyServer,
+    },
+};
+
+/// Settings of the mux server.
+#[derive(Clone, Default)]
+pub struct MuxServerConfig {
+    /// Shared secret that clients must send.
+    pub secret: Box<[u8]>,
+    /// Clients must start TLS before sending the secret if set.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+    /// Destinations that clients may reach.
+    pub policy: DestPolicy,
+}
+
+/// Accept moproxy clients on `listener`, and connect to destinations
+/// directly on behalf of them.
+pub async fn run_server(
+    mut listener: TcpListener,
+    server: Arc<ProxyServer>,
+    config: MuxServerConfig,
+) {
+    let config = Arc::new(config);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = stream.set_nodelay(true) {
+                    warn!("mux: fail to set nodelay: {}", err);
+                }
+                let src = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        info!("mux: fail to get peer address: {}", err);
+                        continue;
+                    }
+                };
+                tokio::spawn(accept(stream, src, server.clone(), config.clone()));
+            }
+            Err(err) => info!("mux: error on accept client: {}", err),
+        }
+    }
+}
+
+/// Start TLS if it is configured, then serve the connection.
+async fn accept(
+    stream: TcpStream,
+    src: SocketAddr,
+    server: Arc<ProxyServer>,
+    config: Arc<MuxServerConfig>,
+) {
+    #[cfg(feature = "tls")]
+    {
+        if let Some(acceptor) = config.tls.clone() {
+            match timeout(server.handshake_timeout(), acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve(stream, src, server, config).await,
+                Ok(Err(err)) => info!("mux: TLS handshake with {} failed: {}", src, err),
+                Err(_) => info!("mux: TLS handshake with {} timed out", src),
+            }
+            return;
+        }
+    }
+    serve(stream, src, server, config).await
+}
+
+/// Check the secret, then serve all streams on a moproxy connection
+/// from `src`.
+pub async fn serve<S>(
+    mut stream: S,
+    src: SocketAddr,
+    server: Arc<ProxyServer>,
+    config: Arc<MuxServerConfig>,
+) where
+    S: AsyncStream + Send + 'static,
+{
+    match timeout(
+        server.handshake_timeout(),
+        read_preamble(&mut stream, &config.secret),
+    )
+    .await
+    {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => {
+            info!("mux: client rejected: {}", err);
+            return;
+        }
+        Err(_) => {
+            info!("mux: client rejected: timeout on preamble");
+            return;
+        }
+    }
+    let session = Session::new(stream, false, &[]);
+    while let Some(stream) = session.accept().await {
+        let server = server.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay(stream, src, server, &config.policy).await {
+                debug!("mux: error on relay: {}", err);
+            }
+        });
+    }
+    debug!("mux: session closed");
+}
+
+async fn relay(
+    mut stream: MuxStream,
+    src: SocketAddr,
+    server: Arc<ProxyServer>,
+    policy: &DestPolicy,
+) -> io::Result<()> {
+    let dest = timeout(server.handshake_timeout(), read_address(&mut stream)).await??;
+    let opts = server.socket_options();
+    let connect = connect(&dest, &src, policy, &opts);
+    let remote = match timeout(server.connect_timeout(), connect).await {
+        Ok(Ok(remote)) => remote,
+        Ok(Err(err)) => {
+            stream.reset();
+            return Err(err);
+        }
+        Err(err) => {
+            stream.reset();
+            return Err(err.into());
+        }
+    };
+    debug!("mux: stream {} => {}", stream.id(), dest);
+    ConnectedClient::new(stream, remote, dest, server)
+        .serve()
+        .await
+}
+
+/// Connect to `dest` in the same way as the direct server. Addresses
+/// of domain names are checked for loops and `policy` too, after
+/// resolving, and the denied ones are skipped.
+async fn connect(
+    dest: &Destination,
+    src: &SocketAddr,
+    policy: &DestPolicy,
+    opts: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let mut addrs: Vec<_> = match dest.host {
+        Address::Ip(addr) => vec![SocketAddr::new(addr, dest.port)],
+        Address::Domain(ref name) => lookup_host((name.as_ref(), dest.port)).await?.collect(),
+    };
+    for addr in addrs.iter() {
+        check_dest_loop(src, &(*addr).into())?;
+    }
+    addrs.retain(|addr| policy.is_allowed(addr.ip()));
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "destination not allowed",
+        ));
+    }
+    let stream = happy_eyeballs::connect(&addrs, opts).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
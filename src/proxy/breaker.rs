@@ -0,0 +1,164 @@
+//! Circuit breaker that stops clients from connecting to a failing
+//! server until it recovers.
+use serde::{Serialize, Serializer};
+use serde_derive::Serialize;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    /// Connections are allowed.
+    Closed,
+    /// Connections are skipped.
+    Open,
+    /// A limited number of trial connections are allowed.
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// `BreakerState` in an atomic.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicBreakerState(AtomicU8);
+
+impl AtomicBreakerState {
+    pub(crate) fn load(&self) -> BreakerState {
+        match self.0.load(Ordering::Relaxed) {
+            0 => BreakerState::Closed,
+            1 => BreakerState::Open,
+            _ => BreakerState::HalfOpen,
+        }
+    }
+
+    pub(crate) fn store(&self, state: BreakerState) {
+        self.0.store(state as u8, Ordering::Relaxed);
+    }
+}
+
+/// Closed until the server fails too many times, then open for a while,
+/// then half-open to let trial connections decide whether it's closed or
+/// open again.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    since: Option<Instant>,
+    /// Number of trial connections made since half-open.
+    trials: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            since: None,
+            trials: 0,
+        }
+    }
+}
+
+impl Serialize for CircuitBreaker {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.state.serialize(serializer)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    fn set_state(&mut self, state: BreakerState) {
+        self.state = state;
+        self.since = Some(Instant::now());
+        self.trials = 0;
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.since.map(|t| t.elapsed()).unwrap_or_default()
+    }
+
+    /// Whether a new connection is allowed. Counted as a trial if
+    /// half-open. Open turns into half-open after `cooldown`, so do
+    /// half-open without any result of its trials, in case they got
+    /// cancelled.
+    pub fn allow(&mut self, cooldown: Duration, max_trials: u32) -> bool {
+        match self.state {
+            BreakerState::Closed => return true,
+            BreakerState::Open if self.elapsed() < cooldown => return false,
+            BreakerState::Open => self.set_state(BreakerState::HalfOpen),
+            BreakerState::HalfOpen if self.elapsed() >= cooldown => {
+                self.set_state(BreakerState::HalfOpen)
+            }
+            BreakerState::HalfOpen => (),
+        }
+        if self.trials < max_trials {
+            self.trials += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Stop connections.
+    pub fn open(&mut self) {
+        self.set_state(BreakerState::Open);
+    }
+
+    /// Let trial connections through if open. Called once the server
+    /// passed a probe.
+    pub fn half_open(&mut self) {
+        if self.state == BreakerState::Open {
+            self.set_state(BreakerState::HalfOpen);
+        }
+    }
+
+    /// A connection succeed.
+    pub fn on_success(&mut self) {
+        if self.state != BreakerState::Closed {
+            self.set_state(BreakerState::Closed);
+        }
+    }
+
+    /// A connection failed. Re-open if it's a trial.
+    pub fn on_failure(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            self.open();
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let cooldown = Duration::from_millis(50);
+    let mut breaker = CircuitBreaker::default();
+    assert!(breaker.allow(cooldown, 1));
+    breaker.open();
+    assert!(!breaker.allow(cooldown, 1));
+
+    std::thread::sleep(cooldown);
+    assert!(breaker.allow(cooldown, 1));
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    assert!(!breaker.allow(cooldown, 1));
+    breaker.on_failure();
+    assert_eq!(breaker.state(), BreakerState::Open);
+
+    breaker.half_open();
+    assert!(breaker.allow(cooldown, 2));
+    assert!(breaker.allow(cooldown, 2));
+    assert!(!breaker.allow(cooldown, 2));
+    breaker.on_success();
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    assert!(breaker.allow(cooldown, 2));
+}
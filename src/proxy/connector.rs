@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::{collections::HashMap, fmt, future::Future, io, pin::Pin, sync::Arc};
+
+use super::{stream::AsyncStream, Destination, ServerAddr};
+
+pub type BoxedStream = Box<dyn AsyncStream + Send>;
+pub type ConnectFuture<'a> = Pin<Box<dyn Future<Output = io::Result<BoxedStream>> + Send + 'a>>;
+
+/// Connect to destinations via a custom proxy protocol.
+pub trait Connector: Send + Sync {
+    /// Return a stream connected to `dest`, with `data` (if any) already
+    /// sent to it.
+    fn connect<'a>(&'a self, dest: &'a Destination, data: Option<Bytes>) -> ConnectFuture<'a>;
+}
+
+impl fmt::Debug for dyn Connector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+/// Build a connector for the server on `addr` with all attributes of
+/// the server from the server list.
+pub type ConnectorFactory = dyn Fn(&ServerAddr, &HashMap<String, String>) -> Result<Arc<dyn Connector>, &'static str>
+    + Send
+    + Sync;
+
+lazy_static! {
+    static ref FACTORIES: RwLock<HashMap<String, Arc<ConnectorFactory>>> = Default::default();
+}
+
+/// Register a connector factory under the protocol name, so that servers
+/// with `protocol=NAME` in the server list will use it.
+/// Names are case insensitive. Registering an existing name replaces it.
+pub fn register_connector<F>(name: &str, factory: F)
+where
+    F: Fn(&ServerAddr, &HashMap<String, String>) -> Result<Arc<dyn Connector>, &'static str>
+        + Send
+        + Sync
+        + 'static,
+{
+    FACTORIES
+        .write()
+        .insert(name.to_lowercase(), Arc::new(factory));
+}
+
+/// Build a connector using the factory registered under the protocol name.
+/// Return `None` if no such factory.
+pub fn build_connector(
+    name: &str,
+    addr: &ServerAddr,
+    props: &HashMap<String, String>,
+) -> Option<Result<Arc<dyn Connector>, &'static str>> {
+    let factory = FACTORIES.read().get(&name.to_lowercase())?.clone();
+    Some(factory(addr, props))
+}
@@ -4,20 +4,19 @@ use std::{
     fmt,
     future::Future,
     io,
-    net::Shutdown,
     ops::Neg,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
     thread_local,
+    time::Duration,
 };
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
-};
+use tokio::time::{delay_for, Delay, Instant};
 
 use self::Side::{Left, Right};
-use crate::proxy::{ProxyServer, Traffic};
+#[cfg(target_os = "linux")]
+use crate::proxy::splice::Pipe;
+use crate::proxy::{stream::AsyncStream, ProxyServer, Traffic};
 
 #[derive(Debug, Clone)]
 enum Side {
@@ -45,6 +44,18 @@ impl Neg for Side {
     }
 }
 
+/// Why a pipe is closed before both sides reach EOF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// Timed out by TCP, either by keepalive or on retransmission. They
+    /// cannot be told apart once the socket fails.
+    TimedOut,
+    /// Nothing received from either side within the idle timeout.
+    IdleTimeout,
+    /// Reached the maximum lifetime.
+    MaxLifetime,
+}
+
 macro_rules! try_poll {
     ($expr:expr) => {
         match $expr {
@@ -61,17 +72,24 @@ thread_local!(
     static SHARED_BUFFER: RefCell<[u8; BUF_SIZE]> = RefCell::new([0u8; BUF_SIZE]);
 );
 
-struct StreamWithBuffer {
-    pub stream: TcpStream,
+struct StreamWithBuffer<S> {
+    pub stream: S,
     buf: Option<Box<[u8]>>,
     pos: usize,
     cap: usize,
     pub read_eof: bool,
     pub all_done: bool,
+    /// Data read from this stream waiting to be written to the other,
+    /// only if both are TCP sockets.
+    #[cfg(target_os = "linux")]
+    pipe: Option<Pipe>,
 }
 
-impl StreamWithBuffer {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S> StreamWithBuffer<S>
+where
+    S: AsyncStream,
+{
+    pub fn new(stream: S) -> Self {
         StreamWithBuffer {
             stream,
             buf: None,
@@ -79,6 +97,8 @@ impl StreamWithBuffer {
             cap: 0,
             read_eof: false,
             all_done: false,
+            #[cfg(target_os = "linux")]
+            pipe: None,
         }
     }
 
@@ -105,11 +125,29 @@ impl StreamWithBuffer {
         Poll::Ready(Ok(n))
     }
 
-    pub fn poll_write_buffer_to(
+    /// Move data from the pipe to the private buffer, so that it can be
+    /// written with `poll_write_buffer_to()`. Buffer must be empty.
+    #[cfg(target_os = "linux")]
+    fn spill_pipe(&mut self) -> io::Result<()> {
+        let pipe = self.pipe.as_mut().expect("no pipe");
+        let buf = self
+            .buf
+            .get_or_insert_with(|| vec![0; BUF_SIZE].into_boxed_slice());
+        let n = pipe.read(buf)?;
+        trace!("{} bytes spilled from pipe", n);
+        self.pos = 0;
+        self.cap = n;
+        Ok(())
+    }
+
+    pub fn poll_write_buffer_to<W>(
         &mut self,
         cx: &mut Context,
-        writer: &mut TcpStream,
-    ) -> Poll<io::Result<usize>> {
+        writer: &mut W,
+    ) -> Poll<io::Result<usize>>
+    where
+        W: AsyncStream,
+    {
         let writer = Pin::new(writer);
 
         let result = if let Some(ref buf) = self.buf {
@@ -146,70 +184,262 @@ impl StreamWithBuffer {
     }
 }
 
-// Pipe two TcpStream in both direction,
+// Pipe two streams in both direction,
 // update traffic amount to ProxyServer on the fly.
-pub struct BiPipe {
-    left: StreamWithBuffer,
-    right: StreamWithBuffer,
+// Once one side reach EOF, the other side is half closed if supported,
+// otherwise piping of both directions is stopped.
+// Piping is also stopped on idle timeout or maximum lifetime of the
+// server's pipe options.
+pub struct BiPipe<L, R> {
+    left: StreamWithBuffer<L>,
+    right: StreamWithBuffer<R>,
     server: Arc<ProxyServer>,
     traffic: Traffic,
+    idle_timeout: Option<Duration>,
+    /// Time of the last data received from either side.
+    last_active: Instant,
+    idle_timer: Option<Delay>,
+    lifetime_timer: Option<Delay>,
+    closed_by: Option<CloseReason>,
 }
 
-pub fn pipe(left: TcpStream, right: TcpStream, server: Arc<ProxyServer>) -> BiPipe {
-    let (left, right) = (StreamWithBuffer::new(left), StreamWithBuffer::new(right));
+pub fn pipe<L, R>(left: L, right: R, server: Arc<ProxyServer>) -> BiPipe<L, R>
+where
+    L: AsyncStream,
+    R: AsyncStream,
+{
+    #[allow(unused_mut)]
+    let (mut left, mut right) = (StreamWithBuffer::new(left), StreamWithBuffer::new(right));
+    #[cfg(target_os = "linux")]
+    {
+        if left.stream.splice_fd().is_some() && right.stream.splice_fd().is_some() {
+            match Pipe::new().and_then(|l| Ok((l, Pipe::new()?))) {
+                Ok((l, r)) => {
+                    left.pipe = Some(l);
+                    right.pipe = Some(r);
+                }
+                Err(err) => debug!("fail to create pipe, fallback to copy: {}", err),
+            }
+        }
+    }
+    let options = server.pipe_options();
     BiPipe {
         left,
         right,
         server,
         traffic: Default::default(),
+        idle_timeout: options.idle_timeout,
+        last_active: Instant::now(),
+        idle_timer: options.idle_timeout.map(delay_for),
+        lifetime_timer: options.max_lifetime.map(delay_for),
+        closed_by: None,
     }
 }
 
-impl BiPipe {
+impl<L, R> BiPipe<L, R>
+where
+    L: AsyncStream,
+    R: AsyncStream,
+{
+    /// Amount of traffic piped so far.
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    /// Why the pipe is closed, `None` if closed by EOF or other errors.
+    pub fn closed_by(&self) -> Option<CloseReason> {
+        self.closed_by
+    }
+
     fn poll_one_side(&mut self, cx: &mut Context, side: Side) -> Poll<io::Result<()>> {
         let Self {
             ref mut left,
             ref mut right,
             ref mut server,
             ref mut traffic,
+            ..
         } = *self;
-        let (reader, writer) = match side {
-            Left => (left, right),
-            Right => (right, left),
+        let result = match side {
+            Left => poll_one_direction(cx, left, right, server, traffic, side),
+            Right => poll_one_direction(cx, right, left, server, traffic, side),
         };
-        loop {
-            // read something if buffer is empty
-            if reader.is_empty() && !reader.read_eof {
-                let n = try_poll!(reader.poll_read_to_buffer(cx));
-                let amt = match side {
-                    Left => (n, 0),
-                    Right => (0, n),
-                }
-                .into();
-                server.add_traffic(amt);
-                *traffic += amt;
+        if let Poll::Ready(Err(ref err)) = result {
+            if err.kind() == io::ErrorKind::TimedOut {
+                self.closed_by = Some(CloseReason::TimedOut);
+            }
+        }
+        result
+    }
+
+    fn poll_timers(&mut self, cx: &mut Context) -> Poll<CloseReason> {
+        if let Some(ref mut timer) = self.lifetime_timer {
+            if Pin::new(timer).poll(cx).is_ready() {
+                return Poll::Ready(CloseReason::MaxLifetime);
             }
-            // write out if buffer is not empty
-            while !reader.is_empty() {
-                try_poll!(reader.poll_write_buffer_to(cx, &mut writer.stream));
+        }
+        if let (Some(timeout), Some(timer)) = (self.idle_timeout, self.idle_timer.as_mut()) {
+            // the timer is reset lazily on fire rather than on every read
+            while Pin::new(&mut *timer).poll(cx).is_ready() {
+                let deadline = self.last_active + timeout;
+                if deadline <= Instant::now() {
+                    return Poll::Ready(CloseReason::IdleTimeout);
+                }
+                timer.reset(deadline);
             }
-            // flush and does half close if seen eof
-            if reader.read_eof {
-                try_poll!(Pin::new(&mut writer.stream).poll_flush(cx));
-                if let Err(err) = writer.stream.shutdown(Shutdown::Write) {
-                    debug!("fail to shutdown: {}", err);
+        }
+        Poll::Pending
+    }
+}
+
+fn poll_one_direction<A, B>(
+    cx: &mut Context,
+    reader: &mut StreamWithBuffer<A>,
+    writer: &mut StreamWithBuffer<B>,
+    server: &ProxyServer,
+    traffic: &mut Traffic,
+    side: Side,
+) -> Poll<io::Result<()>>
+where
+    A: AsyncStream,
+    B: AsyncStream,
+{
+    #[cfg(target_os = "linux")]
+    {
+        if reader.pipe.is_some() {
+            return poll_one_direction_splice(cx, reader, writer, server, traffic, side);
+        }
+    }
+    loop {
+        // read something if buffer is empty
+        if reader.is_empty() && !reader.read_eof {
+            let n = match reader.poll_read_to_buffer(cx) {
+                Poll::Pending => {
+                    // Streams like TLS or WebSocket may buffer written data,
+                    // flush it out before waiting for more.
+                    try_poll!(Pin::new(&mut writer.stream).poll_flush(cx));
+                    return Poll::Pending;
                 }
-                reader.all_done = true;
-                return Poll::Ready(Ok(()));
+                Poll::Ready(result) => result?,
+            };
+            let amt = match side {
+                Left => (n, 0),
+                Right => (0, n),
+            }
+            .into();
+            server.add_traffic(amt);
+            *traffic += amt;
+        }
+        // write out if buffer is not empty
+        while !reader.is_empty() {
+            try_poll!(reader.poll_write_buffer_to(cx, &mut writer.stream));
+        }
+        // flush and does half close if seen eof
+        if reader.read_eof {
+            return poll_close_writer(cx, reader, writer);
+        }
+    }
+}
+
+/// Same as `poll_one_direction()` but move data through a pipe with
+/// splice(2), without copying into user space.
+///
+/// Readiness of the reader is polled with `poll_peek()`. Since there is
+/// no way to wait for the writer being writable other than writing to it,
+/// data in the pipe is spilled into the buffer and written as usual once
+/// the writer blocks.
+#[cfg(target_os = "linux")]
+fn poll_one_direction_splice<A, B>(
+    cx: &mut Context,
+    reader: &mut StreamWithBuffer<A>,
+    writer: &mut StreamWithBuffer<B>,
+    server: &ProxyServer,
+    traffic: &mut Traffic,
+    side: Side,
+) -> Poll<io::Result<()>>
+where
+    A: AsyncStream,
+    B: AsyncStream,
+{
+    let (reader_fd, writer_fd) = match (reader.stream.splice_fd(), writer.stream.splice_fd()) {
+        (Some(reader_fd), Some(writer_fd)) => (reader_fd, writer_fd),
+        _ => unreachable!("splice on non-TCP stream"),
+    };
+    loop {
+        // write out spilled data first
+        while !reader.is_empty() {
+            try_poll!(reader.poll_write_buffer_to(cx, &mut writer.stream));
+        }
+        // move data from pipe to writer
+        let pipe = reader.pipe.as_mut().expect("no pipe");
+        if !pipe.is_empty() {
+            match pipe.splice_to(writer_fd) {
+                Ok(n) => trace!("{} bytes spliced out", n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => reader.spill_pipe()?,
+                Err(err) => return Poll::Ready(Err(err)),
             }
+            continue;
+        }
+        if reader.read_eof {
+            return poll_close_writer(cx, reader, writer);
         }
+        // move data from reader to pipe once readable
+        try_poll!(reader.stream.poll_peek(cx, &mut [0u8; 1]));
+        let n = match pipe.splice_from(reader_fd) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        trace!("{} bytes spliced in", n);
+        if n == 0 {
+            reader.read_eof = true;
+            continue;
+        }
+        let amt = match side {
+            Left => (n, 0),
+            Right => (0, n),
+        }
+        .into();
+        server.add_traffic(amt);
+        *traffic += amt;
+    }
+}
+
+/// Flush and half close the writer after the reader reached EOF.
+fn poll_close_writer<A, B>(
+    cx: &mut Context,
+    reader: &mut StreamWithBuffer<A>,
+    writer: &mut StreamWithBuffer<B>,
+) -> Poll<io::Result<()>>
+where
+    A: AsyncStream,
+    B: AsyncStream,
+{
+    try_poll!(Pin::new(&mut writer.stream).poll_flush(cx));
+    if writer.stream.supports_half_close() {
+        // TLS and others may need several polls to send out the close.
+        match Pin::new(&mut writer.stream).poll_shutdown(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => debug!("fail to shutdown: {}", err),
+            Poll::Ready(Ok(())) => (),
+        }
+    } else {
+        // Cannot close one direction only, close both.
+        trace!("half close unsupported, stop piping");
+        writer.all_done = true;
     }
+    reader.all_done = true;
+    Poll::Ready(Ok(()))
 }
 
-impl Future for BiPipe {
+impl<L, R> Future for BiPipe<L, R>
+where
+    L: AsyncStream,
+    R: AsyncStream,
+{
     type Output = io::Result<Traffic>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Traffic>> {
+        let traffic = self.traffic;
         if !self.left.all_done {
             trace!("poll left");
             if let Poll::Ready(Err(err)) = self.poll_one_side(cx, Left) {
@@ -224,10 +454,17 @@ impl Future for BiPipe {
         }
         if self.left.all_done && self.right.all_done {
             trace!("all done");
-            Poll::Ready(Ok(self.traffic))
-        } else {
-            trace!("pending");
-            Poll::Pending
+            return Poll::Ready(Ok(self.traffic));
+        }
+        if self.traffic != traffic {
+            self.last_active = Instant::now();
+        }
+        if let Poll::Ready(reason) = self.poll_timers(cx) {
+            debug!("stop piping: {:?}", reason);
+            self.closed_by = Some(reason);
+            return Poll::Ready(Ok(self.traffic));
         }
+        trace!("pending");
+        Poll::Pending
     }
 }
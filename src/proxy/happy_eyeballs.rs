@@ -0,0 +1,120 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::{net::TcpStream, time::delay_for};
+
+use super::sockopt::{self, SocketOptions};
+
+/// Delay between two connection attempts, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Reorder addresses by interleaving address families, starting with
+/// the family of the first address (RFC 8305 section 4).
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return vec![],
+    };
+    let (mut primary, mut secondary): (Vec<SocketAddr>, Vec<_>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut sorted = Vec::with_capacity(addrs.len());
+    primary.reverse();
+    secondary.reverse();
+    while !primary.is_empty() || !secondary.is_empty() {
+        sorted.extend(primary.pop());
+        sorted.extend(secondary.pop());
+    }
+    sorted
+}
+
+/// Connect to one of the addresses. Attempts are started one by one in
+/// the order of `interleave()`, the next one is started once the previous
+/// one failed or has not finished within `CONNECTION_ATTEMPT_DELAY`.
+/// Return the first established connection and cancel the others.
+///
+/// With TCP Fast Open, connecting may finish before any packet is sent,
+/// so attempts cannot be raced. Only the first address is tried then,
+/// and its failure shows up on the first read or write.
+pub async fn connect(addrs: &[SocketAddr], opts: &SocketOptions) -> io::Result<TcpStream> {
+    match addrs {
+        [addr] => return sockopt::connect(addr, opts).await,
+        [addr, ..] if opts.fast_open => return sockopt::connect(addr, opts).await,
+        _ => (),
+    }
+    let mut addrs = interleave(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.push(async move {
+                let result = sockopt::connect(&addr, opts).await;
+                if let Err(ref err) = result {
+                    debug!("fail to connect {}: {}", addr, err);
+                }
+                result
+            });
+        }
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(ErrorKind::AddrNotAvailable, "no address to connect")
+            }));
+        }
+        let has_next = addrs.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            },
+            _ = delay_for(CONNECTION_ATTEMPT_DELAY), if has_next => (),
+        }
+    }
+}
+
+#[test]
+fn test_interleave() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[2001:db8::1]:80".parse().unwrap(),
+        "[2001:db8::2]:80".parse().unwrap(),
+        "[2001:db8::3]:80".parse().unwrap(),
+        "192.0.2.1:80".parse().unwrap(),
+    ];
+    let sorted = interleave(&addrs);
+    assert_eq!(sorted, vec![addrs[0], addrs[3], addrs[1], addrs[2]]);
+    assert!(interleave(&[]).is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_fast_open_no_fallback() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_addr = closed.local_addr().unwrap();
+    drop(closed);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs = [closed_addr, listener.local_addr().unwrap()];
+
+    let opts = Default::default();
+    assert!(connect(&addrs, &opts).await.is_ok());
+
+    let opts = SocketOptions {
+        fast_open: true,
+        ..Default::default()
+    };
+    // refused on connecting, or on the first I/O if connecting is deferred
+    let err = match connect(&addrs, &opts).await {
+        Ok(mut stream) => {
+            let _ = stream.write_all(b"x").await;
+            stream.read(&mut [0u8; 1]).await.unwrap_err()
+        }
+        Err(err) => err,
+    };
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+}
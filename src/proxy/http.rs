@@ -2,17 +2,18 @@ use httparse::{Response, Status, EMPTY_HEADER};
 use log::{debug, trace};
 use std::io::{self, ErrorKind};
 use std::net::IpAddr;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::proxy::{Address, Destination};
+use crate::proxy::{
+    not_server_fault,
+    stream::{self, AsyncStream},
+    Address, Destination,
+};
 
 macro_rules! ensure_200 {
     ($code:expr) => {
         if $code != 200 {
-            return Err(io::Error::new(
+            return Err(not_server_fault(
                 ErrorKind::Other,
                 format!("proxy return error: {}", $code),
             ));
@@ -21,17 +22,19 @@ macro_rules! ensure_200 {
 }
 
 const BUF_LEN: usize = 1024;
+const MAX_RESPONSE_LEN: usize = 64_000;
 
-pub async fn handshake<T>(
-    stream: &mut TcpStream,
+pub async fn handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
     with_playload: bool,
 ) -> io::Result<()>
 where
+    S: AsyncStream,
     T: AsRef<[u8]> + 'static,
 {
-    let mut buf = build_request(addr).into_bytes();
+    let buf = build_request(addr).into_bytes();
     stream.write_all(&buf).await?;
 
     if with_playload {
@@ -42,6 +45,26 @@ where
     }
 
     // Parse HTTP response
+    if stream.supports_peek() {
+        read_response_with_peek(stream, buf).await?;
+    } else {
+        read_response_bytewise(stream).await?;
+    }
+
+    // Write out payload if exist
+    if !with_playload {
+        if let Some(ref data) = data {
+            stream.write_all(data.as_ref()).await?;
+        }
+    }
+    trace!("HTTP CONNECT handshaking done");
+    Ok(())
+}
+
+async fn read_response_with_peek<S>(stream: &mut S, mut buf: Vec<u8>) -> io::Result<()>
+where
+    S: AsyncStream,
+{
     buf.clear();
     let mut bytes_read = 0;
     let mut sink = [0u8; BUF_LEN];
@@ -49,7 +72,7 @@ where
         let mut headers = [EMPTY_HEADER; 16];
         let mut response = Response::new(&mut headers);
         buf.resize(bytes_read + BUF_LEN, 0);
-        let peek_len = stream.peek(&mut buf).await?;
+        let peek_len = stream::peek(stream, &mut buf).await?;
         bytes_read += peek_len;
         trace!("bytes peek: {}", bytes_read);
 
@@ -60,7 +83,7 @@ where
                 if let Some(code) = response.code {
                     ensure_200!(code);
                 }
-                if bytes_read > 64_000 {
+                if bytes_read > MAX_RESPONSE_LEN {
                     return Err(io::Error::new(ErrorKind::Other, "response too large"));
                 }
                 // Drop peeked data from socket buffer
@@ -75,19 +98,36 @@ where
                 ensure_200!(response.code.unwrap());
                 let len = peek_len - (bytes_read - bytes_request);
                 stream.read(&mut sink[..len]).await?;
-                break;
+                return Ok(());
             }
         }
     }
+}
 
-    // Write out payload if exist
-    if !with_playload {
-        if let Some(ref data) = data {
-            stream.write_all(data.as_ref()).await?;
+/// Read the response one byte at a time to avoid consuming any data
+/// after it. Used on streams that cannot peek.
+async fn read_response_bytewise<S>(stream: &mut S) -> io::Result<()>
+where
+    S: AsyncStream,
+{
+    let mut buf = Vec::with_capacity(BUF_LEN);
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() > MAX_RESPONSE_LEN {
+            return Err(io::Error::new(ErrorKind::Other, "response too large"));
+        }
+        buf.push(stream.read_u8().await?);
+    }
+    let mut headers = [EMPTY_HEADER; 16];
+    let mut response = Response::new(&mut headers);
+    match response.parse(&buf) {
+        Err(e) => Err(io::Error::new(ErrorKind::Other, e)),
+        Ok(Status::Partial) => Err(io::Error::new(ErrorKind::Other, "incomplete response")),
+        Ok(Status::Complete(_)) => {
+            trace!("response {}", response.code.unwrap());
+            ensure_200!(response.code.unwrap());
+            Ok(())
         }
     }
-    trace!("HTTP CONNECT handshaking done");
-    Ok(())
 }
 
 fn build_request(addr: &Destination) -> String {
@@ -0,0 +1,402 @@
+//! HTTP/2 client for CONNECT tunnels, on top of the `h2` crate.
+//!
+//! If the server enables extended CONNECT (RFC 8441), streams are opened
+//! with the `connect-tcp` protocol, with the destination in the path of
+//! the well-known URI template `/.well-known/masque/tcp/{host}/{port}/`.
+//! Otherwise, plain CONNECT (RFC 7540 section 8.3) is used.
+//!
+//! All streams to a server share one connection, which is driven by a
+//! background task.
+use bytes1::Bytes;
+use futures::ready;
+use h2::{
+    client::{self, SendRequest},
+    ext::Protocol,
+    Ping, RecvStream, SendStream,
+};
+use http::{Method, Request, Uri};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use std::{
+    cmp, fmt,
+    future::Future,
+    io::{self, ErrorKind},
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{
+    compat::Compat, not_server_fault, stream::AsyncStream, Address, Destination, MuxStatus,
+    SharedStatus,
+};
+
+const STREAM_WINDOW_SIZE: u32 = 1024 * 1024;
+const CONNECTION_WINDOW_SIZE: u32 = 4 * 1024 * 1024;
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct Http2Config {
+    /// Do TLS handshake (with ALPN `h2`) before HTTP/2.
+    pub tls: bool,
+    /// TLS server name. Default to the host of server address.
+    pub host: Option<Box<str>>,
+}
+
+fn h2_error(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        return err.into_io().unwrap();
+    }
+    io::Error::new(ErrorKind::Other, err)
+}
+
+/// Path of `connect-tcp` request, host is percent-encoded as required by
+/// URI template (RFC 6570).
+fn connect_tcp_path(dest: &Destination) -> String {
+    let host = match dest.host {
+        Address::Ip(IpAddr::V6(ref ip)) => ip.to_string().replace(':', "%3A"),
+        ref host => host.to_string(),
+    };
+    format!("/.well-known/masque/tcp/{}/{}/", host, dest.port)
+}
+
+/// CONNECT request to `dest`, extended with the `connect-tcp` protocol
+/// if `extended`.
+fn connect_request(target: &Target, dest: &Destination, extended: bool) -> io::Result<Request<()>> {
+    let builder = Request::builder().method(Method::CONNECT);
+    let builder = if extended {
+        let uri = Uri::builder()
+            .scheme(target.scheme)
+            .authority(target.authority.as_str())
+            .path_and_query(connect_tcp_path(dest).as_str())
+            .build();
+        builder
+            .uri(uri.map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?)
+            .extension(Protocol::from_static("connect-tcp"))
+    } else {
+        builder.uri(dest.to_string())
+    };
+    builder
+        .body(())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    /// No more stream can be opened on it.
+    unusable: AtomicBool,
+    /// It is the connection of the pool, not replaced by a new one.
+    current: AtomicBool,
+}
+
+struct Connection {
+    send: SendRequest<Bytes>,
+    state: Arc<ConnectionState>,
+}
+
+async fn run_connection<S: AsyncStream>(
+    connection: client::Connection<Compat<S>, Bytes>,
+    state: Arc<ConnectionState>,
+    status: Arc<Mutex<SharedStatus>>,
+) {
+    match connection.await {
+        Ok(()) => debug!("http2 connection closed"),
+        Err(err) => warn!("http2 connection closed: {}", err),
+    }
+    state.unusable.store(true, Ordering::Relaxed);
+    if state.current.load(Ordering::Relaxed) {
+        update_mux_status(&status, |mux| mux.connected = false);
+    }
+}
+
+fn update_mux_status<F: FnOnce(&mut MuxStatus)>(status: &Mutex<SharedStatus>, f: F) {
+    if let Some(ref mut mux) = status.lock().mux {
+        f(mux)
+    }
+}
+
+/// The proxy server itself, as the target of extended CONNECT requests.
+#[derive(Debug)]
+pub struct Target {
+    /// `https` if over TLS, otherwise `http`.
+    pub scheme: &'static str,
+    /// Host and port of the server.
+    pub authority: String,
+}
+
+/// The long-lived HTTP/2 connection to a server.
+/// Connected on first use, and replaced by a new one once it's closed,
+/// going away, or out of streams.
+pub struct Http2Pool {
+    connection: tokio::sync::Mutex<Option<Connection>>,
+    target: Target,
+    status: Arc<Mutex<SharedStatus>>,
+}
+
+impl fmt::Debug for Http2Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Http2Pool")
+    }
+}
+
+impl Http2Pool {
+    pub fn new(target: Target, status: Arc<Mutex<SharedStatus>>) -> Self {
+        status.lock().mux = Some(Default::default());
+        Http2Pool {
+            connection: Default::default(),
+            target,
+            status,
+        }
+    }
+
+    /// Return a usable connection and whether it is an existing one.
+    /// Create a new one with the transport returned by `connect` if
+    /// necessary.
+    async fn get_connection<F, Fut, S>(
+        &self,
+        connect: &mut Option<F>,
+    ) -> io::Result<(SendRequest<Bytes>, Arc<ConnectionState>, bool)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncStream + Send + 'static,
+    {
+        let mut current = self.connection.lock().await;
+        if let Some(ref conn) = *current {
+            if !conn.state.unusable.load(Ordering::Relaxed) {
+                return Ok((conn.send.clone(), conn.state.clone(), true));
+            }
+            conn.state.current.store(false, Ordering::Relaxed);
+        }
+        let connect = connect.take().expect("transport connected twice");
+        let stream = connect().await?;
+        let (send, mut connection) = client::Builder::new()
+            .enable_push(false)
+            .initial_window_size(STREAM_WINDOW_SIZE)
+            .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+            .handshake(Compat(stream))
+            .await
+            .map_err(h2_error)?;
+        let mut ping_pong = connection.ping_pong().expect("ping pong taken");
+        let state = Arc::new(ConnectionState::default());
+        tokio::spawn(run_connection(
+            connection,
+            state.clone(),
+            self.status.clone(),
+        ));
+        // Server sends its SETTINGS before the reply of our PING, so
+        // whether extended CONNECT can be used is known after that.
+        if let Err(err) = ping_pong.ping(Ping::opaque()).await {
+            state.unusable.store(true, Ordering::Relaxed);
+            return Err(h2_error(err));
+        }
+        info!("http2 connection established");
+        state.current.store(true, Ordering::Relaxed);
+        update_mux_status(&self.status, |mux| {
+            mux.connected = true;
+            mux.connections += 1;
+        });
+        *current = Some(Connection {
+            send: send.clone(),
+            state: state.clone(),
+        });
+        Ok((send, state, false))
+    }
+
+    /// Open a CONNECT stream to `dest`. `data` is sent without waiting for
+    /// the response. On a new connection, the stream is opened after the
+    /// SETTINGS from server is received.
+    pub async fn connect<F, Fut, S>(
+        &self,
+        connect: F,
+        dest: &Destination,
+        data: Option<&[u8]>,
+    ) -> io::Result<Http2Stream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncStream + Send + 'static,
+    {
+        let mut connect = Some(connect);
+        let (response, send) = loop {
+            let (send, state, reused) = self.get_connection(&mut connect).await?;
+            let open = async {
+                let mut send = send.ready().await.map_err(h2_error)?;
+                let extended = send.is_extended_connect_protocol_enabled();
+                let request = connect_request(&self.target, dest, extended)?;
+                send.send_request(request, false).map_err(h2_error)
+            };
+            match open.await {
+                Ok(opened) => break opened,
+                Err(err) => {
+                    // going away or out of streams, try a new connection
+                    state.unusable.store(true, Ordering::Relaxed);
+                    if !reused {
+                        return Err(err);
+                    }
+                    debug!("http2 connection unusable: {}", err);
+                }
+            }
+        };
+        let mut stream = Http2Stream {
+            send,
+            recv: None,
+            recv_buf: Bytes::new(),
+            send_closed: false,
+            status: self.status.clone(),
+        };
+        update_mux_status(&self.status, |mux| mux.streams += 1);
+        if let Some(data) = data {
+            stream
+                .send
+                .send_data(Bytes::copy_from_slice(data), false)
+                .map_err(h2_error)?;
+        }
+        let response = response.await.map_err(h2_error)?;
+        if !response.status().is_success() {
+            return Err(not_server_fault(
+                ErrorKind::Other,
+                format!("http2: CONNECT rejected with {}", response.status()),
+            ));
+        }
+        stream.recv = Some(response.into_body());
+        debug!(
+            "http2 stream {:?} to {} opened",
+            stream.send.stream_id(),
+            dest
+        );
+        Ok(stream)
+    }
+}
+
+/// A CONNECT stream on the HTTP/2 connection.
+///
+/// WINDOW_UPDATE is sent for received DATA only after it has been read
+/// out from here, so the server cannot send faster than we relay.
+pub struct Http2Stream {
+    send: SendStream<Bytes>,
+    /// Set once the response is received.
+    recv: Option<RecvStream>,
+    /// Received but not yet read.
+    recv_buf: Bytes,
+    send_closed: bool,
+    status: Arc<Mutex<SharedStatus>>,
+}
+
+impl Drop for Http2Stream {
+    /// The stream is reset by `h2` if it is not closed yet.
+    fn drop(&mut self) {
+        update_mux_status(&self.status, |mux| mux.streams -= 1);
+    }
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let recv = this.recv.as_mut().expect("response not received");
+        while this.recv_buf.is_empty() {
+            match ready!(recv.poll_data(cx)) {
+                Some(Ok(data)) => this.recv_buf = data,
+                Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = cmp::min(buf.len(), this.recv_buf.len());
+        buf[..n].copy_from_slice(&this.recv_buf.split_to(n));
+        recv.flow_control().release_capacity(n).map_err(h2_error)?;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if this.send_closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        this.send.reserve_capacity(buf.len());
+        while this.send.capacity() == 0 {
+            match ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(_)) => (),
+                Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+                None => return Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+            }
+        }
+        let n = cmp::min(buf.len(), this.send.capacity());
+        this.send
+            .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+            .map_err(h2_error)?;
+        Poll::Ready(Ok(n))
+    }
+
+    /// Data is written by the connection task.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.send_closed {
+            this.send_closed = true;
+            this.send.send_data(Bytes::new(), true).map_err(h2_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncStream for Http2Stream {
+    /// Ending the request stream leaves the response stream open.
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_connect_tcp_path() {
+    let dest = "192.0.2.1:443".parse::<std::net::SocketAddr>().unwrap();
+    assert_eq!(
+        connect_tcp_path(&dest.into()),
+        "/.well-known/masque/tcp/192.0.2.1/443/"
+    );
+    let dest = "[2001:db8::1]:80".parse::<std::net::SocketAddr>().unwrap();
+    assert_eq!(
+        connect_tcp_path(&dest.into()),
+        "/.well-known/masque/tcp/2001%3Adb8%3A%3A1/80/"
+    );
+    assert_eq!(
+        connect_tcp_path(&("example.com", 22).into()),
+        "/.well-known/masque/tcp/example.com/22/"
+    );
+}
+
+#[test]
+fn test_connect_request() {
+    let target = Target {
+        scheme: "https",
+        authority: "proxy.example.com:443".into(),
+    };
+    let dest = "[2001:db8::1]:80".parse::<std::net::SocketAddr>().unwrap();
+    let request = connect_request(&target, &dest.into(), false).unwrap();
+    assert_eq!(request.method(), Method::CONNECT);
+    assert_eq!(request.uri(), "[2001:db8::1]:80");
+    assert!(request.extensions().get::<Protocol>().is_none());
+    let request = connect_request(&target, &dest.into(), true).unwrap();
+    assert_eq!(
+        request.uri(),
+        "https://proxy.example.com:443/.well-known/masque/tcp/2001%3Adb8%3A%3A1/80/"
+    );
+    let protocol = request.extensions().get::<Protocol>().unwrap();
+    assert_eq!(protocol.as_str(), "connect-tcp");
+}
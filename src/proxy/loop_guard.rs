@@ -0,0 +1,172 @@
+//! Detection of connections looped back to moproxy itself, usually
+//! caused by wrong firewall rules.
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How long outgoing connections are remembered. A redirected
+/// connection is accepted by us right after it's made, so it is kept
+/// short to make reuse of the local port by others unlikely.
+const OUTGOING_TTL: Duration = Duration::from_secs(5);
+/// Outgoing connections are spread over shards by local port to keep
+/// lock contention low.
+const OUTGOING_SHARDS: usize = 16;
+/// Minimal interval between two warnings about loops.
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Outgoing {
+    /// Local address => (remote address, connected at).
+    conns: HashMap<SocketAddr, (SocketAddr, Instant)>,
+    next_sweep: Option<Instant>,
+}
+
+impl Outgoing {
+    /// Drop expired entries, at most once per `OUTGOING_TTL`.
+    fn sweep(&mut self, now: Instant) {
+        if matches!(self.next_sweep, Some(t) if now < t) {
+            return;
+        }
+        self.conns
+            .retain(|_, (_, since)| now.duration_since(*since) < OUTGOING_TTL);
+        self.next_sweep = Some(now + OUTGOING_TTL);
+    }
+}
+
+lazy_static! {
+    static ref LISTEN_ADDRS: Mutex<HashSet<SocketAddr>> = Default::default();
+    static ref OUTGOING: [Mutex<Outgoing>; OUTGOING_SHARDS] = Default::default();
+    static ref STARTED_AT: Instant = Instant::now();
+}
+static LOOPS_DETECTED: AtomicUsize = AtomicUsize::new(0);
+/// Milliseconds since `STARTED_AT` of the last warning, plus one.
+static LAST_WARNED: AtomicU64 = AtomicU64::new(0);
+
+fn outgoing_shard(local: &SocketAddr) -> &'static Mutex<Outgoing> {
+    &OUTGOING[local.port() as usize % OUTGOING_SHARDS]
+}
+
+/// Convert IPv4-mapped IPv6 address to IPv4.
+fn normalize(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4() {
+            Some(ip) if v6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                SocketAddr::new(ip.into(), v6.port())
+            }
+            _ => *addr,
+        },
+        _ => *addr,
+    }
+}
+
+fn is_local_ip(ip: IpAddr) -> bool {
+    if ip.is_loopback() {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        crate::tcp::is_local_ip(ip).unwrap_or(false)
+    }
+    #[cfg(not(target_os = "linux"))]
+    false
+}
+
+/// Register an address that moproxy is listening on.
+pub fn add_listen_addr(addr: SocketAddr) {
+    LISTEN_ADDRS.lock().insert(normalize(&addr));
+}
+
+/// Whether connecting to `addr` reaches one of our listeners.
+pub fn is_listen_addr(addr: &SocketAddr) -> bool {
+    let addr = normalize(addr);
+    let matched: Vec<_> = LISTEN_ADDRS
+        .lock()
+        .iter()
+        .filter(|listen| listen.port() == addr.port())
+        .cloned()
+        .collect();
+    if matched.iter().any(|listen| listen.ip() == addr.ip()) {
+        return true;
+    }
+    matched.iter().any(|listen| listen.ip().is_unspecified()) && is_local_ip(addr.ip())
+}
+
+/// Remember a connection we made from `local` to `remote`, so that it
+/// can be recognized if it is redirected back to us.
+pub fn add_outgoing(local: SocketAddr, remote: SocketAddr) {
+    let now = Instant::now();
+    let local = normalize(&local);
+    let mut outgoing = outgoing_shard(&local).lock();
+    outgoing.sweep(now);
+    outgoing.conns.insert(local, (normalize(&remote), now));
+}
+
+/// Whether a connection from `peer` to `target` is made by ourselves.
+/// `target` is the address the peer connected to, i.e. the original
+/// destination if it is NATed.
+///
+/// A connection is matched at most once, so a later connection reusing
+/// the same local port is not mistaken for a loop.
+pub fn is_outgoing(peer: &SocketAddr, target: &SocketAddr) -> bool {
+    let peer = normalize(peer);
+    let mut outgoing = outgoing_shard(&peer).lock();
+    match outgoing.conns.get(&peer) {
+        Some((remote, since)) if *remote == normalize(target) => {
+            let fresh = since.elapsed() < OUTGOING_TTL;
+            outgoing.conns.remove(&peer);
+            fresh
+        }
+        _ => false,
+    }
+}
+
+/// Count a detected loop. Return whether it should be logged, which
+/// is at most once per `WARN_INTERVAL`, so that a loop does not flood
+/// the log with one line per connection.
+pub fn count_loop() -> bool {
+    LOOPS_DETECTED.fetch_add(1, Ordering::Relaxed);
+    let now = STARTED_AT.elapsed().as_millis() as u64 + 1;
+    let last = LAST_WARNED.load(Ordering::Relaxed);
+    if last != 0 && now - last < WARN_INTERVAL.as_millis() as u64 {
+        return false;
+    }
+    LAST_WARNED
+        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+}
+
+/// Number of loops detected so far.
+pub fn loops_detected() -> usize {
+    LOOPS_DETECTED.load(Ordering::Relaxed)
+}
+
+#[test]
+fn test_listen_addr() {
+    add_listen_addr("0.0.0.0:10801".parse().unwrap());
+    add_listen_addr("192.0.2.1:10802".parse().unwrap());
+    assert!(is_listen_addr(&"127.0.0.1:10801".parse().unwrap()));
+    assert!(is_listen_addr(&"[::ffff:127.0.0.1]:10801".parse().unwrap()));
+    assert!(!is_listen_addr(&"198.51.100.7:10801".parse().unwrap()));
+    assert!(is_listen_addr(&"192.0.2.1:10802".parse().unwrap()));
+    assert!(!is_listen_addr(&"127.0.0.1:10802".parse().unwrap()));
+    assert!(!is_listen_addr(&"127.0.0.1:10803".parse().unwrap()));
+}
+
+#[test]
+fn test_outgoing() {
+    let local: SocketAddr = "127.0.0.1:10811".parse().unwrap();
+    let remote: SocketAddr = "127.0.0.1:10812".parse().unwrap();
+    add_outgoing(local, remote);
+    assert!(!is_outgoing(&local, &"127.0.0.1:10813".parse().unwrap()));
+    assert!(is_outgoing(
+        &"[::ffff:127.0.0.1]:10811".parse().unwrap(),
+        &remote
+    ));
+    // Matched once only, the port may be reused afterwards.
+    assert!(!is_outgoing(&local, &remote));
+}
@@ -1,27 +1,83 @@
+pub mod breaker;
+#[cfg(any(feature = "http2", feature = "ssh"))]
+mod compat;
+pub mod connector;
 pub mod copy;
+pub(crate) mod happy_eyeballs;
 pub mod http;
+#[cfg(feature = "http2")]
+pub mod http2;
+pub mod loop_guard;
 #[cfg(feature = "score_script")]
 use rlua::prelude::*;
+#[cfg(feature = "mux")]
+pub(crate) mod session;
+pub mod sockopt;
 pub mod socks5;
-use log::debug;
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod warm_pool;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+use bytes::Bytes;
+use log::{debug, warn};
 use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, Serializer};
 use serde_derive::Serialize;
 use std::{
     cmp,
     collections::HashSet,
+    error::Error,
     fmt,
+    future::Future,
     hash::{Hash, Hasher},
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Add, AddAssign},
+    path::PathBuf,
     str::FromStr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
-use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{self as tokio_net};
+use tokio::{sync::Notify, time::timeout};
+
+#[cfg(feature = "http2")]
+use self::http2::{Http2Config, Http2Pool};
+#[cfg(feature = "ssh")]
+use self::ssh::{SshConfig, SshPool};
+use self::{
+    breaker::{AtomicBreakerState, BreakerState, CircuitBreaker},
+    connector::Connector,
+    copy::CloseReason,
+    sockopt::{Keepalive, SocketOptions},
+    stream::{AsyncStream, ServerStream},
+    warm_pool::WarmPool,
+};
+#[cfg(feature = "websocket")]
+use self::{connector::BoxedStream, websocket::WebSocketConfig};
+use crate::monitor::probe::{Aggregate, ProbeTarget};
+#[cfg(feature = "mux")]
+use crate::mux::pool::{MuxPool, MuxTlsConfig};
 
 const GRAPHITE_PATH_PREFIX: &str = "moproxy.proxy_servers";
+const DEFAULT_POOL_MAX_IDLE_SECS: u64 = 30;
+const DEFAULT_PROBE_SAMPLES: u32 = 1;
+const DEFAULT_FAIL_THRESHOLD: u32 = 3;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 30;
+const DEFAULT_KEEPALIVE_SECS: u64 = 180;
+/// Number of recent handshake times kept for percentiles.
+const HANDSHAKE_SAMPLES: usize = 64;
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
 pub enum ProxyProto {
@@ -47,6 +103,65 @@ pub enum ProxyProto {
         connect_with_payload: bool,
     },
     Direct,
+    /// CONNECT streams multiplexed on one HTTP/2 connection.
+    #[cfg(feature = "http2")]
+    #[serde(rename = "HTTP2")]
+    Http2(Http2Config),
+    /// `direct-tcpip` channels on one SSH session.
+    #[cfg(feature = "ssh")]
+    #[serde(rename = "SSH")]
+    Ssh(SshConfig),
+    /// Tunnel over WebSocket, optionally with TLS.
+    #[cfg(feature = "websocket")]
+    #[serde(rename = "WebSocket")]
+    WebSocket(WebSocketConfig),
+    /// Streams multiplexed on a small pool of connections to another
+    /// moproxy running with `--mux-bind`.
+    #[cfg(feature = "mux")]
+    #[serde(rename = "moproxy")]
+    Moproxy {
+        /// Max number of connections to the server.
+        pool_size: usize,
+        /// Shared secret sent in session preamble.
+        #[serde(skip_serializing)]
+        secret: Option<Box<str>>,
+        /// Start TLS before the session preamble if set.
+        tls: Option<MuxTlsConfig>,
+    },
+    /// Protocol implemented by a registered `Connector`.
+    Custom(CustomProto),
+}
+
+#[derive(Clone, Debug)]
+pub struct CustomProto {
+    name: Box<str>,
+    connector: Arc<dyn Connector>,
+}
+
+impl CustomProto {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Hash for CustomProto {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
+}
+
+impl PartialEq for CustomProto {
+    fn eq(&self, other: &CustomProto) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.connector, &other.connector)
+    }
+}
+
+impl Eq for CustomProto {}
+
+impl Serialize for CustomProto {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
@@ -58,12 +173,38 @@ pub struct SocksUserPassAuthCredential {
 
 #[derive(Debug, Serialize)]
 pub struct ProxyServer {
-    pub addr: SocketAddr,
+    pub addr: ServerAddr,
     pub proto: ProxyProto,
     pub tag: Box<str>,
     config: RwLock<ProxyServerConfig>,
-    status: Mutex<ProxyServerStatus>,
+    status: StatusCell,
     traffic: AtomicTraffic,
+    /// Socket addresses that `addr` resolved to.
+    /// Always the same as `addr` if it's an IP address.
+    resolved_addrs: RwLock<Vec<SocketAddr>>,
+    /// Set if `addr` should be re-resolved before next connect.
+    #[serde(skip_serializing)]
+    resolve_needed: AtomicBool,
+    /// Delays of each probe target in the last round.
+    probe_delays: RwLock<Vec<ProbeDelay>>,
+    #[serde(skip_serializing)]
+    warm_pool: WarmPool,
+    /// Notified once the server get demoted due to connection failures,
+    /// or its circuit breaker closed after trial connections succeed.
+    #[serde(skip_serializing)]
+    probe_needed: Notify,
+    /// Time taken by recent successful handshakes of clients.
+    #[serde(skip_serializing)]
+    handshake_times: HandshakeTimes,
+    #[cfg(feature = "http2")]
+    #[serde(skip_serializing)]
+    http2_pool: Option<Http2Pool>,
+    #[cfg(feature = "ssh")]
+    #[serde(skip_serializing)]
+    ssh_pool: Option<SshPool>,
+    #[cfg(feature = "mux")]
+    #[serde(skip_serializing)]
+    mux_pool: Option<MuxPool>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -72,6 +213,26 @@ pub struct ProxyServerConfig {
     pub max_wait: Duration,
     listen_ports: HashSet<u16>,
     score_base: i32,
+    /// Servers in a tier are used only if all servers in lower tiers are
+    /// down.
+    tier: u8,
+    /// Number of idle connections kept in the warm pool.
+    pool_size: usize,
+    pool_max_idle: Duration,
+    socket: SocketOptions,
+    probes: Vec<ProbeTarget>,
+    probe_aggregate: Aggregate,
+    /// Number of probes sent in each round.
+    probe_samples: u32,
+    /// Number of consecutive connection failures before the server get
+    /// demoted and its circuit breaker opened, zero to disable.
+    fail_threshold: u32,
+    /// Time before an open circuit breaker turns half-open.
+    breaker_cooldown: Duration,
+    /// Number of trial connections allowed while half-open.
+    breaker_trials: u32,
+    timeouts: Timeouts,
+    pipe: PipeOptions,
 }
 
 #[cfg(feature = "score_script")]
@@ -79,25 +240,154 @@ impl ToLua<'_> for ProxyServerConfig {
     fn to_lua(self, ctx: LuaContext<'_>) -> LuaResult<LuaValue<'_>> {
         let table = ctx.create_table()?;
         table.set("test_dns", self.test_dns.to_string())?;
+        let probes = ctx.create_table()?;
+        for target in self.probes {
+            probes.set(target.name.as_ref(), target.probe.to_string())?;
+        }
+        table.set("probes", probes)?;
+        table.set("probe_aggregate", self.probe_aggregate.to_string())?;
+        table.set("probe_samples", self.probe_samples)?;
         table.set("max_wait", self.max_wait.as_secs_f32())?;
         table.set("score_base", self.score_base)?;
+        table.set("tier", self.tier)?;
         table.to_lua(ctx)
     }
 }
 
-#[derive(Debug, Serialize, Clone, Copy)]
-pub enum Delay {
-    Unknown,
-    Some(Duration),
-    TimedOut,
+/// Timeouts of each phase of connecting to a server. Default to
+/// `max_wait` if `None`.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// Establishing the transport, e.g. TCP connecting.
+    pub connect: Option<Duration>,
+    /// Proxy protocol handshaking after the transport established.
+    pub handshake: Option<Duration>,
+    /// Waiting for the first byte from the destination, only if the
+    /// client has sent data in advance.
+    pub response: Option<Duration>,
+}
+
+/// An error not caused by the proxy server being unavailable, e.g. it
+/// refused to connect the destination, or the name of the server could
+/// not be resolved. It does not count as a failure of the server.
+#[derive(Debug)]
+pub struct NotServerFault(Box<dyn Error + Send + Sync>);
+
+impl fmt::Display for NotServerFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
-impl Default for Delay {
+impl Error for NotServerFault {}
+
+/// Create an error of `kind` that is not a fault of the proxy server.
+pub fn not_server_fault<E>(kind: io::ErrorKind, err: E) -> io::Error
+where
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    io::Error::new(kind, NotServerFault(err.into()))
+}
+
+/// Whether `err` counts as a failure of the proxy server.
+pub fn is_server_fault(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(err) => !err.is::<NotServerFault>(),
+        None => true,
+    }
+}
+
+/// For servers configured with TLS while it's not compiled in.
+#[cfg(all(
+    not(feature = "tls"),
+    any(feature = "websocket", feature = "http2", feature = "mux")
+))]
+fn error_tls_disabled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "TLS support disabled during compiling",
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutPhase {
+    Connect,
+    Handshake,
+    Response,
+}
+
+/// Phase of a connection being made, connecting by default. Shared with
+/// whoever times it out as a whole.
+#[derive(Debug, Default)]
+struct PhaseCell(AtomicBool);
+
+impl PhaseCell {
+    fn set(&self, phase: TimeoutPhase) {
+        self.0
+            .store(phase != TimeoutPhase::Connect, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> TimeoutPhase {
+        if self.0.load(Ordering::Relaxed) {
+            TimeoutPhase::Handshake
+        } else {
+            TimeoutPhase::Connect
+        }
+    }
+}
+
+/// Number of timeouts in each phase.
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct TimeoutCounts {
+    pub connect: u32,
+    pub handshake: u32,
+    pub response: u32,
+}
+
+/// Options for piping established connections.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct PipeOptions {
+    /// TCP keepalive on the client side. The server side one is set by
+    /// socket options on connecting.
+    pub keepalive: Option<Keepalive>,
+    /// Close the connection if nothing is received from either side for
+    /// this long.
+    pub idle_timeout: Option<Duration>,
+    /// Close the connection after this long regardless of its activity.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for PipeOptions {
     fn default() -> Self {
-        Delay::Unknown
+        PipeOptions {
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(DEFAULT_KEEPALIVE_SECS),
+                interval: None,
+                count: None,
+            }),
+            idle_timeout: None,
+            max_lifetime: None,
+        }
     }
 }
 
+/// Number of connections closed by each reason other than EOF.
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct CloseCounts {
+    /// Timed out by TCP keepalive or retransmission.
+    pub timed_out: u32,
+    pub idle_timeout: u32,
+    pub max_lifetime: u32,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub enum Delay {
+    #[default]
+    Unknown,
+    Some(Duration),
+    TimedOut,
+}
+
 impl Delay {
     pub fn map<T, F>(self, func: F) -> Option<T>
     where
@@ -117,6 +407,51 @@ impl From<Option<Duration>> for Delay {
     }
 }
 
+/// Statistics of probes in one round.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct ProbeStats {
+    /// Number of probes sent.
+    pub samples: u32,
+    /// Number of probes failed or timed out.
+    pub lost: u32,
+    pub min: Duration,
+    pub median: Duration,
+    /// Mean difference between consecutive delays.
+    pub jitter: Duration,
+}
+
+impl ProbeStats {
+    /// Return `None` if no probe succeed.
+    pub fn from_samples(samples: &[Option<Duration>]) -> Option<Self> {
+        let delays: Vec<_> = samples.iter().filter_map(|t| *t).collect();
+        let jitter = delays
+            .windows(2)
+            .map(|w| w[0].max(w[1]) - w[0].min(w[1]))
+            .sum::<Duration>()
+            .checked_div(delays.len().saturating_sub(1) as u32)
+            .unwrap_or_default();
+        let mut sorted = delays;
+        sorted.sort();
+        Some(Self {
+            samples: samples.len() as u32,
+            lost: (samples.len() - sorted.len()) as u32,
+            min: *sorted.first()?,
+            median: sorted[(sorted.len() - 1) / 2],
+            jitter,
+        })
+    }
+
+    pub fn loss(&self) -> f32 {
+        self.lost as f32 / self.samples as f32
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ProbeDelay {
+    pub name: Box<str>,
+    pub delay: Delay,
+}
+
 #[cfg(feature = "score_script")]
 impl ToLua<'_> for Delay {
     fn to_lua(self, ctx: LuaContext<'_>) -> LuaResult<LuaValue<'_>> {
@@ -132,12 +467,195 @@ impl ToLua<'_> for Delay {
 #[derive(Debug, Serialize, Clone, Copy, Default)]
 pub struct ProxyServerStatus {
     pub delay: Delay,
+    /// Statistics of the last probe round, unless all probes failed.
+    pub probe: Option<ProbeStats>,
     pub score: Option<i32>,
     pub conn_alive: u32,
     pub conn_total: u32,
     pub conn_error: u32,
     #[serde(with = "serde_with::rust::display_fromstr")]
     pub close_history: u64,
+    /// Number of consecutive connect or handshake failures.
+    pub failures: u32,
+    pub breaker: CircuitBreaker,
+    pub timeouts: TimeoutCounts,
+    pub closes: CloseCounts,
+    /// Only for protocols that multiplex connections.
+    pub mux: Option<MuxStatus>,
+    /// Only if warm pool is enabled.
+    pub warm_pool: Option<WarmPoolStatus>,
+}
+
+/// Status shared with connection pools of a server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SharedStatus {
+    /// Only for protocols that multiplex connections.
+    pub mux: Option<MuxStatus>,
+    /// Only if warm pool is enabled.
+    pub warm_pool: Option<WarmPoolStatus>,
+}
+
+/// Status updated on probes or unusual events.
+#[derive(Debug, Clone, Copy, Default)]
+struct ProbeStatus {
+    delay: Delay,
+    probe: Option<ProbeStats>,
+    timeouts: TimeoutCounts,
+    closes: CloseCounts,
+}
+
+/// Status of a server. Anything read or updated on every connection is
+/// kept in atomics, the rest behind locks.
+#[derive(Debug, Default)]
+struct StatusCell {
+    shared: Arc<Mutex<SharedStatus>>,
+    probe: Mutex<ProbeStatus>,
+    breaker: Mutex<CircuitBreaker>,
+    /// Same as the state of `breaker`, checked without locking.
+    breaker_state: AtomicBreakerState,
+    score: AtomicScore,
+    failures: AtomicU32,
+    conns: ConnCounters,
+}
+
+#[derive(Debug, Default)]
+struct ConnCounters {
+    alive: AtomicU32,
+    total: AtomicU32,
+    error: AtomicU32,
+    close_history: AtomicU64,
+}
+
+/// `Option<i32>` in an atomic, `i64::MIN` for `None`.
+#[derive(Debug)]
+struct AtomicScore(AtomicI64);
+
+impl Default for AtomicScore {
+    fn default() -> Self {
+        AtomicScore(AtomicI64::new(i64::MIN))
+    }
+}
+
+impl AtomicScore {
+    fn load(&self) -> Option<i32> {
+        match self.0.load(Ordering::Relaxed) {
+            i64::MIN => None,
+            score => Some(score as i32),
+        }
+    }
+
+    fn store(&self, score: Option<i32>) {
+        let score = score.map(i64::from).unwrap_or(i64::MIN);
+        self.0.store(score, Ordering::Relaxed);
+    }
+}
+
+impl StatusCell {
+    /// Change the circuit breaker with `func`.
+    fn with_breaker<R, F: FnOnce(&mut CircuitBreaker) -> R>(&self, func: F) -> R {
+        let mut breaker = self.breaker.lock();
+        let result = func(&mut breaker);
+        self.breaker_state.store(breaker.state());
+        result
+    }
+
+    fn snapshot(&self) -> ProxyServerStatus {
+        let probe = *self.probe.lock();
+        let shared = *self.shared.lock();
+        ProxyServerStatus {
+            delay: probe.delay,
+            probe: probe.probe,
+            score: self.score.load(),
+            conn_alive: self.conns.alive.load(Ordering::Relaxed),
+            conn_total: self.conns.total.load(Ordering::Relaxed),
+            conn_error: self.conns.error.load(Ordering::Relaxed),
+            close_history: self.conns.close_history.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            breaker: *self.breaker.lock(),
+            timeouts: probe.timeouts,
+            closes: probe.closes,
+            mux: shared.mux,
+            warm_pool: shared.warm_pool,
+        }
+    }
+}
+
+impl Serialize for StatusCell {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+impl ConnCounters {
+    /// Shift `bit` into the close history.
+    fn push_history(&self, bit: bool) {
+        let _ = self
+            .close_history
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |history| {
+                Some(history << 1 | bit as u64)
+            });
+    }
+}
+
+/// Ring of recent handshake times in microseconds, zero for empty slots.
+#[derive(Debug)]
+struct HandshakeTimes {
+    slots: Box<[AtomicU32]>,
+    next: AtomicUsize,
+}
+
+impl Default for HandshakeTimes {
+    fn default() -> Self {
+        HandshakeTimes {
+            slots: (0..HANDSHAKE_SAMPLES).map(|_| AtomicU32::new(0)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl HandshakeTimes {
+    fn push(&self, time: Duration) {
+        let micros = time.as_micros().max(1).min(u32::MAX as u128) as u32;
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % HANDSHAKE_SAMPLES;
+        self.slots[i].store(micros, Ordering::Relaxed);
+    }
+
+    fn p90(&self) -> Option<Duration> {
+        let mut times = [0u32; HANDSHAKE_SAMPLES];
+        let mut len = 0;
+        for slot in self.slots.iter() {
+            match slot.load(Ordering::Relaxed) {
+                0 => (),
+                micros => {
+                    times[len] = micros;
+                    len += 1;
+                }
+            }
+        }
+        let times = &mut times[..len];
+        times.sort_unstable();
+        let i = (len * 9 / 10).min(len.checked_sub(1)?);
+        Some(Duration::from_micros(times[i].into()))
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct MuxStatus {
+    pub connected: bool,
+    /// Number of streams currently open.
+    pub streams: u32,
+    /// Number of connections established so far.
+    pub connections: u32,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct WarmPoolStatus {
+    /// Number of idle connections in the pool.
+    pub idle: u32,
+    /// Number of connections taken from the pool.
+    pub hits: u32,
+    /// Number of connections made while the pool is empty.
+    pub misses: u32,
 }
 
 #[cfg(feature = "score_script")]
@@ -145,11 +663,33 @@ impl ToLua<'_> for ProxyServerStatus {
     fn to_lua(self, ctx: LuaContext<'_>) -> LuaResult<LuaValue<'_>> {
         let status = ctx.create_table()?;
         status.set("delay", self.delay)?;
+        if let Some(probe) = self.probe {
+            status.set("delay_min", probe.min.as_secs_f32())?;
+            status.set("jitter", probe.jitter.as_secs_f32())?;
+            status.set("loss", probe.loss())?;
+        }
         status.set("score", self.score)?;
         status.set("conn_alive", self.conn_alive)?;
         status.set("conn_total", self.conn_total)?;
         status.set("conn_error", self.conn_error)?;
         status.set("close_history", self.close_history)?;
+        status.set("failures", self.failures)?;
+        status.set("breaker", self.breaker.state().to_string())?;
+        status.set("connect_timeouts", self.timeouts.connect)?;
+        status.set("handshake_timeouts", self.timeouts.handshake)?;
+        status.set("response_timeouts", self.timeouts.response)?;
+        status.set("timeout_closes", self.closes.timed_out)?;
+        status.set("idle_closes", self.closes.idle_timeout)?;
+        status.set("lifetime_closes", self.closes.max_lifetime)?;
+        if let Some(mux) = self.mux {
+            status.set("mux_connected", mux.connected)?;
+            status.set("mux_streams", mux.streams)?;
+        }
+        if let Some(pool) = self.warm_pool {
+            status.set("pool_idle", pool.idle)?;
+            status.set("pool_hits", pool.hits)?;
+            status.set("pool_misses", pool.misses)?;
+        }
         status.to_lua(ctx)
     }
 }
@@ -178,13 +718,18 @@ impl ToLua<'_> for &ProxyServer {
         table.set("proto", self.proto.to_string())?;
         table.set("tag", self.tag.to_string())?;
         table.set("config", self.config.read().clone())?;
-        table.set("status", *self.status.lock())?;
+        table.set("status", self.status_snapshot())?;
         table.set("traffic", self.traffic())?;
+        let probes = ctx.create_table()?;
+        for probe in self.probe_delays.read().iter() {
+            probes.set(probe.name.as_ref(), probe.delay)?;
+        }
+        table.set("probes", probes)?;
         table.to_lua(ctx)
     }
 }
 
-#[derive(Hash, Clone, Debug)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub enum Address {
     Ip(IpAddr),
     Domain(Box<str>),
@@ -208,7 +753,7 @@ impl From<String> for Address {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct Destination {
     pub host: Address,
     pub port: u16,
@@ -242,6 +787,38 @@ impl From<(Address, u16)> for Destination {
     }
 }
 
+impl Serialize for Destination {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where the proxy server listen on.
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub enum ServerAddr {
+    Inet(Destination),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<Destination> for ServerAddr {
+    fn from(dest: Destination) -> Self {
+        ServerAddr::Inet(dest)
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ServerAddr::Inet(addr.into())
+    }
+}
+
+impl Serialize for ServerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 #[derive(Debug)]
 pub struct AtomicTraffic {
     tx_bytes: AtomicUsize,
@@ -341,6 +918,48 @@ impl ProxyProto {
             connect_with_payload,
         }
     }
+
+    #[cfg(feature = "http2")]
+    pub fn http2(config: Http2Config) -> Self {
+        ProxyProto::Http2(config)
+    }
+
+    #[cfg(feature = "ssh")]
+    pub fn ssh(config: SshConfig) -> Self {
+        ProxyProto::Ssh(config)
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn websocket(config: WebSocketConfig) -> Self {
+        ProxyProto::WebSocket(config)
+    }
+
+    #[cfg(feature = "mux")]
+    pub fn moproxy(pool_size: usize, secret: Option<&str>, tls: Option<MuxTlsConfig>) -> Self {
+        ProxyProto::Moproxy {
+            pool_size,
+            secret: secret.map(Into::into),
+            tls,
+        }
+    }
+
+    pub fn custom(name: &str, connector: Arc<dyn Connector>) -> Self {
+        ProxyProto::Custom(CustomProto {
+            name: name.into(),
+            connector,
+        })
+    }
+
+    /// Whether a fresh transport is connected for each client, so that
+    /// transports can be connected in advance by the warm pool.
+    pub fn supports_warm_pool(&self) -> bool {
+        match self {
+            ProxyProto::Socks5 { .. } | ProxyProto::Http { .. } => true,
+            #[cfg(feature = "websocket")]
+            ProxyProto::WebSocket(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl ProxyServerConfig {
@@ -355,13 +974,25 @@ impl ProxyServerConfig {
             max_wait,
             listen_ports: listen_ports.unwrap_or_default(),
             score_base: score_base.unwrap_or(0),
+            tier: 0,
+            pool_size: 0,
+            pool_max_idle: Duration::from_secs(DEFAULT_POOL_MAX_IDLE_SECS),
+            socket: Default::default(),
+            probes: vec![Default::default()],
+            probe_aggregate: Default::default(),
+            probe_samples: DEFAULT_PROBE_SAMPLES,
+            fail_threshold: DEFAULT_FAIL_THRESHOLD,
+            breaker_cooldown: Duration::from_secs(DEFAULT_BREAKER_COOLDOWN_SECS),
+            breaker_trials: 1,
+            timeouts: Default::default(),
+            pipe: Default::default(),
         }
     }
 }
 
 impl ProxyServer {
     pub fn new(
-        addr: SocketAddr,
+        addr: ServerAddr,
         proto: ProxyProto,
         test_dns: SocketAddr,
         max_wait: Duration,
@@ -369,11 +1000,57 @@ impl ProxyServer {
         tag: Option<&str>,
         score_base: Option<i32>,
     ) -> ProxyServer {
+        let resolved_addrs = match addr {
+            ServerAddr::Inet(Destination {
+                host: Address::Ip(ip),
+                port,
+            }) => vec![SocketAddr::new(ip, port)],
+            _ => vec![],
+        };
+        let status = StatusCell::default();
+        #[cfg(feature = "http2")]
+        let http2_pool = match proto {
+            ProxyProto::Http2(ref config) => {
+                let authority = match (&config.host, &addr) {
+                    (Some(host), ServerAddr::Inet(dest)) => format!("{}:{}", host, dest.port),
+                    (None, ServerAddr::Inet(dest)) => dest.to_string(),
+                    #[cfg(unix)]
+                    (_, ServerAddr::Unix(_)) => "localhost".to_string(),
+                };
+                let scheme = if config.tls { "https" } else { "http" };
+                let target = http2::Target { scheme, authority };
+                Some(Http2Pool::new(target, status.shared.clone()))
+            }
+            _ => None,
+        };
+        #[cfg(feature = "ssh")]
+        let ssh_pool = match proto {
+            ProxyProto::Ssh(_) => Some(SshPool::new(status.shared.clone())),
+            _ => None,
+        };
+        #[cfg(feature = "mux")]
+        let mux_pool = match proto {
+            ProxyProto::Moproxy {
+                pool_size,
+                ref secret,
+                ..
+            } => {
+                let secret = secret.as_deref().unwrap_or_default().as_bytes();
+                Some(MuxPool::new(pool_size, secret, status.shared.clone()))
+            }
+            _ => None,
+        };
         ProxyServer {
-            addr,
             proto,
             tag: match tag {
-                None => format!("{}", addr.port()),
+                None => match addr {
+                    ServerAddr::Inet(ref dest) => format!("{}", dest.port),
+                    #[cfg(unix)]
+                    ServerAddr::Unix(ref path) => path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                },
                 Some(s) => {
                     if !s.is_ascii() || s.contains(' ') || s.contains('\n') {
                         panic!(
@@ -387,99 +1064,679 @@ impl ProxyServer {
             }
             .into_boxed_str(),
             config: ProxyServerConfig::new(test_dns, score_base, listen_ports, max_wait).into(),
-            status: Default::default(),
             traffic: Default::default(),
+            resolved_addrs: resolved_addrs.into(),
+            resolve_needed: AtomicBool::new(false),
+            probe_delays: Default::default(),
+            warm_pool: WarmPool::new(status.shared.clone()),
+            probe_needed: Notify::new(),
+            handshake_times: Default::default(),
+            #[cfg(feature = "http2")]
+            http2_pool,
+            #[cfg(feature = "ssh")]
+            ssh_pool,
+            #[cfg(feature = "mux")]
+            mux_pool,
+            status,
+            addr,
         }
     }
 
     pub fn direct(max_wait: Duration) -> Self {
-        let stub_addr = "0.0.0.0:0".parse().unwrap();
+        let stub_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let status = StatusCell::default();
         Self {
-            addr: stub_addr,
+            addr: stub_addr.into(),
             proto: ProxyProto::Direct,
             tag: "__DIRECT__".into(),
             config: ProxyServerConfig::new(stub_addr, None, None, max_wait).into(),
-            status: Default::default(),
             traffic: Default::default(),
+            resolved_addrs: Default::default(),
+            resolve_needed: AtomicBool::new(false),
+            probe_delays: Default::default(),
+            warm_pool: WarmPool::new(status.shared.clone()),
+            probe_needed: Notify::new(),
+            handshake_times: Default::default(),
+            status,
+            #[cfg(feature = "http2")]
+            http2_pool: None,
+            #[cfg(feature = "ssh")]
+            ssh_pool: None,
+            #[cfg(feature = "mux")]
+            mux_pool: None,
         }
     }
 
+    /// A SOCKSv5 server tagged `tag` for unit tests. It has a score if
+    /// `delay` (in milliseconds) is given, as if it passed a probe.
+    #[cfg(test)]
+    pub(crate) fn new_for_test(
+        tag: &str,
+        listen_ports: Option<HashSet<u16>>,
+        delay: Option<u64>,
+    ) -> Self {
+        let server = ProxyServer::new(
+            "127.0.0.1:1".parse().unwrap(),
+            ProxyProto::socks5(false),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            listen_ports,
+            Some(tag),
+            None,
+        );
+        if let Some(delay) = delay {
+            let stats = ProbeStats::from_samples(&[Some(Duration::from_millis(delay))]);
+            server.update_delay(stats);
+        }
+        server
+    }
+
     pub fn copy_config_from(&self, from: &Self) {
         if !std::ptr::eq(&from.config, &self.config) {
             *self.config.write() = from.config.read().clone();
         }
     }
 
+    /// Keep `size` idle connections to the server for new clients.
+    /// Connections idle longer than `max_idle` are dropped.
+    /// Only applies to protocols that do handshake on each connection,
+    /// and not with TCP Fast Open, whose sockets connect on first write.
+    pub fn set_warm_pool(&self, size: usize, max_idle: Duration) {
+        let mut config = self.config.write();
+        config.pool_size = size;
+        config.pool_max_idle = max_idle;
+        if size > 0 {
+            self.status
+                .shared
+                .lock()
+                .warm_pool
+                .get_or_insert_with(Default::default);
+        }
+    }
+
+    /// Set options on sockets connecting to the server, or to the
+    /// destination if it is the direct server.
+    pub fn set_socket_options(&self, opts: SocketOptions) {
+        self.config.write().socket = opts;
+    }
+
+    pub fn socket_options(&self) -> SocketOptions {
+        self.config.read().socket.clone()
+    }
+
+    /// Set how the server is tested. Default to a DNS query to `test_dns`.
+    /// Delays of multiple targets are combined with `aggregate`.
+    pub fn set_probes(&self, probes: Vec<ProbeTarget>, aggregate: Aggregate) {
+        assert!(!probes.is_empty(), "no probe target");
+        let mut config = self.config.write();
+        config.probes = probes;
+        config.probe_aggregate = aggregate;
+    }
+
+    pub fn probes(&self) -> (Vec<ProbeTarget>, Aggregate) {
+        let config = self.config.read();
+        (config.probes.clone(), config.probe_aggregate)
+    }
+
+    /// Set number of probes sent in each round. A round fails only if
+    /// all of them fail.
+    pub fn set_probe_samples(&self, samples: u32) {
+        assert!(samples > 0, "zero probe samples");
+        self.config.write().probe_samples = samples;
+    }
+
+    pub fn probe_samples(&self) -> u32 {
+        self.config.read().probe_samples
+    }
+
+    /// Set number of consecutive connection failures before the server
+    /// get demoted and probed immediately. Zero to disable.
+    pub fn set_fail_threshold(&self, threshold: u32) {
+        self.config.write().fail_threshold = threshold;
+    }
+
+    /// Set priority tier of the server, zero is the highest.
+    pub fn set_tier(&self, tier: u8) {
+        self.config.write().tier = tier;
+    }
+
+    pub fn tier(&self) -> u8 {
+        self.config.read().tier
+    }
+
+    /// Whether the server passed the last probe and its circuit breaker
+    /// is closed.
+    pub fn is_up(&self) -> bool {
+        self.status.score.load().is_some()
+            && self.status.breaker_state.load() == BreakerState::Closed
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.status.breaker_state.load()
+    }
+
+    /// Set how long the circuit breaker keeps open before letting
+    /// `trials` connections through to test the server.
+    pub fn set_breaker(&self, cooldown: Duration, trials: u32) {
+        let mut config = self.config.write();
+        config.breaker_cooldown = cooldown;
+        config.breaker_trials = trials;
+    }
+
+    /// Whether clients may connect to the server, according to its
+    /// circuit breaker.
+    pub fn breaker_allow(&self) -> bool {
+        if self.status.breaker_state.load() == BreakerState::Closed {
+            return true;
+        }
+        let config = self.config.read();
+        config.fail_threshold == 0
+            || self.status.with_breaker(|breaker| {
+                breaker.allow(config.breaker_cooldown, config.breaker_trials)
+            })
+    }
+
+    pub fn set_probe_delays(&self, delays: Vec<ProbeDelay>) {
+        *self.probe_delays.write() = delays;
+    }
+
+    pub fn probe_delays(&self) -> Vec<ProbeDelay> {
+        self.probe_delays.read().clone()
+    }
+
     pub fn serve_port(&self, port: u16) -> bool {
         let listen_ports = &self.config.read().listen_ports;
         listen_ports.is_empty() || listen_ports.contains(&port)
     }
 
-    pub async fn connect<T>(&self, addr: &Destination, data: Option<T>) -> io::Result<TcpStream>
+    /// Return socket addresses of the server, resolving its host name
+    /// first if it has never been resolved or re-resolving is requested.
+    pub async fn socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.resolved_addrs.read().clone();
+        if addrs.is_empty() || self.resolve_needed.swap(false, Ordering::Relaxed) {
+            match self.resolve().await {
+                Ok(addrs) => return Ok(addrs),
+                // keep using old addresses if any
+                Err(err) if addrs.is_empty() => return Err(err),
+                Err(err) => warn!("fail to resolve {}: {}", self.addr, err),
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Resolve host name of the server and update its socket addresses.
+    /// Do nothing if the server is specified by IP address or unix socket.
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let (name, port) = match self.addr {
+            ServerAddr::Inet(Destination {
+                host: Address::Domain(ref name),
+                port,
+            }) => (name, port),
+            _ => return Ok(self.resolved_addrs.read().clone()),
+        };
+        let addrs: Vec<_> = tokio_net::lookup_host((name.as_ref(), port))
+            .await?
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no address resolved",
+            ));
+        }
+        debug!("{} resolved to {:?}", self.addr, addrs);
+        *self.resolved_addrs.write() = addrs.clone();
+        Ok(addrs)
+    }
+
+    async fn connect_inet(&self) -> io::Result<ServerStream> {
+        let addrs = self
+            .socket_addrs()
+            .await
+            .map_err(|err| not_server_fault(err.kind(), err))?;
+        let opts = self.socket_options();
+        let stream = match happy_eyeballs::connect(&addrs, &opts).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                // the name may point to somewhere else now
+                if let ServerAddr::Inet(Destination {
+                    host: Address::Domain(_),
+                    ..
+                }) = self.addr
+                {
+                    self.resolve_needed.store(true, Ordering::Relaxed);
+                }
+                return Err(err);
+            }
+        };
+        debug!("connected with {:?}", stream.peer_addr());
+        stream.set_nodelay(true)?;
+        Ok(stream.into())
+    }
+
+    pub async fn connect<T>(&self, addr: &Destination, data: Option<T>) -> io::Result<ServerStream>
     where
         T: AsRef<[u8]> + 'static,
     {
-        let mut stream = TcpStream::connect(&self.addr).await?;
-        debug!("connected with {:?}", stream.peer_addr());
-        stream.set_nodelay(true)?;
+        self.connect_with(addr, data, true, &PhaseCell::default())
+            .await
+    }
+
+    /// Like `connect()`, but also time out connecting and handshaking as
+    /// a whole, since custom connectors and multiplexed protocols do not
+    /// time out each phase. The timeout is counted in the phase running
+    /// at that time, or handshaking for custom connectors.
+    pub async fn connect_within<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+    ) -> io::Result<ServerStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let phase = PhaseCell::default();
+        let max_wait = self.connect_timeout() + self.handshake_timeout();
+        match timeout(max_wait, self.connect_with(addr, data, true, &phase)).await {
+            Ok(result) => result,
+            Err(_) => {
+                let phase = phase.get();
+                self.update_stats_timeout(phase);
+                let msg = match phase {
+                    TimeoutPhase::Connect => "connect timeout",
+                    _ => "handshake timeout",
+                };
+                Err(io::Error::new(io::ErrorKind::TimedOut, msg))
+            }
+        }
+    }
+
+    /// Like `connect()`, but never take a connection from the warm
+    /// pool, so that probes measure a new connection.
+    pub async fn connect_fresh<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+    ) -> io::Result<ServerStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        self.connect_with(addr, data, false, &PhaseCell::default())
+            .await
+    }
+
+    /// Connect and handshake, with the phase running kept in `phase`.
+    async fn connect_with<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+        pooled: bool,
+        phase: &PhaseCell,
+    ) -> io::Result<ServerStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        if let ProxyProto::Custom(ref proto) = self.proto {
+            phase.set(TimeoutPhase::Handshake);
+            let data = data.map(|data| Bytes::copy_from_slice(data.as_ref()));
+            let stream = proto.connector.connect(addr, data).await?;
+            return Ok(ServerStream::Custom(stream));
+        }
+        #[cfg(feature = "http2")]
+        {
+            if let (ProxyProto::Http2(config), Some(pool)) = (&self.proto, &self.http2_pool) {
+                let data = data.as_ref().map(|data| data.as_ref());
+                // a new transport is connected only if no session to share
+                phase.set(TimeoutPhase::Handshake);
+                let transport = || self.connect_http2_transport(config, phase);
+                let stream = pool.connect(transport, addr, data).await?;
+                return Ok(ServerStream::Custom(Box::new(stream)));
+            }
+        }
+        #[cfg(feature = "ssh")]
+        {
+            if let (ProxyProto::Ssh(config), Some(pool)) = (&self.proto, &self.ssh_pool) {
+                let data = data.as_ref().map(|data| data.as_ref());
+                phase.set(TimeoutPhase::Handshake);
+                let transport = || self.connect_transport_in(phase);
+                let host = self.ssh_host_name();
+                let stream = pool.connect(transport, config, &host, addr, data).await?;
+                return Ok(ServerStream::Custom(Box::new(stream)));
+            }
+        }
+        #[cfg(feature = "mux")]
+        {
+            if let (ProxyProto::Moproxy { tls, .. }, Some(pool)) = (&self.proto, &self.mux_pool) {
+                let data = data.as_ref().map(|data| data.as_ref());
+                phase.set(TimeoutPhase::Handshake);
+                let transport = || self.connect_mux_transport(tls, phase);
+                let stream = pool.connect(transport, addr, data).await?;
+                return Ok(ServerStream::Custom(Box::new(stream)));
+            }
+        }
+        let mut stream = if pooled {
+            self.connect_pooled(phase).await?
+        } else {
+            self.connect_transport_in(phase).await?
+        };
+        #[cfg(feature = "websocket")]
+        {
+            if let ProxyProto::WebSocket(ref config) = self.proto {
+                let handshake = self.websocket_handshake(stream, config, addr, data);
+                let stream = self.handshake_timeout_on(handshake).await?;
+                return Ok(ServerStream::Custom(stream));
+            }
+        }
+        let handshake = self.handshake(&mut stream, addr, data);
+        self.handshake_timeout_on(handshake).await?;
+        Ok(stream)
+    }
+
+    /// Run `handshake` within the handshake timeout.
+    async fn handshake_timeout_on<F, R>(&self, handshake: F) -> io::Result<R>
+    where
+        F: Future<Output = io::Result<R>>,
+    {
+        match timeout(self.handshake_timeout(), handshake).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.update_stats_timeout(TimeoutPhase::Handshake);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timeout"))
+            }
+        }
+    }
+
+    /// Connect to the server without any handshaking.
+    async fn connect_transport(&self) -> io::Result<ServerStream> {
+        let connect = async {
+            match self.addr {
+                ServerAddr::Inet(_) => self.connect_inet().await,
+                #[cfg(unix)]
+                ServerAddr::Unix(ref path) => Ok(UnixStream::connect(path).await?.into()),
+            }
+        };
+        match timeout(self.connect_timeout(), connect).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.update_stats_timeout(TimeoutPhase::Connect);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))
+            }
+        }
+    }
+
+    /// Like `connect_transport()`, marking `phase` as connecting until
+    /// it's done.
+    async fn connect_transport_in(&self, phase: &PhaseCell) -> io::Result<ServerStream> {
+        phase.set(TimeoutPhase::Connect);
+        let result = self.connect_transport().await;
+        phase.set(TimeoutPhase::Handshake);
+        result
+    }
+
+    /// Take a connection from the warm pool, or connect to the server if
+    /// the pool is empty or disabled.
+    async fn connect_pooled(&self, phase: &PhaseCell) -> io::Result<ServerStream> {
+        let max_idle = self.config.read().pool_max_idle;
+        if self.warm_pool_size() > 0 {
+            if let Some(stream) = self.warm_pool.take(max_idle) {
+                phase.set(TimeoutPhase::Handshake);
+                return Ok(stream);
+            }
+        }
+        self.connect_transport_in(phase).await
+    }
+
+    /// Refill the warm pool up to its size. Do nothing if the pool is
+    /// disabled.
+    pub async fn fill_warm_pool(&self) {
+        let size = self.warm_pool_size();
+        let (max_idle, max_wait) = {
+            let config = self.config.read();
+            (config.pool_max_idle, config.max_wait)
+        };
+        if size > 0 && self.proto.supports_warm_pool() {
+            let connect = || self.connect_transport();
+            self.warm_pool.fill(size, max_idle, max_wait, connect).await;
+        }
+    }
+
+    /// Wait until a connection is taken from the warm pool.
+    pub async fn warm_pool_taken(&self) {
+        self.warm_pool.taken().await
+    }
+
+    /// Size of the warm pool, zero if disabled or TCP Fast Open is on.
+    pub fn warm_pool_size(&self) -> usize {
+        let config = self.config.read();
+        if config.socket.fast_open {
+            0
+        } else {
+            config.pool_size
+        }
+    }
+
+    /// Name used for TLS and Host header, `host` if set, or host of
+    /// the server address.
+    #[cfg(any(
+        feature = "websocket",
+        all(any(feature = "http2", feature = "mux"), feature = "tls")
+    ))]
+    fn server_name(&self, host: &Option<Box<str>>) -> String {
+        match (host, &self.addr) {
+            (Some(host), _) => host.to_string(),
+            (None, ServerAddr::Inet(dest)) => dest.host.to_string(),
+            #[cfg(unix)]
+            (None, ServerAddr::Unix(_)) => "localhost".to_string(),
+        }
+    }
+
+    /// Name of the server in known_hosts, `[host]:port` if port is not 22.
+    #[cfg(feature = "ssh")]
+    fn ssh_host_name(&self) -> String {
+        match self.addr {
+            ServerAddr::Inet(ref dest) if dest.port == 22 => dest.host.to_string(),
+            ServerAddr::Inet(ref dest) => format!("[{}]:{}", dest.host, dest.port),
+            #[cfg(unix)]
+            ServerAddr::Unix(_) => "localhost".to_string(),
+        }
+    }
+
+    #[cfg(feature = "http2")]
+    async fn connect_http2_transport(
+        &self,
+        config: &Http2Config,
+        phase: &PhaseCell,
+    ) -> io::Result<ServerStream> {
+        let stream = self.connect_transport_in(phase).await?;
+        if !config.tls {
+            return Ok(stream);
+        }
+        #[cfg(feature = "tls")]
+        {
+            let host = self.server_name(&config.host);
+            let stream = tls::connect(&host, stream, &["h2"]).await?;
+            Ok(ServerStream::Custom(Box::new(stream)))
+        }
+        #[cfg(not(feature = "tls"))]
+        Err(error_tls_disabled())
+    }
+
+    #[cfg(feature = "mux")]
+    async fn connect_mux_transport(
+        &self,
+        config: &Option<MuxTlsConfig>,
+        phase: &PhaseCell,
+    ) -> io::Result<ServerStream> {
+        let stream = self.connect_transport_in(phase).await?;
+        match config {
+            None => Ok(stream),
+            #[cfg(feature = "tls")]
+            Some(config) => {
+                let host = self.server_name(&config.host);
+                let root = config.ca.as_deref().map(tls::certificate).transpose()?;
+                let stream = tls::connect_with_root(&host, stream, &[], root).await?;
+                Ok(ServerStream::Custom(Box::new(stream)))
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(_) => Err(error_tls_disabled()),
+        }
+    }
 
+    #[cfg(feature = "websocket")]
+    async fn websocket_handshake<T>(
+        &self,
+        stream: ServerStream,
+        config: &WebSocketConfig,
+        addr: &Destination,
+        data: Option<T>,
+    ) -> io::Result<BoxedStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let host = self.server_name(&config.host);
+        let data = data.as_ref().map(|data| data.as_ref());
+        if config.tls {
+            #[cfg(feature = "tls")]
+            {
+                let stream = tls::connect(&host, stream, &[]).await?;
+                let stream = websocket::handshake(stream, config, &host, addr, data).await?;
+                return Ok(Box::new(stream));
+            }
+            #[cfg(not(feature = "tls"))]
+            return Err(error_tls_disabled());
+        }
+        let stream = websocket::handshake(stream, config, &host, addr, data).await?;
+        Ok(Box::new(stream))
+    }
+
+    /// Do proxy handshake on a stream that already connected to the server.
+    /// Useful for connecting the server via custom transport.
+    pub async fn handshake<S, T>(
+        &self,
+        stream: &mut S,
+        addr: &Destination,
+        data: Option<T>,
+    ) -> io::Result<()>
+    where
+        S: AsyncStream,
+        T: AsRef<[u8]> + 'static,
+    {
         match &self.proto {
             ProxyProto::Direct => unimplemented!(),
+            ProxyProto::Custom(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "custom protocol cannot do handshake on given stream",
+            )),
+            #[cfg(feature = "http2")]
+            ProxyProto::Http2(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "http2 cannot do handshake in place, use connect()",
+            )),
+            #[cfg(feature = "ssh")]
+            ProxyProto::Ssh(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ssh cannot do handshake in place, use connect()",
+            )),
+            #[cfg(feature = "websocket")]
+            ProxyProto::WebSocket(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "websocket cannot do handshake in place, use connect()",
+            )),
+            #[cfg(feature = "mux")]
+            ProxyProto::Moproxy { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "moproxy cannot do handshake in place, use connect()",
+            )),
             ProxyProto::Socks5 {
                 fake_handshaking,
                 user_pass_auth,
-            } => {
-                socks5::handshake(&mut stream, &addr, data, *fake_handshaking, user_pass_auth)
-                    .await?
-            }
+            } => socks5::handshake(stream, addr, data, *fake_handshaking, user_pass_auth).await,
             ProxyProto::Http {
                 connect_with_payload,
-            } => http::handshake(&mut stream, &addr, data, *connect_with_payload).await?,
+            } => http::handshake(stream, addr, data, *connect_with_payload).await,
         }
-        Ok(stream)
     }
 
     pub fn status_snapshot(&self) -> ProxyServerStatus {
-        *self.status.lock()
+        self.status.snapshot()
     }
 
     pub fn score(&self) -> Option<i32> {
-        self.status.lock().score
+        self.status.score.load()
     }
 
     pub fn traffic(&self) -> Traffic {
         self.traffic.read()
     }
 
+    pub fn resolved_addrs(&self) -> Vec<SocketAddr> {
+        self.resolved_addrs.read().clone()
+    }
+
     pub fn max_wait(&self) -> Duration {
         self.config.read().max_wait
     }
 
-    pub fn test_dns(&self) -> SocketAddr {
-        self.config.read().test_dns
+    /// Set timeouts of connecting phases, `None` for `max_wait`.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        self.config.write().timeouts = timeouts;
     }
 
-    pub fn update_delay(&self, delay: Option<Duration>) {
-        let mut status = self.status.lock();
+    pub fn connect_timeout(&self) -> Duration {
         let config = self.config.read();
+        config.timeouts.connect.unwrap_or(config.max_wait)
+    }
 
-        if let Some(delay) = delay {
-            let last_score = status.score.unwrap_or_else(|| {
+    pub fn handshake_timeout(&self) -> Duration {
+        let config = self.config.read();
+        config.timeouts.handshake.unwrap_or(config.max_wait)
+    }
+
+    pub fn response_timeout(&self) -> Duration {
+        let config = self.config.read();
+        config.timeouts.response.unwrap_or(config.max_wait)
+    }
+
+    /// Set options for piping connections via this server.
+    pub fn set_pipe_options(&self, options: PipeOptions) {
+        self.config.write().pipe = options;
+    }
+
+    pub fn pipe_options(&self) -> PipeOptions {
+        self.config.read().pipe
+    }
+
+    pub fn test_dns(&self) -> SocketAddr {
+        self.config.read().test_dns
+    }
+
+    pub fn update_delay(&self, stats: Option<ProbeStats>) {
+        let (score_base, max_wait) = {
+            let config = self.config.read();
+            (config.score_base, config.max_wait)
+        };
+        let mut status = self.status.probe.lock();
+        status.probe = stats;
+
+        if let Some(stats) = stats {
+            let delay = stats.median;
+            self.status.failures.store(0, Ordering::Relaxed);
+            self.status.with_breaker(|breaker| breaker.half_open());
+            let last_score = self.status.score.load().unwrap_or_else(|| {
                 match status.delay {
                     Delay::Some(d) => d,
                     Delay::Unknown => delay,
-                    Delay::TimedOut => config.max_wait,
+                    Delay::TimedOut => max_wait,
                 }
                 .as_millis() as i32
-                    + config.score_base
+                    + score_base
             });
-            let err_rate = status
-                .recent_error_rate(16)
-                .min(status.recent_error_rate(64));
+            let history = self.status.conns.close_history.load(Ordering::Relaxed);
+            let err_rate = recent_error_rate(history, 16).min(recent_error_rate(history, 64));
 
-            let score = delay.as_millis() as i32 + config.score_base;
+            let score = (delay + stats.jitter).as_millis() as i32 + score_base;
             // give penalty for continuous errors
             let score = score + (score as f32 * err_rate * 10f32).round() as i32;
+            // and for lost probes
+            let score = score + (score as f32 * stats.loss()).round() as i32;
             // moving average on score
             // give more weight to delays exceed the mean for network jitter penalty
             let score = if score < last_score {
@@ -487,28 +1744,50 @@ impl ProxyServer {
             } else {
                 (last_score * 8 + score * 2) / 10
             };
-            status.score = Some(score);
+            self.status.score.store(Some(score));
             status.delay = Delay::Some(delay);
 
             // Shift error history
             // This give the server with high error penalty a chance to recovery.
-            status.close_history <<= 1;
+            self.status.conns.push_history(false);
         } else {
             // Timed out
             status.delay = Delay::TimedOut;
-            status.score = None;
+            self.status.score.store(None);
         };
     }
 
     #[cfg(feature = "score_script")]
-    pub fn update_delay_with_lua(&self, delay: Option<Duration>, ctx: LuaContext) -> LuaResult<()> {
+    pub fn update_delay_with_lua(
+        &self,
+        stats: Option<ProbeStats>,
+        ctx: LuaContext,
+    ) -> LuaResult<()> {
         let func: LuaFunction = ctx.globals().get("calc_score")?;
+        let delay = stats.map(|s| s.median);
         let delay_secs = delay.map(|t| t.as_secs_f32());
-        let score: Option<i32> = func.call((self, delay_secs))?;
+        let stats_table = match stats {
+            Some(stats) => {
+                let table = ctx.create_table()?;
+                table.set("min", stats.min.as_secs_f32())?;
+                table.set("median", stats.median.as_secs_f32())?;
+                table.set("jitter", stats.jitter.as_secs_f32())?;
+                table.set("loss", stats.loss())?;
+                table.set("samples", stats.samples)?;
+                Some(table)
+            }
+            None => None,
+        };
+        let score: Option<i32> = func.call((self, delay_secs, stats_table))?;
 
-        let mut status = self.status.lock();
-        status.score = score;
+        let mut status = self.status.probe.lock();
+        self.status.score.store(score);
         status.delay = delay.into();
+        status.probe = stats;
+        if stats.is_some() {
+            self.status.failures.store(0, Ordering::Relaxed);
+            self.status.with_breaker(|breaker| breaker.half_open());
+        }
         Ok(())
     }
 
@@ -517,21 +1796,101 @@ impl ProxyServer {
     }
 
     pub fn update_stats_conn_open(&self) {
-        let mut status = self.status.lock();
-        status.conn_alive += 1;
-        status.conn_total += 1;
+        let conns = &self.status.conns;
+        conns.alive.fetch_add(1, Ordering::Relaxed);
+        conns.total.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn update_stats_conn_close(&self, has_error: bool) {
-        let mut status = self.status.lock();
-        status.conn_alive -= 1;
-        status.close_history <<= 1;
+        let conns = &self.status.conns;
+        conns.alive.fetch_sub(1, Ordering::Relaxed);
+        conns.push_history(has_error);
         if has_error {
-            status.conn_error += 1;
-            status.close_history += 1;
+            conns.error.fetch_add(1, Ordering::Relaxed);
         }
     }
 
+    /// Record a failure on connecting or handshaking. Once consecutive
+    /// failures reach the threshold, the server is demoted and its
+    /// circuit breaker opened until its next successful probe.
+    pub fn update_stats_conn_fail(&self) {
+        let threshold = self.config.read().fail_threshold;
+        let failures = self
+            .status
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_add(1))
+            })
+            .unwrap_or_default()
+            .saturating_add(1);
+        if self.status.breaker_state.load() != BreakerState::Closed {
+            self.status.with_breaker(|breaker| breaker.on_failure());
+        }
+        if threshold > 0 && failures == threshold {
+            warn!("{} failed {} times in a row, demoted", self, threshold);
+            self.status.score.store(None);
+            self.status.with_breaker(|breaker| breaker.open());
+            self.probe_needed.notify();
+        }
+    }
+
+    pub fn update_stats_timeout(&self, phase: TimeoutPhase) {
+        let mut status = self.status.probe.lock();
+        let count = match phase {
+            TimeoutPhase::Connect => &mut status.timeouts.connect,
+            TimeoutPhase::Handshake => &mut status.timeouts.handshake,
+            TimeoutPhase::Response => &mut status.timeouts.response,
+        };
+        *count += 1;
+    }
+
+    /// Count a connection closed for `reason`.
+    pub fn update_stats_close_reason(&self, reason: CloseReason) {
+        let mut status = self.status.probe.lock();
+        let count = match reason {
+            CloseReason::TimedOut => &mut status.closes.timed_out,
+            CloseReason::IdleTimeout => &mut status.closes.idle_timeout,
+            CloseReason::MaxLifetime => &mut status.closes.max_lifetime,
+        };
+        *count += 1;
+    }
+
+    /// Reset consecutive failures and close the circuit breaker after a
+    /// successful connection.
+    pub fn update_stats_conn_ok(&self) {
+        if self.status.failures.load(Ordering::Relaxed) != 0 {
+            self.status.failures.store(0, Ordering::Relaxed);
+        }
+        if self.status.breaker_state.load() != BreakerState::Closed {
+            let recovered = self.status.with_breaker(|breaker| {
+                let recovered = breaker.state() != BreakerState::Closed;
+                breaker.on_success();
+                recovered
+            });
+            if recovered {
+                // score is cleared on demotion, get a new one
+                self.probe_needed.notify();
+            }
+        }
+    }
+
+    /// Record time taken by a successful handshake.
+    pub fn record_handshake(&self, time: Duration) {
+        self.handshake_times.push(time);
+    }
+
+    /// 90th percentile of recent handshake times. `None` if no handshake
+    /// recorded.
+    pub fn handshake_p90(&self) -> Option<Duration> {
+        self.handshake_times.p90()
+    }
+
+    /// Wait until the server needs a probe sooner than scheduled: it got
+    /// demoted due to connection failures, or recovered from that.
+    pub async fn probe_needed(&self) {
+        self.probe_needed.notified().await
+    }
+
     pub fn graphite_path(&self, suffix: &str) -> String {
         format!(
             "{}.{}.{}",
@@ -544,15 +1903,24 @@ impl ProxyServer {
 
 impl ProxyServerStatus {
     pub fn recent_error_count(&self, n: u8) -> u8 {
-        let n = 64 - cmp::min(n, 64);
-        (self.close_history << n).count_ones() as u8
+        recent_error_count(self.close_history, n)
     }
 
     pub fn recent_error_rate(&self, n: u8) -> f32 {
-        self.recent_error_count(n) as f32 / (cmp::min(n, 64) as f32)
+        recent_error_rate(self.close_history, n)
     }
 }
 
+/// Number of errors in the recent `n` closed connections.
+fn recent_error_count(close_history: u64, n: u8) -> u8 {
+    let n = 64 - cmp::min(n, 64);
+    (close_history << n).count_ones() as u8
+}
+
+fn recent_error_rate(close_history: u64, n: u8) -> f32 {
+    recent_error_count(close_history, n) as f32 / (cmp::min(n, 64) as f32)
+}
+
 impl fmt::Display for ProxyServer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.proto == ProxyProto::Direct {
@@ -569,6 +1937,15 @@ impl fmt::Display for ProxyProto {
             ProxyProto::Socks5 { .. } => write!(f, "SOCKSv5"),
             ProxyProto::Http { .. } => write!(f, "HTTP"),
             ProxyProto::Direct { .. } => write!(f, "DIRECT"),
+            #[cfg(feature = "http2")]
+            ProxyProto::Http2(_) => write!(f, "HTTP/2"),
+            #[cfg(feature = "ssh")]
+            ProxyProto::Ssh(_) => write!(f, "SSH"),
+            #[cfg(feature = "websocket")]
+            ProxyProto::WebSocket(_) => write!(f, "WebSocket"),
+            #[cfg(feature = "mux")]
+            ProxyProto::Moproxy { .. } => write!(f, "moproxy"),
+            ProxyProto::Custom(ref proto) => write!(f, "{}", proto.name),
         }
     }
 }
@@ -582,9 +1959,22 @@ impl fmt::Display for Address {
     }
 }
 
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddr::Inet(ref dest) => write!(f, "{}", dest),
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 impl fmt::Display for Destination {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}", self.host, self.port)
+        match self.host {
+            Address::Ip(IpAddr::V6(ref ip)) => write!(f, "[{}]:{}", ip, self.port),
+            ref host => write!(f, "{}:{}", host, self.port),
+        }
     }
 }
 
@@ -600,3 +1990,99 @@ impl FromStr for ProxyProto {
         }
     }
 }
+
+impl FromStr for Destination {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Destination, &'static str> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+        let mut parts = s.rsplitn(2, ':');
+        let port = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or("missing or invalid port number")?;
+        let host = parts.next().ok_or("missing port number")?;
+        if host.is_empty() || host.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Err("not a valid host name");
+        }
+        Ok((host, port).into())
+    }
+}
+
+impl FromStr for ServerAddr {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<ServerAddr, &'static str> {
+        #[cfg(unix)]
+        {
+            if s.starts_with('/') {
+                return Ok(ServerAddr::Unix(s.into()));
+            }
+        }
+        s.parse::<Destination>().map(ServerAddr::Inet)
+    }
+}
+
+#[test]
+fn test_parse_destination() {
+    let dest: Destination = "192.0.2.1:1080".parse().unwrap();
+    assert_eq!(dest.host, Address::Ip("192.0.2.1".parse().unwrap()));
+    assert_eq!(dest.to_string(), "192.0.2.1:1080");
+    let dest: Destination = "[2001:db8::1]:1080".parse().unwrap();
+    assert_eq!(dest.to_string(), "[2001:db8::1]:1080");
+    let dest: Destination = "proxy.example.com:3128".parse().unwrap();
+    assert_eq!(dest.host, Address::Domain("proxy.example.com".into()));
+    assert_eq!(dest.port, 3128);
+    assert!("proxy.example.com".parse::<Destination>().is_err());
+    assert!("2001:db8::1:1080".parse::<Destination>().is_err());
+    assert!(":1080".parse::<Destination>().is_err());
+    #[cfg(unix)]
+    assert_eq!(
+        "/run/tor/socks.sock".parse::<ServerAddr>().unwrap(),
+        ServerAddr::Unix("/run/tor/socks.sock".into())
+    );
+}
+
+#[test]
+fn test_probe_stats() {
+    let ms = |n| Some(Duration::from_millis(n));
+    let stats = ProbeStats::from_samples(&[ms(120), None, ms(100), ms(140)]).unwrap();
+    assert_eq!(stats.samples, 4);
+    assert_eq!(stats.lost, 1);
+    assert_eq!(stats.min, Duration::from_millis(100));
+    assert_eq!(stats.median, Duration::from_millis(120));
+    assert_eq!(stats.jitter, Duration::from_millis(30));
+    assert_eq!(stats.loss(), 0.25);
+    assert_eq!(ProbeStats::from_samples(&[None, None]), None);
+
+    let server = ProxyServer::direct(Duration::from_secs(1));
+    server.update_delay(Some(stats));
+    assert!(server.score().is_some());
+    server.update_delay(None);
+    assert!(server.score().is_none());
+}
+
+#[test]
+fn test_demote_on_failures() {
+    use futures::FutureExt;
+    let server = ProxyServer::direct(Duration::from_secs(1));
+    let stats = ProbeStats::from_samples(&[Some(Duration::from_millis(100))]);
+    server.update_delay(stats);
+    server.update_stats_conn_fail();
+    server.update_stats_conn_ok();
+    server.update_stats_conn_fail();
+    server.update_stats_conn_fail();
+    assert!(server.score().is_some());
+    assert!(server.probe_needed().now_or_never().is_none());
+    server.update_stats_conn_fail();
+    assert!(server.score().is_none());
+    assert!(server.probe_needed().now_or_never().is_some());
+    assert!(!server.breaker_allow());
+    server.update_delay(stats);
+    assert!(server.score().is_some());
+    assert_eq!(server.status_snapshot().failures, 0);
+    assert!(server.breaker_allow());
+    assert!(!server.breaker_allow());
+    server.update_stats_conn_ok();
+    assert!(server.breaker_allow());
+}
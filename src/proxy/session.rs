@@ -0,0 +1,164 @@
+//! Connection core of moproxy mux, which multiplexes streams over one
+//! transport.
+//!
+//! Streams encode their frames into the `WriteQueue` of the shared state.
+//! A background task owns the `Transport`, which moves queued frames to
+//! the transport and hands received bytes to the protocol's frame parser.
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use super::stream::AsyncStream;
+
+/// Streams wait once this many bytes are queued but not yet written.
+const MAX_PENDING_WRITE: usize = 256 * 1024;
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// Encoded frames waiting for the connection task.
+#[derive(Default)]
+pub(crate) struct WriteQueue {
+    buf: Vec<u8>,
+    io_waker: Option<Waker>,
+    /// Streams waiting for `buf` to be drained.
+    waiters: Vec<Waker>,
+}
+
+impl WriteQueue {
+    /// Queue with `buf` to be sent first.
+    pub fn new(buf: Vec<u8>) -> Self {
+        WriteQueue {
+            buf,
+            ..Default::default()
+        }
+    }
+
+    /// Buffer to append frames into. Wake up the connection task.
+    pub fn buf(&mut self) -> &mut Vec<u8> {
+        self.wake_io();
+        &mut self.buf
+    }
+
+    pub fn wake_io(&mut self) {
+        if let Some(waker) = self.io_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Ready if there is room for more frames. Otherwise, the stream is
+    /// woken up once the queue is taken by the connection task.
+    pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<()> {
+        if self.buf.len() < MAX_PENDING_WRITE {
+            return Poll::Ready(());
+        }
+        self.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Move all queued frames into the empty `buf`.
+    fn take_into(&mut self, buf: &mut Vec<u8>) {
+        mem::swap(buf, &mut self.buf);
+        self.waiters.drain(..).for_each(Waker::wake);
+    }
+
+    /// Wake up all waiting streams on connection closed.
+    pub fn close(&mut self) {
+        self.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// Shared state of a session, guarded by a mutex.
+pub(crate) trait SessionState {
+    fn queue(&mut self) -> &mut WriteQueue;
+
+    /// Whether the connection task can stop once all frames are written.
+    fn is_finished(&self) -> bool;
+}
+
+/// The transport of a session, owned by the connection task.
+pub(crate) struct Transport<S> {
+    stream: S,
+    read_buf: BytesMut,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncStream> Transport<S> {
+    pub fn new(stream: S) -> Self {
+        Transport {
+            stream,
+            read_buf: BytesMut::new(),
+            write_buf: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Write out frames queued in `state`, and call `handle` with the read
+    /// buffer whenever more data is received. `handle` consumes complete
+    /// frames and leaves the partial one in the buffer.
+    ///
+    /// Ready on EOF, error, or once `state` is finished.
+    pub fn poll<T, H>(
+        &mut self,
+        cx: &mut Context,
+        state: &Mutex<T>,
+        mut handle: H,
+    ) -> Poll<io::Result<()>>
+    where
+        T: SessionState,
+        H: FnMut(&mut BytesMut) -> io::Result<()>,
+    {
+        loop {
+            if self.write_pos == self.write_buf.len() {
+                self.write_buf.clear();
+                self.write_pos = 0;
+                state.lock().queue().take_into(&mut self.write_buf);
+            }
+            let mut write_pending = false;
+            if self.write_pos < self.write_buf.len() {
+                while self.write_pos < self.write_buf.len() {
+                    let buf = &self.write_buf[self.write_pos..];
+                    match Pin::new(&mut self.stream).poll_write(cx, buf) {
+                        Poll::Pending => {
+                            write_pending = true;
+                            break;
+                        }
+                        Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                        Poll::Ready(Ok(n)) => self.write_pos += n,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    }
+                }
+                if let Poll::Ready(Err(err)) = Pin::new(&mut self.stream).poll_flush(cx) {
+                    return Poll::Ready(Err(err));
+                }
+            }
+
+            self.read_buf.reserve(READ_BUF_LEN);
+            let read_more = match Pin::new(&mut self.stream).poll_read_buf(cx, &mut self.read_buf) {
+                Poll::Pending => false,
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(_)) => true,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            };
+            if read_more {
+                handle(&mut self.read_buf)?;
+                continue;
+            }
+
+            let mut state = state.lock();
+            let finished = state.is_finished();
+            let queue = state.queue();
+            if finished && !write_pending && queue.buf.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            if write_pending || queue.buf.is_empty() {
+                queue.io_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+    }
+}
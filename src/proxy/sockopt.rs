@@ -0,0 +1,133 @@
+use serde_derive::Serialize;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+
+use super::loop_guard;
+
+/// Options applied on outgoing TCP sockets.
+///
+/// Only supported on Linux, except keepalive without its interval and
+/// count. All options are left as system defaults by default.
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Send data written before the handshake completes within SYN
+    /// (TCP Fast Open). Connecting errors are reported on the first
+    /// read or write instead.
+    pub fast_open: bool,
+    /// TCP congestion control algorithm.
+    pub congestion: Option<Box<str>>,
+    /// `TCP_NOTSENT_LOWAT` in bytes.
+    pub notsent_lowat: Option<u32>,
+    /// IP TOS, or traffic class on IPv6. DSCP is its upper 6 bits.
+    pub tos: Option<u8>,
+    pub keepalive: Option<Keepalive>,
+    /// `SO_MARK` for policy routing and firewall rules.
+    pub mark: Option<u32>,
+    /// Name of the network interface to bind to.
+    pub bind_device: Option<Box<str>>,
+    /// Local address to bind to. Connecting to addresses in other family
+    /// fails.
+    pub bind_addr: Option<IpAddr>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Keepalive {
+    /// Idle time before sending the first probe.
+    pub idle: Duration,
+    /// Interval between probes, system default if not set.
+    pub interval: Option<Duration>,
+    /// Number of unacknowledged probes before dropping the connection,
+    /// system default if not set.
+    pub count: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether any option other than keepalive is set.
+    pub fn is_linux_only(&self) -> bool {
+        let others = SocketOptions {
+            keepalive: None,
+            ..self.clone()
+        };
+        !others.is_default()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply<F: std::os::unix::io::AsRawFd>(&self, fd: &F, ipv6: bool) -> io::Result<()> {
+        use crate::tcp;
+
+        if self.fast_open {
+            tcp::set_fast_open_connect(fd)?;
+        }
+        if let Some(ref alg) = self.congestion {
+            tcp::set_congestion(fd, &**alg)?;
+        }
+        if let Some(bytes) = self.notsent_lowat {
+            tcp::set_notsent_lowat(fd, bytes)?;
+        }
+        if let Some(tos) = self.tos {
+            tcp::set_tos(fd, tos, ipv6)?;
+        }
+        if let Some(ka) = self.keepalive {
+            tcp::set_keepalive(fd, ka.idle, ka.interval, ka.count)?;
+        }
+        if let Some(mark) = self.mark {
+            tcp::set_mark(fd, mark)?;
+        }
+        if let Some(ref device) = self.bind_device {
+            tcp::set_bind_device(fd, device)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect to `addr` with `opts` set before connecting.
+/// The connection is registered to `loop_guard`.
+pub async fn connect(addr: &SocketAddr, opts: &SocketOptions) -> io::Result<TcpStream> {
+    let stream = connect_with_options(addr, opts).await?;
+    loop_guard::add_outgoing(stream.local_addr()?, *addr);
+    Ok(stream)
+}
+
+async fn connect_with_options(addr: &SocketAddr, opts: &SocketOptions) -> io::Result<TcpStream> {
+    if opts.is_default() {
+        return TcpStream::connect(addr).await;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let builder = match addr {
+            SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+        };
+        opts.apply(&builder, addr.is_ipv6())?;
+        if let Some(ip) = opts.bind_addr {
+            if ip.is_ipv6() != addr.is_ipv6() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("cannot connect {} from {}", addr, ip),
+                ));
+            }
+            builder.bind(SocketAddr::new(ip, 0))?;
+        }
+        TcpStream::connect_std(builder.to_tcp_stream()?, addr).await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        if opts.is_linux_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "socket options are only supported on Linux",
+            ));
+        }
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_keepalive(opts.keepalive.map(|ka| ka.idle))?;
+        Ok(stream)
+    }
+}
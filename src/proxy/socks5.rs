@@ -1,22 +1,20 @@
-use crate::proxy::{Address, Destination};
+use crate::proxy::{not_server_fault, Address, Destination};
 use log::trace;
 use std::io::{self, ErrorKind};
 use std::net::IpAddr;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use super::SocksUserPassAuthCredential;
 
-pub async fn handshake<T>(
-    stream: &mut TcpStream,
+pub async fn handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
     fake_handshaking: bool,
     user_pass_auth: &Option<SocksUserPassAuthCredential>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     if fake_handshaking && user_pass_auth.is_none() {
@@ -28,12 +26,13 @@ where
     }
 }
 
-pub async fn fake_handshake<T>(
-    stream: &mut TcpStream,
+pub async fn fake_handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     let mut buf = Vec::with_capacity(16);
@@ -54,13 +53,14 @@ macro_rules! err {
     };
 }
 
-pub async fn full_handshake<T>(
-    stream: &mut TcpStream,
+pub async fn full_handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
     user_pass_auth: &Option<SocksUserPassAuthCredential>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     let mut buf = vec![];
@@ -122,8 +122,20 @@ where
     buf.resize(10, 0);
     stream.read_exact(&mut buf).await?;
     trace!("socks: read reply {:?}", buf);
-    if !buf.starts_with(&[0x05, 0x00]) {
-        err!("socks server reply error");
+    if buf[0] != 0x05 {
+        err!("unrecognized reply from socks server");
+    }
+    match buf[1] {
+        0x00 => (),
+        // network or host unreachable, connection refused, TTL expired:
+        // the destination is to blame, not the server
+        0x03..=0x06 => {
+            return Err(not_server_fault(
+                ErrorKind::Other,
+                format!("socks server reply error: {}", buf[1]),
+            ))
+        }
+        rep => err!(format!("socks server reply error: {}", rep)),
     }
     if buf[3] == 4 {
         // Consume truncted IPv6 address
@@ -139,8 +151,52 @@ where
     Ok(())
 }
 
-fn build_request(buffer: &mut Vec<u8>, addr: &Destination) {
+pub(crate) fn build_request(buffer: &mut Vec<u8>, addr: &Destination) {
     buffer.extend_from_slice(&[5, 1, 0]);
+    build_address(buffer, addr);
+}
+
+/// Read a destination in the format of ATYP, DST.ADDR and DST.PORT.
+pub(crate) async fn read_address<S>(stream: &mut S) -> io::Result<Destination>
+where
+    S: AsyncRead + Unpin,
+{
+    let addr: Address = match stream.read_u8().await? {
+        0x01 => {
+            // IPv4
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        0x03 => {
+            // Domain name
+            let len = stream.read_u8().await? as usize;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await?;
+            let domain = String::from_utf8(buf).map_err(|_| {
+                io::Error::new(ErrorKind::InvalidInput, "SOCKSv5: Invalid domain name")
+            })?;
+            domain.into()
+        }
+        0x04 => {
+            // IPv6
+            let mut buf = [0u8; 16];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "SOCKSv5: unknown address type",
+            ))
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok((addr, port).into())
+}
+
+/// Append `addr` in the format of ATYP, DST.ADDR and DST.PORT.
+pub(crate) fn build_address(buffer: &mut Vec<u8>, addr: &Destination) {
     match addr.host {
         Address::Ip(ip) => match ip {
             IpAddr::V4(ip) => {
@@ -0,0 +1,78 @@
+use libc::{c_int, c_void, O_CLOEXEC, O_NONBLOCK, SPLICE_F_MOVE, SPLICE_F_NONBLOCK};
+use std::{io, os::unix::io::RawFd, ptr};
+
+/// Max number of bytes moved into the pipe at once, same as the default
+/// capacity of pipes.
+const SPLICE_SIZE: usize = 64 * 1024;
+
+/// A pipe used as the in-kernel buffer for moving data from one socket
+/// to another with splice(2).
+#[derive(Debug)]
+pub struct Pipe {
+    read: RawFd,
+    write: RawFd,
+    /// Number of bytes in the pipe.
+    len: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds: [c_int; 2] = [-1; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), O_NONBLOCK | O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+            len: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Move data from the socket into the pipe. Return zero on EOF.
+    pub fn splice_from(&mut self, fd: RawFd) -> io::Result<usize> {
+        let n = splice(fd, self.write, SPLICE_SIZE)?;
+        self.len += n;
+        Ok(n)
+    }
+
+    /// Move data from the pipe into the socket.
+    pub fn splice_to(&mut self, fd: RawFd) -> io::Result<usize> {
+        let n = splice(self.read, fd, self.len)?;
+        self.len -= n;
+        Ok(n)
+    }
+
+    /// Read data from the pipe into `buf`.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.len);
+        let n = unsafe { libc::read(self.read, buf.as_mut_ptr() as *mut c_void, len) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.len -= n as usize;
+        Ok(n as usize)
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let flags = SPLICE_F_MOVE | SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len, flags) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
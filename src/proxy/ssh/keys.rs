@@ -0,0 +1,113 @@
+//! known_hosts files of OpenSSH.
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use std::io::{self, ErrorKind};
+
+/// Match `host` against a pattern with `*` and `?` wildcards.
+fn match_pattern(pattern: &[u8], host: &[u8]) -> bool {
+    match (pattern.split_first(), host.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            match_pattern(rest, host) || (!host.is_empty() && match_pattern(pattern, &host[1..]))
+        }
+        (Some((b'?', rest)), Some((_, host))) => match_pattern(rest, host),
+        (Some((p, rest)), Some((h, host))) if p.eq_ignore_ascii_case(h) => {
+            match_pattern(rest, host)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the host field of a known_hosts line matches `host`.
+fn match_hosts(hosts: &str, host: &str) -> bool {
+    if let Some(hashed) = hosts.strip_prefix("|1|") {
+        let mut parts = hashed.splitn(2, '|');
+        let salt = parts.next().and_then(|s| base64::decode(s).ok());
+        let hash = parts.next().and_then(|s| base64::decode(s).ok());
+        return match (salt, hash) {
+            (Some(salt), Some(hash)) => {
+                let mut mac = Hmac::<Sha1>::new_varkey(&salt).unwrap();
+                mac.update(host.as_bytes());
+                mac.verify(&hash).is_ok()
+            }
+            _ => false,
+        };
+    }
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if match_pattern(pattern.as_bytes(), host.as_bytes()) {
+                return false;
+            }
+        } else if match_pattern(pattern.as_bytes(), host.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Check server's `host_key`, in SSH wire format, against the content of
+/// a known_hosts file. `host` is either `name` or `[name]:port` if port is
+/// not 22.
+pub(super) fn check_known_hosts(known_hosts: &str, host: &str, host_key: &[u8]) -> io::Result<()> {
+    let mut found = false;
+    for line in known_hosts.lines() {
+        let mut fields = line.split_whitespace().peekable();
+        let marker = match fields.peek() {
+            Some(field) if field.starts_with('#') => continue,
+            Some(field) if field.starts_with('@') => fields.next(),
+            Some(_) => None,
+            None => continue,
+        };
+        // The key type is also part of the key itself
+        let (hosts, key) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(hosts), Some(_), Some(key)) => (hosts, key),
+            _ => continue,
+        };
+        if !match_hosts(hosts, host) {
+            continue;
+        }
+        let same_key = matches!(base64::decode(key), Ok(key) if key == host_key);
+        match marker {
+            Some("@revoked") if same_key => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("ssh: host key of {} is revoked", host),
+                ))
+            }
+            None if same_key => found = true,
+            _ => (),
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("ssh: host key of {} not found in known_hosts", host),
+        ))
+    }
+}
+
+#[test]
+fn test_known_hosts() {
+    let key = "AAAAC3NzaC1lZDI1NTE5AAAAIERkgQQx8yct3kJ8rRh5KEP3h8/5uCGhwSeWRA+nAJuN";
+    let blob = base64::decode(key).unwrap();
+    let known_hosts = format!(
+        "# comment\n\
+         |1|dkR2XSztCYRE9XjktNolORDjZvk=|r2T8kSx/YF5v1FvRZU8yEldDB2A= ssh-ed25519 {key}\n\
+         *.example.net,!bad.example.net ssh-ed25519 {key} host\n\
+         [192.0.2.1]:2222 ssh-ed25519 {key}\n\
+         @revoked old.example.org ssh-ed25519 {key}\n\
+         old.example.org ssh-ed25519 {key}\n",
+        key = key
+    );
+    let check = |host| check_known_hosts(&known_hosts, host, &blob).is_ok();
+    assert!(check("example.com"));
+    assert!(check("www.example.net"));
+    assert!(check("[192.0.2.1]:2222"));
+    assert!(!check("bad.example.net"));
+    assert!(!check("192.0.2.1"));
+    assert!(!check("old.example.org"));
+    assert!(check_known_hosts(&known_hosts, "example.com", &blob[1..]).is_err());
+}
@@ -0,0 +1,266 @@
+use futures::future::poll_fn;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{
+    fmt, io,
+    mem::MaybeUninit,
+    net::Shutdown,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use super::{connector::BoxedStream, sockopt::Keepalive};
+
+/// A bidirectional byte stream that can be piped by moproxy.
+///
+/// Besides `AsyncRead` and `AsyncWrite`, socket-specific operations are
+/// optional capabilities. Their default implementations do nothing or
+/// report the operation as unsupported, so that any transport can be
+/// plugged in by implementing only the required traits.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin {
+    /// Set `TCP_NODELAY`. Do nothing by default.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Set TCP keepalive. Do nothing by default.
+    fn set_keepalive(&self, _keepalive: Option<Keepalive>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether `poll_peek()` is supported. `false` by default.
+    fn supports_peek(&self) -> bool {
+        false
+    }
+
+    /// Receive data without removing it from the receive queue.
+    /// Return error by default.
+    fn poll_peek(&mut self, _cx: &mut Context, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::Other,
+            "peek is not supported",
+        )))
+    }
+
+    /// Whether `poll_shutdown()` closes the write half only, i.e. data can
+    /// still be read after it. `false` by default.
+    fn supports_half_close(&self) -> bool {
+        false
+    }
+
+    /// Raw fd of the TCP socket, so that data can be moved with splice(2)
+    /// without copying through user space. `None` by default.
+    ///
+    /// Must only be returned by streams without any user-space buffering,
+    /// and whose `poll_peek()` reports the readiness of the socket.
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Receive data without removing it from the receive queue.
+pub async fn peek<S: AsyncStream>(stream: &mut S, buf: &mut [u8]) -> io::Result<usize> {
+    poll_fn(|cx| stream.poll_peek(cx, buf)).await
+}
+
+impl AsyncStream for TcpStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        // interval & count are only supported on Linux
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ka) = keepalive {
+                return crate::tcp::set_keepalive(self, ka.idle, ka.interval, ka.count);
+            }
+        }
+        TcpStream::set_keepalive(self, keepalive.map(|ka| ka.idle))
+    }
+
+    fn supports_peek(&self) -> bool {
+        true
+    }
+
+    fn poll_peek(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        TcpStream::poll_peek(self, cx, buf)
+    }
+
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(unix)]
+impl AsyncStream for UnixStream {
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
+
+/// Connection to a proxy server.
+pub enum ServerStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// Stream returned by a custom `Connector`.
+    Custom(BoxedStream),
+}
+
+impl fmt::Debug for ServerStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerStream::Tcp(s) => f.debug_tuple("Tcp").field(s).finish(),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => f.debug_tuple("Unix").field(s).finish(),
+            ServerStream::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl From<TcpStream> for ServerStream {
+    fn from(stream: TcpStream) -> Self {
+        ServerStream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for ServerStream {
+    fn from(stream: UnixStream) -> Self {
+        ServerStream::Unix(stream)
+    }
+}
+
+impl ServerStream {
+    /// Shut down the socket. Do nothing on custom streams.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ServerStream::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => s.shutdown(how),
+            ServerStream::Custom(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncStream for ServerStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            ServerStream::Tcp(s) => AsyncStream::set_nodelay(s, nodelay),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => AsyncStream::set_nodelay(s, nodelay),
+            ServerStream::Custom(s) => s.set_nodelay(nodelay),
+        }
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        match self {
+            ServerStream::Tcp(s) => AsyncStream::set_keepalive(s, keepalive),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => AsyncStream::set_keepalive(s, keepalive),
+            ServerStream::Custom(s) => s.set_keepalive(keepalive),
+        }
+    }
+
+    fn supports_peek(&self) -> bool {
+        match self {
+            ServerStream::Tcp(s) => s.supports_peek(),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => s.supports_peek(),
+            ServerStream::Custom(s) => s.supports_peek(),
+        }
+    }
+
+    fn poll_peek(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self {
+            ServerStream::Tcp(s) => AsyncStream::poll_peek(s, cx, buf),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => AsyncStream::poll_peek(s, cx, buf),
+            ServerStream::Custom(s) => s.poll_peek(cx, buf),
+        }
+    }
+
+    fn supports_half_close(&self) -> bool {
+        match self {
+            ServerStream::Tcp(s) => s.supports_half_close(),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => s.supports_half_close(),
+            ServerStream::Custom(s) => s.supports_half_close(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice_fd(&self) -> Option<RawFd> {
+        match self {
+            ServerStream::Tcp(s) => s.splice_fd(),
+            ServerStream::Unix(_) => None,
+            ServerStream::Custom(s) => s.splice_fd(),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        match self {
+            ServerStream::Tcp(s) => s.prepare_uninitialized_buffer(buf),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => s.prepare_uninitialized_buffer(buf),
+            ServerStream::Custom(s) => s.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            ServerStream::Custom(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            ServerStream::Custom(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            ServerStream::Custom(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            ServerStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            ServerStream::Custom(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+pub use native_tls::Certificate;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+pub use tokio_native_tls::{TlsAcceptor, TlsStream};
+
+use super::{sockopt::Keepalive, stream::AsyncStream};
+
+/// Start TLS on the stream, verify server's certificate with `domain`.
+/// Protocols in `alpn` are offered via ALPN if not empty.
+pub async fn connect<S>(domain: &str, stream: S, alpn: &[&str]) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    connect_with_root(domain, stream, alpn, None).await
+}
+
+/// Same as `connect()`, but also trust `root` as a CA certificate.
+pub async fn connect_with_root<S>(
+    domain: &str,
+    stream: S,
+    alpn: &[&str],
+    root: Option<Certificate>,
+) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.request_alpns(alpn);
+    if let Some(root) = root {
+        builder.add_root_certificate(root);
+    }
+    let connector = builder.build().map_err(tls_error)?;
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .map_err(tls_error)
+}
+
+/// Parse a PEM-encoded certificate.
+pub fn certificate(pem: &[u8]) -> io::Result<Certificate> {
+    Certificate::from_pem(pem).map_err(tls_error)
+}
+
+/// Server side of TLS with the PEM-encoded certificate chain `cert`
+/// and its PKCS #8 private key `key`.
+pub fn acceptor(cert: &[u8], key: &[u8]) -> io::Result<TlsAcceptor> {
+    let identity = native_tls::Identity::from_pkcs8(cert, key).map_err(tls_error)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(tls_error)?;
+    Ok(acceptor.into())
+}
+
+fn tls_error(err: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// TLS cannot be half closed: closing the write side sends close_notify
+/// and ends the session.
+impl<S: AsyncStream> AsyncStream for TlsStream<S> {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.get_ref().get_ref().get_ref().set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.get_ref().get_ref().get_ref().set_keepalive(keepalive)
+    }
+}
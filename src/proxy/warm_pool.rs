@@ -0,0 +1,146 @@
+use futures::{future::join_all, FutureExt};
+use log::debug;
+use parking_lot::Mutex;
+use std::{collections::VecDeque, fmt, future::Future, io, sync::Arc, time::Duration};
+use tokio::{
+    sync::Notify,
+    time::{timeout, Instant},
+};
+
+use super::{
+    stream::{peek, AsyncStream, ServerStream},
+    SharedStatus, WarmPoolStatus,
+};
+
+struct IdleStream {
+    stream: ServerStream,
+    since: Instant,
+}
+
+impl IdleStream {
+    /// Whether the stream is still usable after idle for a while.
+    /// Any data or EOF received from the server means it's broken.
+    fn is_healthy(&mut self, max_idle: Duration) -> bool {
+        if self.since.elapsed() >= max_idle {
+            return false;
+        }
+        if !self.stream.supports_peek() {
+            return true;
+        }
+        let mut buf = [0u8; 1];
+        peek(&mut self.stream, &mut buf).now_or_never().is_none()
+    }
+}
+
+/// Idle connections to a proxy server established in advance, so that
+/// new clients can start their handshakes without waiting for TCP
+/// connecting.
+pub struct WarmPool {
+    idle: Mutex<VecDeque<IdleStream>>,
+    taken: Notify,
+    status: Arc<Mutex<SharedStatus>>,
+}
+
+impl fmt::Debug for WarmPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WarmPool")
+            .field("idle", &self.idle.lock().len())
+            .finish()
+    }
+}
+
+impl WarmPool {
+    pub fn new(status: Arc<Mutex<SharedStatus>>) -> Self {
+        WarmPool {
+            idle: Default::default(),
+            taken: Notify::new(),
+            status,
+        }
+    }
+
+    /// Take a healthy idle connection out of the pool, or return `None`
+    /// if there is no one.
+    pub fn take(&self, max_idle: Duration) -> Option<ServerStream> {
+        // Check health without holding the lock
+        let stream = loop {
+            let stream = self.idle.lock().pop_front();
+            match stream {
+                Some(mut stream) => {
+                    if stream.is_healthy(max_idle) {
+                        break Some(stream.stream);
+                    }
+                    debug!("drop broken or expired idle connection");
+                }
+                None => break None,
+            }
+        };
+        let left = self.idle.lock().len();
+        self.update_status(|status| {
+            match stream {
+                Some(_) => status.hits += 1,
+                None => status.misses += 1,
+            }
+            status.idle = left as u32;
+        });
+        self.taken.notify();
+        stream
+    }
+
+    /// Drop broken or expired connections, then connect to the server
+    /// until there are `size` idle connections in the pool.
+    pub async fn fill<F, Fut>(
+        &self,
+        size: usize,
+        max_idle: Duration,
+        max_wait: Duration,
+        connect: F,
+    ) where
+        F: Fn() -> Fut,
+        Fut: Future<Output = io::Result<ServerStream>>,
+    {
+        // Check in place, since peeking won't block, so that healthy
+        // connections are left available to `take()`.
+        let missing = {
+            let mut idle = self.idle.lock();
+            idle.retain_mut(|stream| stream.is_healthy(max_idle));
+            idle.truncate(size);
+            size.saturating_sub(idle.len())
+        };
+        if missing > 0 {
+            let connects = (0..missing).map(|_| timeout(max_wait, connect()));
+            let streams = join_all(connects)
+                .await
+                .into_iter()
+                .filter_map(|result| match result {
+                    Ok(Ok(stream)) => Some(stream),
+                    Ok(Err(err)) => {
+                        debug!("fail to fill pool: {}", err);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("fail to fill pool: timed out");
+                        None
+                    }
+                });
+            let since = Instant::now();
+            self.idle
+                .lock()
+                .extend(streams.map(|stream| IdleStream { stream, since }));
+        }
+        let idle = self.idle.lock().len();
+        self.update_status(|status| status.idle = idle as u32);
+    }
+
+    /// Wait until a connection is requested from the pool.
+    pub async fn taken(&self) {
+        self.taken.notified().await
+    }
+
+    fn update_status<F>(&self, func: F)
+    where
+        F: FnOnce(&mut WarmPoolStatus),
+    {
+        let mut status = self.status.lock();
+        func(status.warm_pool.get_or_insert_with(Default::default));
+    }
+}
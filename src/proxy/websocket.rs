@@ -0,0 +1,477 @@
+use bytes::{Buf, BytesMut};
+use httparse::{Response, Status, EMPTY_HEADER};
+use log::{debug, trace};
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+use std::{
+    cmp,
+    fmt::Write,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{sockopt::Keepalive, socks5::build_request, stream::AsyncStream, Destination};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_RESPONSE_LEN: usize = 64_000;
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+const READ_BUF_LEN: usize = 4096;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+macro_rules! try_poll {
+    ($expr:expr) => {
+        match $expr {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(v)) => v,
+        }
+    };
+}
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct WebSocketConfig {
+    /// Path of the HTTP Upgrade request.
+    pub path: Box<str>,
+    /// Value of Host header and TLS server name.
+    /// Default to the host of server address.
+    pub host: Option<Box<str>>,
+    /// Do TLS handshake before the HTTP Upgrade request.
+    pub tls: bool,
+    /// Extra headers sent with the HTTP Upgrade request.
+    /// They may contain credentials so never serialized.
+    #[serde(skip_serializing)]
+    pub headers: Vec<(Box<str>, Box<str>)>,
+    /// Send destination as `host:port` in this header. If not set,
+    /// a SOCKSv5 CONNECT request is sent as the preamble of first message.
+    pub dest_header: Option<Box<str>>,
+}
+
+/// Do WebSocket handshake on `stream`, then send the destination (if
+/// `dest_header` is not set) and `data` in the first binary message.
+pub async fn handshake<S>(
+    mut stream: S,
+    config: &WebSocketConfig,
+    host: &str,
+    dest: &Destination,
+    data: Option<&[u8]>,
+) -> io::Result<WebSocketStream<S>>
+where
+    S: AsyncStream,
+{
+    let key = base64::encode(rand::random::<[u8; 16]>());
+    let mut request = String::new();
+    write!(
+        &mut request,
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n",
+        config.path, host, key
+    )
+    .unwrap();
+    for (name, value) in config.headers.iter() {
+        write!(&mut request, "{}: {}\r\n", name, value).unwrap();
+    }
+    if let Some(ref name) = config.dest_header {
+        write!(&mut request, "{}: {}\r\n", name, dest).unwrap();
+    }
+    request.push_str("\r\n");
+    trace!("websocket: write request {:?}", request);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read until the end of response header
+    let mut buf = BytesMut::with_capacity(READ_BUF_LEN);
+    let header_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_RESPONSE_LEN {
+            return Err(io::Error::new(ErrorKind::Other, "response too large"));
+        }
+        buf.reserve(READ_BUF_LEN);
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "websocket: connection closed during handshake",
+            ));
+        }
+    };
+    check_response(&buf[..header_len], &key)?;
+    buf.advance(header_len);
+    debug!("websocket handshaking done");
+
+    let mut stream = WebSocketStream::new(stream, buf);
+    let mut first_msg = Vec::new();
+    if config.dest_header.is_none() {
+        build_request(&mut first_msg, dest);
+    }
+    if let Some(data) = data {
+        first_msg.extend_from_slice(data);
+    }
+    if !first_msg.is_empty() {
+        stream.write_all(&first_msg).await?;
+        stream.flush().await?;
+    }
+    Ok(stream)
+}
+
+fn check_response(header: &[u8], key: &str) -> io::Result<()> {
+    let mut headers = [EMPTY_HEADER; 32];
+    let mut response = Response::new(&mut headers);
+    match response.parse(header) {
+        Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
+        Ok(Status::Partial) => {
+            return Err(io::Error::new(ErrorKind::Other, "incomplete response"));
+        }
+        Ok(Status::Complete(_)) => (),
+    }
+    let code = response.code.unwrap();
+    if code != 101 {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("websocket: upgrade rejected with {}", code),
+        ));
+    }
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WS_GUID.as_bytes());
+    let expected = base64::encode(sha1.finalize());
+    let accept = response
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+        .map(|h| h.value);
+    if accept != Some(expected.as_bytes()) {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "websocket: invalid Sec-WebSocket-Accept",
+        ));
+    }
+    Ok(())
+}
+
+/// Append a masked frame into `buf`.
+fn encode_frame(buf: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    buf.push(0x80 | opcode); // FIN
+    let len = payload.len();
+    if len < 126 {
+        buf.push(0x80 | len as u8);
+    } else if len <= 0xffff {
+        buf.push(0x80 | 126);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0x80 | 127);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    let mask: [u8; 4] = rand::random();
+    buf.extend_from_slice(&mask);
+    buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+}
+
+#[derive(Debug, PartialEq)]
+struct FrameHeader {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    payload_len: u64,
+    header_len: usize,
+}
+
+/// Return `None` if not enough data.
+fn parse_frame_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut header_len) = match buf[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            len.copy_from_slice(buf.get(2..4)?);
+            (u16::from_be_bytes(len) as u64, 4)
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(buf.get(2..10)?);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (len as u64, 2),
+    };
+    let mask = if masked {
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(buf.get(header_len..header_len + 4)?);
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    Some(FrameHeader {
+        opcode,
+        mask,
+        payload_len,
+        header_len,
+    })
+}
+
+/// Byte stream over WebSocket binary messages.
+///
+/// Each write is sent as one binary frame. Text and binary messages from
+/// server are both read as bytes; ping is answered with pong; close frame
+/// is answered with close frame and read as EOF. Shutdown sends a close frame, so half close is not
+/// supported.
+pub struct WebSocketStream<S> {
+    inner: S,
+    /// Bytes read from `inner` but not consumed yet.
+    read_buf: BytesMut,
+    /// Remaining payload length of current data frame.
+    payload_left: u64,
+    /// Masking key of current data frame and the offset within it.
+    payload_mask: Option<([u8; 4], usize)>,
+    read_closed: bool,
+    /// Encoded frames waiting to be written into `inner`.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_closed: bool,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncStream,
+{
+    fn new(inner: S, read_buf: BytesMut) -> Self {
+        WebSocketStream {
+            inner,
+            read_buf,
+            payload_left: 0,
+            payload_mask: None,
+            read_closed: false,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_closed: false,
+        }
+    }
+
+    /// Write out all pending frames.
+    fn poll_write_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let buf = &self.write_buf[self.write_pos..];
+            let n = try_poll!(Pin::new(&mut self.inner).poll_write(cx, buf));
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Handle a control frame. Return `false` if the payload is incomplete.
+    fn handle_control_frame(&mut self, header: &FrameHeader) -> bool {
+        let frame_len = header.header_len + header.payload_len as usize;
+        if self.read_buf.len() < frame_len {
+            return false;
+        }
+        let mut payload = self.read_buf[header.header_len..frame_len].to_vec();
+        if let Some(mask) = header.mask {
+            payload
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        self.read_buf.advance(frame_len);
+        match header.opcode {
+            OP_CLOSE => {
+                trace!("websocket: close frame received");
+                self.read_closed = true;
+                // Echo the status code back (RFC 6455 section 5.5.1).
+                if !self.write_closed {
+                    self.write_closed = true;
+                    let code = payload.get(..2).unwrap_or_default();
+                    encode_frame(&mut self.write_buf, OP_CLOSE, code);
+                }
+            }
+            OP_PING => {
+                trace!("websocket: ping received");
+                if !self.write_closed {
+                    encode_frame(&mut self.write_buf, OP_PONG, &payload);
+                }
+            }
+            _ => trace!("websocket: pong received"),
+        }
+        true
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncStream,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // send out pending pong, if any
+        if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if this.read_closed {
+                // make sure the reply of close frame is sent before EOF
+                try_poll!(this.poll_write_pending(cx));
+                return Poll::Ready(Ok(0));
+            }
+            if this.payload_left > 0 && !this.read_buf.is_empty() {
+                let n = cmp::min(buf.len(), this.read_buf.len());
+                let n = cmp::min(n as u64, this.payload_left) as usize;
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                if let Some((mask, ref mut offset)) = this.payload_mask {
+                    for b in buf[..n].iter_mut() {
+                        *b ^= mask[*offset % 4];
+                        *offset += 1;
+                    }
+                }
+                this.read_buf.advance(n);
+                this.payload_left -= n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            if this.payload_left == 0 {
+                if let Some(header) = parse_frame_header(&this.read_buf) {
+                    match header.opcode {
+                        OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                            this.read_buf.advance(header.header_len);
+                            this.payload_left = header.payload_len;
+                            this.payload_mask = header.mask.map(|mask| (mask, 0));
+                            continue;
+                        }
+                        OP_CLOSE | OP_PING | OP_PONG => {
+                            if header.payload_len > 125 {
+                                return Poll::Ready(Err(io::Error::new(
+                                    ErrorKind::InvalidData,
+                                    "websocket: control frame too large",
+                                )));
+                            }
+                            if this.handle_control_frame(&header) {
+                                if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+                                    return Poll::Ready(Err(err));
+                                }
+                                continue;
+                            }
+                        }
+                        op => {
+                            return Poll::Ready(Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("websocket: unknown opcode {}", op),
+                            )));
+                        }
+                    }
+                }
+            }
+            // need more data
+            this.read_buf.reserve(READ_BUF_LEN);
+            let n = try_poll!(Pin::new(&mut this.inner).poll_read_buf(cx, &mut this.read_buf));
+            if n == 0 {
+                if this.payload_left > 0 || !this.read_buf.is_empty() {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "websocket: incomplete frame",
+                    )));
+                }
+                this.read_closed = true;
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncStream,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write_closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        try_poll!(this.poll_write_pending(cx));
+        let n = cmp::min(buf.len(), MAX_FRAME_PAYLOAD);
+        encode_frame(&mut this.write_buf, OP_BINARY, &buf[..n]);
+        // the frame is buffered, remaining will be written on next poll
+        if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        try_poll!(self.poll_write_pending(cx));
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if !self.write_closed {
+            self.write_closed = true;
+            encode_frame(&mut self.write_buf, OP_CLOSE, &[]);
+        }
+        try_poll!(self.poll_write_pending(cx));
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> AsyncStream for WebSocketStream<S>
+where
+    S: AsyncStream,
+{
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
+}
+
+#[test]
+fn test_frame_header() {
+    let mut buf = Vec::new();
+    encode_frame(&mut buf, OP_BINARY, &[1, 2, 3]);
+    let header = parse_frame_header(&buf).unwrap();
+    assert_eq!(header.opcode, OP_BINARY);
+    assert_eq!(header.payload_len, 3);
+    assert_eq!(header.header_len, 6);
+    let mask = header.mask.unwrap();
+    let payload: Vec<_> = buf[6..]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    assert_eq!(payload, vec![1, 2, 3]);
+
+    buf.clear();
+    encode_frame(&mut buf, OP_BINARY, &[0; 300]);
+    assert_eq!(parse_frame_header(&buf).unwrap().payload_len, 300);
+    assert_eq!(parse_frame_header(&buf[..3]), None);
+    assert_eq!(
+        parse_frame_header(&[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        Some(FrameHeader {
+            opcode: OP_BINARY,
+            mask: None,
+            payload_len: 65536,
+            header_len: 10,
+        })
+    );
+}
@@ -1,14 +1,20 @@
-use libc::{self, c_void, setsockopt, socklen_t, IPPROTO_TCP, TCP_CONGESTION};
+use libc::{
+    self, c_int, c_void, setsockopt, socklen_t, IPPROTO_IP, IPPROTO_IPV6, IPPROTO_TCP, IPV6_TCLASS,
+    IP_TOS, SOL_SOCKET, SO_BINDTODEVICE, SO_KEEPALIVE, SO_MARK, TCP_CONGESTION,
+    TCP_FASTOPEN_CONNECT, TCP_KEEPCNT, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_NOTSENT_LOWAT,
+};
 use nix::{
     self,
-    sys::socket::{getsockopt, sockopt::OriginalDst},
+    ifaddrs::getifaddrs,
+    sys::socket::{getsockopt, sockopt::OriginalDst, SockAddr},
 };
 use std::{
     ffi::OsStr,
     io::{self, ErrorKind},
     mem,
-    net::{SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, SocketAddrV4, SocketAddrV6},
     os::unix::io::AsRawFd,
+    time::Duration,
 };
 
 pub fn get_original_dest<F>(fd: &F) -> io::Result<SocketAddrV4>
@@ -74,3 +80,102 @@ where
         Err(io::Error::last_os_error())
     }
 }
+
+fn set_int_opt<F>(fd: &F, level: c_int, name: c_int, value: c_int) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let ret = unsafe {
+        setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const c_void,
+            mem::size_of_val(&value) as socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Let `connect()` return immediately and carry the first written data
+/// in SYN (TCP Fast Open). Must be set before connecting.
+pub fn set_fast_open_connect<F: AsRawFd>(fd: &F) -> io::Result<()> {
+    set_int_opt(fd, IPPROTO_TCP, TCP_FASTOPEN_CONNECT, 1)
+}
+
+pub fn set_notsent_lowat<F: AsRawFd>(fd: &F, bytes: u32) -> io::Result<()> {
+    set_int_opt(fd, IPPROTO_TCP, TCP_NOTSENT_LOWAT, bytes as c_int)
+}
+
+/// Set IP TOS, or traffic class if `ipv6` is true.
+pub fn set_tos<F: AsRawFd>(fd: &F, tos: u8, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        set_int_opt(fd, IPPROTO_IPV6, IPV6_TCLASS, tos as c_int)
+    } else {
+        set_int_opt(fd, IPPROTO_IP, IP_TOS, tos as c_int)
+    }
+}
+
+/// Enable TCP keepalive. Use system defaults for `interval` and `count`
+/// if not given.
+pub fn set_keepalive<F: AsRawFd>(
+    fd: &F,
+    idle: Duration,
+    interval: Option<Duration>,
+    count: Option<u32>,
+) -> io::Result<()> {
+    set_int_opt(fd, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+    set_int_opt(
+        fd,
+        IPPROTO_TCP,
+        TCP_KEEPIDLE,
+        idle.as_secs().max(1) as c_int,
+    )?;
+    if let Some(interval) = interval {
+        let secs = interval.as_secs().max(1) as c_int;
+        set_int_opt(fd, IPPROTO_TCP, TCP_KEEPINTVL, secs)?;
+    }
+    if let Some(count) = count {
+        set_int_opt(fd, IPPROTO_TCP, TCP_KEEPCNT, count as c_int)?;
+    }
+    Ok(())
+}
+
+/// Set `SO_MARK`. Require CAP_NET_ADMIN.
+pub fn set_mark<F: AsRawFd>(fd: &F, mark: u32) -> io::Result<()> {
+    set_int_opt(fd, SOL_SOCKET, SO_MARK, mark as c_int)
+}
+
+/// Bind the socket to a network interface (`SO_BINDTODEVICE`).
+pub fn set_bind_device<F: AsRawFd>(fd: &F, device: &str) -> io::Result<()> {
+    let ret = unsafe {
+        setsockopt(
+            fd.as_raw_fd(),
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            device.as_ptr() as *const c_void,
+            device.len() as socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether `ip` is assigned to one of the network interfaces.
+pub fn is_local_ip(ip: IpAddr) -> io::Result<bool> {
+    let addrs = getifaddrs().map_err(|e| match e {
+        nix::Error::Sys(err) => io::Error::from(err),
+        _ => io::Error::new(ErrorKind::Other, e),
+    })?;
+    Ok(addrs.into_iter().any(|addr| match addr.address {
+        Some(SockAddr::Inet(addr)) => addr.to_std().ip() == ip,
+        _ => false,
+    }))
+}
@@ -36,7 +36,7 @@ use tokio::{
 
 use crate::{
     monitor::{Monitor, Throughput},
-    proxy::{Delay, ProxyServer},
+    proxy::{breaker::BreakerState, Delay, ProxyServer},
 };
 
 pub use hyper::server::accept::from_stream;
@@ -116,8 +116,11 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
     let mut table = Table::new();
     table.add_row(row![
         "Server",
+        "Resolved",
+        "Tier",
         "Score",
         "Delay",
+        "CB",
         "CUR",
         "TTL",
         "E16:64",
@@ -134,6 +137,16 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         let row = table.add_empty_row();
         // Server
         row.add_cell(cell!(l -> server.tag));
+        // Resolved
+        let addrs = server.resolved_addrs();
+        if addrs.is_empty() {
+            row.add_cell(cell!(l -> "-"));
+        } else {
+            let addrs: Vec<_> = addrs.iter().map(|addr| addr.to_string()).collect();
+            row.add_cell(cell!(l -> addrs.join(", ")));
+        }
+        // Tier
+        row.add_cell(cell!(r -> server.tier()));
         // Score
         if let Some(v) = status.score {
             row.add_cell(cell!(r -> v));
@@ -146,6 +159,11 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         } else {
             row.add_cell(cell!(r -> "-"));
         }
+        // Circuit breaker
+        match status.breaker.state() {
+            BreakerState::Closed => row.add_cell(cell!(r -> "-")),
+            state => row.add_cell(cell!(r -> state)),
+        }
         // CUR TTL
         row.add_cell(cell!(r -> status.conn_alive));
         row.add_cell(cell!(r -> status.conn_total));
@@ -5,7 +5,11 @@ use std::{
 };
 
 use super::{ServerStatus, Status};
-use crate::{monitor::Monitor, proxy::Delay};
+use crate::{
+    client,
+    monitor::Monitor,
+    proxy::{breaker::BreakerState, loop_guard, Delay},
+};
 
 fn new_metric(buf: &mut String, name: &str, metric_type: &str, help: &str) {
     writeln!(buf, "# HELP moproxy_{} {}", name, help).unwrap();
@@ -66,6 +70,16 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> Response<Body> {
         "Current total number of connections",
         |s| Some(s.server.status_snapshot().conn_total)
     );
+    server_gauge!(
+        "proxy_server_pool_hits_total",
+        "Current total number of connections taken from warm pool",
+        |s| s.server.status_snapshot().warm_pool.map(|p| p.hits)
+    );
+    server_gauge!(
+        "proxy_server_pool_misses_total",
+        "Current total number of connections made while warm pool is empty",
+        |s| s.server.status_snapshot().warm_pool.map(|p| p.misses)
+    );
     server_gauge!(
         "proxy_server_dns_delay_seconds",
         "Total seconds for the last DNS query test",
@@ -74,12 +88,109 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> Response<Body> {
             _ => None,
         }
     );
+    server_gauge!(
+        "proxy_server_probe_jitter_seconds",
+        "Mean difference between consecutive probe delays in the last round",
+        |s| s
+            .server
+            .status_snapshot()
+            .probe
+            .map(|p| p.jitter.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_probe_loss_ratio",
+        "Ratio of probes failed in the last round",
+        |s| s.server.status_snapshot().probe.map(|p| p.loss())
+    );
+    server_gauge!(
+        "proxy_server_circuit_breaker_state",
+        "State of circuit breaker, 0 for closed, 1 for half-open, 2 for open",
+        |s| Some(match s.server.status_snapshot().breaker.state() {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        })
+    );
+    server_gauge!(
+        "proxy_server_connect_timeouts_total",
+        "Current total number of timeouts on TCP connecting",
+        |s| Some(s.server.status_snapshot().timeouts.connect)
+    );
+    server_gauge!(
+        "proxy_server_handshake_timeouts_total",
+        "Current total number of timeouts on proxy handshaking",
+        |s| Some(s.server.status_snapshot().timeouts.handshake)
+    );
+    server_gauge!(
+        "proxy_server_response_timeouts_total",
+        "Current total number of timeouts on waiting for the first response",
+        |s| Some(s.server.status_snapshot().timeouts.response)
+    );
+    server_gauge!(
+        "proxy_server_timeout_closes_total",
+        "Current total number of connections timed out by TCP keepalive or retransmission",
+        |s| Some(s.server.status_snapshot().closes.timed_out)
+    );
+    server_gauge!(
+        "proxy_server_idle_closes_total",
+        "Current total number of connections closed on idle timeout",
+        |s| Some(s.server.status_snapshot().closes.idle_timeout)
+    );
+    server_gauge!(
+        "proxy_server_lifetime_closes_total",
+        "Current total number of connections closed on reaching max lifetime",
+        |s| Some(s.server.status_snapshot().closes.max_lifetime)
+    );
+    server_gauge!(
+        "proxy_server_tier",
+        "Priority tier of server, 0 is the highest",
+        |s| Some(s.server.tier())
+    );
     server_gauge!(
         "proxy_server_score",
         "Score of server based on the last DNS query test",
         |s| s.server.status_snapshot().score
     );
 
+    new_metric(
+        &mut buf,
+        "redirect_loops_total",
+        "counter",
+        "Current total number of connections rejected for redirect loop",
+    );
+    writeln!(
+        &mut buf,
+        "moproxy_redirect_loops_total {}",
+        loop_guard::loops_detected()
+    )
+    .unwrap();
+
+    new_metric(
+        &mut buf,
+        "top_server_switches_total",
+        "counter",
+        "Current total number of times the top server changed",
+    );
+    writeln!(
+        &mut buf,
+        "moproxy_top_server_switches_total {}",
+        monitor.top_switches()
+    )
+    .unwrap();
+
+    new_metric(
+        &mut buf,
+        "inbound_handshake_timeouts_total",
+        "counter",
+        "Current total number of clients dropped for timeout on SOCKSv5 handshaking",
+    );
+    writeln!(
+        &mut buf,
+        "moproxy_inbound_handshake_timeouts_total {}",
+        client::inbound_timeouts()
+    )
+    .unwrap();
+
     Response::builder()
         .header("Content-Type", "text/plain; charset=utf-8")
         .body(buf.into())
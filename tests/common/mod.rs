@@ -0,0 +1,27 @@
+//! Helpers shared by integration tests.
+#![allow(dead_code)]
+use moproxy::proxy::{ProxyProto, ProxyServer, ServerAddr};
+use std::time::Duration;
+
+/// A server speaking `proto` at `addr`, with one second of max wait.
+pub fn server<A: Into<ServerAddr>>(addr: A, proto: ProxyProto) -> ProxyServer {
+    server_with(addr, proto, None, Duration::from_secs(1))
+}
+
+/// A server speaking `proto` at `addr`, with `tag` and `max_wait`.
+pub fn server_with<A: Into<ServerAddr>>(
+    addr: A,
+    proto: ProxyProto,
+    tag: Option<&str>,
+    max_wait: Duration,
+) -> ProxyServer {
+    ProxyServer::new(
+        addr.into(),
+        proto,
+        "127.0.0.1:53".parse().unwrap(),
+        max_wait,
+        None,
+        tag,
+        None,
+    )
+}
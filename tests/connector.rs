@@ -0,0 +1,67 @@
+mod common;
+
+use bytes::Bytes;
+use moproxy::proxy::{
+    connector::{build_connector, register_connector, ConnectFuture, Connector},
+    Address, Destination, ProxyProto,
+};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Connect to the destination directly.
+struct DirectConnector;
+
+impl Connector for DirectConnector {
+    fn connect<'a>(&'a self, dest: &'a Destination, data: Option<Bytes>) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let addr = match dest.host {
+                Address::Ip(ip) => SocketAddr::new(ip, dest.port),
+                Address::Domain(_) => panic!("domain name is not supported"),
+            };
+            let mut stream = TcpStream::connect(addr).await?;
+            if let Some(data) = data {
+                stream.write_all(&data).await?;
+            }
+            Ok(Box::new(stream) as Box<_>)
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_custom_connector() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"early-payload");
+        stream.write_all(b"response").await.unwrap();
+    });
+
+    register_connector("Test-Direct", |_, props| {
+        assert_eq!(props.get("foo").map(|s| s.as_str()), Some("bar"));
+        Ok(Arc::new(DirectConnector))
+    });
+    let mut props = HashMap::new();
+    props.insert("foo".to_string(), "bar".to_string());
+    let server_addr = "127.0.0.1:1".parse().unwrap();
+    let connector = build_connector("test-direct", &server_addr, &props)
+        .expect("connector not registered")
+        .unwrap();
+    assert!(build_connector("not-exist", &server_addr, &props).is_none());
+
+    let server = common::server(server_addr, ProxyProto::custom("test-direct", connector));
+    let mut stream = server
+        .connect(&addr.into(), Some(b"early-payload"))
+        .await
+        .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use moproxy::{
+    client::{Connectable, Hedge, NewClient},
+    proxy::{ProxyProto, ProxyServer},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{delay_for, Instant},
+};
+
+/// HTTP proxy that replies 200 to any request after `delay`.
+async fn start_proxy(tag: &str, delay: Duration) -> Arc<ProxyServer> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(buf[..n].starts_with(b"CONNECT "));
+                delay_for(delay).await;
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                let _ = stream.read(&mut buf).await;
+            });
+        }
+    });
+    let server = common::server_with(
+        addr,
+        ProxyProto::http(false),
+        Some(tag),
+        Duration::from_secs(2),
+    );
+    Arc::new(server)
+}
+
+async fn new_client(servers: Vec<Arc<ProxyServer>>) -> NewClient<TcpStream> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let left = TcpStream::connect(addr).await.unwrap();
+    tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        delay_for(Duration::from_secs(5)).await;
+    });
+    let dest = "127.0.0.1:80".parse().unwrap();
+    NewClient::new(left, addr, dest, servers, addr.port())
+}
+
+#[tokio::test]
+async fn test_hedge() {
+    let slow = start_proxy("slow", Duration::from_millis(1000)).await;
+    let fast = start_proxy("fast", Duration::from_millis(0)).await;
+    let servers = vec![slow.clone(), fast.clone()];
+
+    // without hedging, wait for the slow one
+    let client = new_client(servers.clone()).await;
+    client.connect_server(0).await.unwrap();
+    assert!(slow.handshake_p90().unwrap() >= Duration::from_millis(1000));
+    assert!(fast.handshake_p90().is_none());
+
+    let now = Instant::now();
+    let mut client = new_client(servers).await;
+    client.set_hedge(Some(Hedge::After(Duration::from_millis(100))));
+    client.connect_server(0).await.unwrap();
+    assert!(fast.handshake_p90().is_some());
+    assert!(now.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn test_parse_hedge() {
+    assert_eq!("auto".parse(), Ok(Hedge::Auto));
+    assert_eq!("250".parse(), Ok(Hedge::After(Duration::from_millis(250))));
+    assert!("1s".parse::<Hedge>().is_err());
+}
@@ -0,0 +1,63 @@
+use moproxy::proxy::http::handshake;
+use std::net::SocketAddr;
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn test_http_connect() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"CONNECT 192.0.2.1:443 HTTP/1.1\r\n"));
+        assert!(buf[..n].ends_with(b"\r\n\r\n"));
+        // response header followed by data in the same segment
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nresponse")
+            .await
+            .unwrap();
+
+        stream.read(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"early-payload"));
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    handshake(&mut stream, &dest, Some(b"early-payload"), false)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_http_connect_without_peek() {
+    use tokio::net::UnixStream;
+
+    let (mut stream, mut server) = UnixStream::pair().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 128];
+        let n = server.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"CONNECT example.com:80 HTTP/1.1\r\n"));
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nX-Foo: bar\r\n\r\nresponse")
+            .await
+            .unwrap();
+    });
+
+    let dest = ("example.com", 80).into();
+    handshake(&mut stream, &dest, None::<&[u8]>, false)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+}
@@ -0,0 +1,309 @@
+#![cfg(feature = "http2")]
+mod common;
+
+use bytes::Bytes;
+use http::{Method, Response, StatusCode};
+use moproxy::proxy::{http2::Http2Config, ProxyProto, ProxyServer, Timeouts};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+async fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header).await.unwrap();
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.unwrap();
+    (header[3], header[4], id, payload)
+}
+
+async fn write_frame(stream: &mut TcpStream, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await.unwrap();
+}
+
+/// Read frames until one of type `kind`, answering PINGs on the way.
+async fn expect_frame(stream: &mut TcpStream, kind: u8) -> (u8, u32, Vec<u8>) {
+    loop {
+        let (t, flags, id, payload) = read_frame(stream).await;
+        if t == kind {
+            return (flags, id, payload);
+        }
+        if t == 0x6 && flags & 0x1 == 0 {
+            write_frame(stream, 0x6, 0x1, 0, &payload).await;
+        }
+    }
+}
+
+/// Minimal HTTP/2 server: accept a connection, send SETTINGS, read the
+/// first request and reply 200.
+async fn accept_raw(
+    listener: &mut TcpListener,
+    settings: &[(u16, u32)],
+) -> (TcpStream, u32, Headers) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut preface = [0u8; 24];
+    stream.read_exact(&mut preface).await.unwrap();
+    assert_eq!(&preface, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    let mut payload = Vec::new();
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    write_frame(&mut stream, 0x4, 0, 0, &payload).await;
+    let (flags, id, block) = expect_frame(&mut stream, 0x1).await;
+    assert_eq!(flags & 0x4, 0x4); // END_HEADERS
+    let headers = hpack::Decoder::new().decode(&block).unwrap();
+    write_frame(&mut stream, 0x1, 0x4, id, &[0x88]).await; // :status 200
+    (stream, id, headers)
+}
+
+fn get_header<'a>(headers: &'a Headers, name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name.as_bytes())
+        .map(|(_, v)| v.as_slice())
+}
+
+fn http2_server(addr: SocketAddr) -> ProxyServer {
+    common::server(
+        addr,
+        ProxyProto::http2(Http2Config {
+            tls: false,
+            host: None,
+        }),
+    )
+}
+
+#[tokio::test]
+async fn test_http2_connect() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = h2_02::server::handshake(stream).await.unwrap();
+        while let Some(request) = conn.accept().await {
+            let (request, mut respond) = request.unwrap();
+            assert_eq!(request.method(), Method::CONNECT);
+            let dest = request.uri().authority().unwrap().to_string();
+            let mut body = request.into_body();
+            let response = Response::builder().status(StatusCode::OK).body(()).unwrap();
+            let mut send = respond.send_response(response, false).unwrap();
+            tokio::spawn(async move {
+                // echo with destination prefixed
+                send.send_data(Bytes::from(dest), false).unwrap();
+                while let Some(data) = body.data().await {
+                    let data = data.unwrap();
+                    body.flow_control().release_capacity(data.len()).unwrap();
+                    send.send_data(data, false).unwrap();
+                }
+                send.send_data(Bytes::new(), true).unwrap();
+            });
+        }
+    });
+
+    let config = Http2Config {
+        tls: false,
+        host: None,
+    };
+    let server = common::server(addr, ProxyProto::http2(config));
+    for port in [80, 443].iter() {
+        let dest = "192.0.2.1:0"
+            .parse::<SocketAddr>()
+            .map(|mut addr| {
+                addr.set_port(*port);
+                addr
+            })
+            .unwrap()
+            .into();
+        let mut stream = server.connect(&dest, Some(b"ping")).await.unwrap();
+        let expected = format!("192.0.2.1:{}ping", port);
+        let mut buf = vec![0u8; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected.as_bytes());
+
+        stream.write_all(b"pong").await.unwrap();
+        AsyncWriteExt::shutdown(&mut stream).await.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+        assert_eq!(server.status_snapshot().mux.unwrap().streams, 1);
+    }
+    let mux = server.status_snapshot().mux.unwrap();
+    assert_eq!(mux.streams, 0);
+    assert_eq!(mux.connections, 1);
+    assert!(mux.connected);
+}
+
+#[tokio::test]
+async fn test_http2_flow_control() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = h2_02::server::handshake(stream).await.unwrap();
+        let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+        tokio::spawn(async move { while conn.accept().await.is_some() {} });
+        let mut body = request.into_body();
+        let response = Response::builder().status(StatusCode::OK).body(()).unwrap();
+        let mut send = respond.send_response(response, false).unwrap();
+        let mut total = 0;
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            total += data.len();
+            body.flow_control().release_capacity(data.len()).unwrap();
+        }
+        // reply with the same amount of data
+        let chunk = Bytes::from(vec![0u8; 16 * 1024]);
+        while total > 0 {
+            let n = total.min(chunk.len());
+            send.reserve_capacity(n);
+            let capacity = futures::future::poll_fn(|cx| send.poll_capacity(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let n = n.min(capacity);
+            send.send_data(chunk.slice(..n), false).unwrap();
+            total -= n;
+        }
+        send.send_data(Bytes::new(), true).unwrap();
+    });
+
+    let server = common::server(
+        addr,
+        ProxyProto::http2(Http2Config {
+            tls: false,
+            host: None,
+        }),
+    );
+    let dest = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+    let mut stream = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let data = vec![1u8; 5 * 1024 * 1024];
+    stream.write_all(&data).await.unwrap();
+    AsyncWriteExt::shutdown(&mut stream).await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf.len(), data.len());
+}
+
+#[tokio::test]
+async fn test_http2_extended_connect() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        // SETTINGS_ENABLE_CONNECT_PROTOCOL
+        let (mut stream, id, headers) = accept_raw(&mut listener, &[(0x8, 1)]).await;
+        write_frame(&mut stream, 0x0, 0, id, b"hello").await;
+        (stream, headers)
+    });
+
+    let proxy = http2_server(addr);
+    let dest = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    let mut stream = proxy.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let (_stream, headers) = server.await.unwrap();
+    assert_eq!(get_header(&headers, ":method"), Some(&b"CONNECT"[..]));
+    assert_eq!(get_header(&headers, ":protocol"), Some(&b"connect-tcp"[..]));
+    assert_eq!(get_header(&headers, ":scheme"), Some(&b"http"[..]));
+    let authority = addr.to_string();
+    assert_eq!(
+        get_header(&headers, ":authority"),
+        Some(authority.as_bytes())
+    );
+    assert_eq!(
+        get_header(&headers, ":path"),
+        Some(&b"/.well-known/masque/tcp/192.0.2.1/443/"[..])
+    );
+}
+
+#[tokio::test]
+async fn test_http2_zero_stream_window_update() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (mut stream, id, headers) = accept_raw(&mut listener, &[]).await;
+        // plain CONNECT without the setting
+        assert_eq!(get_header(&headers, ":protocol"), None);
+        assert_eq!(
+            get_header(&headers, ":authority"),
+            Some(&b"192.0.2.1:80"[..])
+        );
+        write_frame(&mut stream, 0x8, 0, id, &0u32.to_be_bytes()).await;
+        // h2 treats it as a connection error
+        let (_, id, payload) = expect_frame(&mut stream, 0x7).await;
+        assert_eq!(id, 0);
+        assert_eq!(payload[4..], 1u32.to_be_bytes()); // PROTOCOL_ERROR
+    });
+
+    let proxy = http2_server(addr);
+    let dest = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+    let mut stream = proxy.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = [0u8; 16];
+    assert!(stream.read(&mut buf).await.is_err());
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_http2_zero_connection_window_update() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (mut stream, _, _) = accept_raw(&mut listener, &[]).await;
+        write_frame(&mut stream, 0x8, 0, 0, &0u32.to_be_bytes()).await;
+        let (_, id, payload) = expect_frame(&mut stream, 0x7).await;
+        assert_eq!(id, 0);
+        assert_eq!(payload[4..], 1u32.to_be_bytes()); // PROTOCOL_ERROR
+    });
+
+    let proxy = http2_server(addr);
+    let dest = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+    let mut stream = proxy.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = [0u8; 16];
+    assert!(stream.read(&mut buf).await.is_err());
+    server.await.unwrap();
+    assert!(!proxy.status_snapshot().mux.unwrap().connected);
+}
+
+#[tokio::test]
+async fn test_http2_handshake_timeout() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        tokio::time::delay_for(Duration::from_secs(5)).await;
+    });
+
+    let proxy = http2_server(addr);
+    proxy.set_timeouts(Timeouts {
+        connect: Some(Duration::from_millis(100)),
+        handshake: Some(Duration::from_millis(100)),
+        ..Default::default()
+    });
+    let dest = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+    let err = proxy
+        .connect_within(&dest, None::<&[u8]>)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    // connected but no response from the server
+    let counts = proxy.status_snapshot().timeouts;
+    assert_eq!((counts.connect, counts.handshake), (0, 1));
+}
@@ -0,0 +1,51 @@
+use moproxy::{
+    client::NewClient,
+    monitor::Monitor,
+    proxy::{
+        loop_guard,
+        sockopt::{self, SocketOptions},
+    },
+};
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::{
+    self,
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+/// Send a SOCKSv5 CONNECT request to 192.0.2.1:80.
+async fn socks5_connect(stream: &mut TcpStream) {
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    stream
+        .write_all(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80])
+        .await
+        .unwrap();
+}
+
+async fn accept(listener: &mut TcpListener) -> io::Result<NewClient> {
+    let servers = Monitor::new(vec![], None).snapshot();
+    let (sock, _) = listener.accept().await.unwrap();
+    NewClient::from_socket(sock, &servers, Duration::from_secs(5)).await
+}
+
+#[tokio::test]
+async fn test_reject_loop() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+
+    // Made by ourselves, as if a proxy server redirected back to us.
+    let mut stream = sockopt::connect(&addr, &SocketOptions::default())
+        .await
+        .unwrap();
+    socks5_connect(&mut stream).await;
+    let loops = loop_guard::loops_detected();
+    let err = accept(&mut listener).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(loop_guard::loops_detected(), loops + 1);
+
+    // Made by others.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    socks5_connect(&mut stream).await;
+    let client = accept(&mut listener).await.unwrap();
+    assert_eq!(client.dest, "192.0.2.1:80".parse().unwrap());
+}
@@ -0,0 +1,205 @@
+#![cfg(feature = "mux")]
+mod common;
+
+#[cfg(feature = "tls")]
+use moproxy::{mux::pool::MuxTlsConfig, proxy::tls};
+use moproxy::{
+    mux::{self, policy::DestPolicy, server::MuxServerConfig},
+    proxy::{loop_guard, sockopt::SocketOptions, ProxyProto, ProxyServer},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Relay with `opts` on its outgoing sockets, allowed to connect to
+/// loopback addresses.
+async fn start_relay(secret: Option<&str>, opts: SocketOptions) -> ProxyServer {
+    let proto = ProxyProto::moproxy(2, secret, None);
+    start_relay_with(relay_config(), opts, proto).await
+}
+
+fn relay_config() -> MuxServerConfig {
+    let mut policy = DestPolicy::default();
+    policy.allow("127.0.0.0/8".parse().unwrap());
+    MuxServerConfig {
+        secret: b"secret"[..].into(),
+        policy,
+        ..Default::default()
+    }
+}
+
+async fn start_relay_with(
+    config: MuxServerConfig,
+    opts: SocketOptions,
+    proto: ProxyProto,
+) -> ProxyServer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let direct = Arc::new(ProxyServer::direct(Duration::from_secs(1)));
+    direct.set_socket_options(opts);
+    tokio::spawn(mux::server::run_server(listener, direct, config));
+    common::server(addr, proto)
+}
+
+#[tokio::test]
+async fn test_mux_relay() {
+    // echo server as destination
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap().into();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    });
+
+    let server = start_relay(Some("secret"), Default::default()).await;
+    let mut first = server.connect(&dest, Some(b"ping")).await.unwrap();
+    let mut second = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = [0u8; 4];
+    first.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let data = vec![7u8; 1024 * 1024];
+    second.write_all(&data).await.unwrap();
+    AsyncWriteExt::shutdown(&mut second).await.unwrap();
+    let mut buf = Vec::new();
+    second.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, data);
+
+    let mux = server.status_snapshot().mux.unwrap();
+    assert!(mux.connected);
+    assert_eq!(mux.streams, 2);
+    assert!(mux.connections <= 2);
+    drop(first);
+    drop(second);
+    assert_eq!(server.status_snapshot().mux.unwrap().streams, 0);
+}
+
+#[tokio::test]
+async fn test_mux_unreachable() {
+    // bind then drop to get a closed port
+    let dest = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .into();
+    let server = start_relay(Some("secret"), Default::default()).await;
+    let mut stream = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = Vec::new();
+    assert!(stream.read_to_end(&mut buf).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mux_wrong_secret() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap().into();
+    tokio::spawn(async move {
+        let _ = listener.accept().await;
+        panic!("should not connect to destination");
+    });
+    for secret in [None, Some("wrong")].iter() {
+        let server = start_relay(*secret, Default::default()).await;
+        // the stream is opened without waiting for the server
+        let mut stream = server.connect(&dest, Some(b"ping")).await.unwrap();
+        let mut buf = Vec::new();
+        assert!(stream.read_to_end(&mut buf).await.is_err());
+        assert!(buf.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_mux_denied() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let _ = listener.accept().await;
+        panic!("should not connect to denied destination");
+    });
+    let config = MuxServerConfig {
+        policy: DestPolicy::default(),
+        ..relay_config()
+    };
+    let proto = ProxyProto::moproxy(2, Some("secret"), None);
+    let server = start_relay_with(config, Default::default(), proto).await;
+    for dest in [addr.into(), ("localhost", addr.port()).into()].iter() {
+        let mut stream = server.connect(dest, Some(b"ping")).await.unwrap();
+        let mut buf = Vec::new();
+        assert!(stream.read_to_end(&mut buf).await.is_err());
+        assert!(buf.is_empty());
+    }
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_mux_tls() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap().into();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+    });
+    let cert = include_bytes!("data/mux-cert.pem");
+    let key = include_bytes!("data/mux-key.pem");
+    let config = MuxServerConfig {
+        tls: Some(tls::acceptor(cert, key).unwrap()),
+        ..relay_config()
+    };
+    let tls = MuxTlsConfig {
+        host: None,
+        ca: Some(cert[..].into()),
+    };
+    let proto = ProxyProto::moproxy(2, Some("secret"), Some(tls));
+    let server = start_relay_with(config.clone(), Default::default(), proto).await;
+    let mut stream = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"pong");
+
+    // the secret is never sent without TLS
+    let proto = ProxyProto::moproxy(2, Some("secret"), None);
+    let server = start_relay_with(config, Default::default(), proto).await;
+    let mut stream = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let mut buf = Vec::new();
+    assert!(stream.read_to_end(&mut buf).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mux_loop() {
+    // destination is one of our own listeners
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    loop_guard::add_listen_addr(addr);
+    tokio::spawn(async move {
+        let _ = listener.accept().await;
+        panic!("should not connect to our own listener");
+    });
+    let server = start_relay(Some("secret"), Default::default()).await;
+    let mut stream = server.connect(&addr.into(), Some(b"ping")).await.unwrap();
+    let mut buf = Vec::new();
+    assert!(stream.read_to_end(&mut buf).await.is_err());
+    assert!(buf.is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_mux_socket_options() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap().into();
+    let opts = SocketOptions {
+        bind_addr: Some("127.0.0.2".parse().unwrap()),
+        ..Default::default()
+    };
+    let server = start_relay(Some("secret"), opts).await;
+    let _stream = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use bytes::Bytes;
+use moproxy::{
+    monitor::probe::Probe,
+    proxy::{
+        connector::{ConnectFuture, Connector},
+        Address, Destination, ProxyProto, ProxyServer,
+    },
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Connect to the destination directly.
+struct DirectConnector;
+
+impl Connector for DirectConnector {
+    fn connect<'a>(&'a self, dest: &'a Destination, data: Option<Bytes>) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let addr = match dest.host {
+                Address::Ip(ip) => SocketAddr::new(ip, dest.port),
+                Address::Domain(_) => panic!("domain name is not supported"),
+            };
+            let mut stream = TcpStream::connect(addr).await?;
+            if let Some(data) = data {
+                stream.write_all(&data).await?;
+            }
+            Ok(Box::new(stream) as Box<_>)
+        })
+    }
+}
+
+fn direct_server() -> ProxyServer {
+    common::server(
+        "127.0.0.1:1".parse::<SocketAddr>().unwrap(),
+        ProxyProto::custom("direct", Arc::new(DirectConnector)),
+    )
+}
+
+/// HTTP server that responses `status` to any request.
+async fn start_http(status: &'static str) -> SocketAddr {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"GET /generate_204 HTTP/1.1\r\n"));
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_http_probe() {
+    let server = direct_server();
+    let addr = start_http("204 No Content").await;
+    let probe: Probe = format!("http http://{}/generate_204 204", addr)
+        .parse()
+        .unwrap();
+    probe.run(&server).await.unwrap();
+
+    let probe: Probe = format!("http http://{}/generate_204", addr)
+        .parse()
+        .unwrap();
+    assert!(probe.run(&server).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tcp_probe() {
+    let server = direct_server();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let probe: Probe = format!("tcp {}", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    probe.run(&server).await.unwrap();
+    drop(listener);
+    assert!(probe.run(&server).await.is_err());
+}
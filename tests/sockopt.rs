@@ -0,0 +1,77 @@
+#![cfg(target_os = "linux")]
+use moproxy::proxy::sockopt::{self, Keepalive, SocketOptions};
+use std::{mem, os::unix::io::AsRawFd, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+fn get_int_opt<F: AsRawFd>(fd: &F, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(ret, 0);
+    value
+}
+
+#[tokio::test]
+async fn test_socket_options() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let opts = SocketOptions {
+        fast_open: true,
+        notsent_lowat: Some(16384),
+        tos: Some(46 << 2),
+        keepalive: Some(Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Some(Duration::from_secs(10)),
+            count: Some(3),
+        }),
+        ..Default::default()
+    };
+    let mut stream = sockopt::connect(&addr, &opts).await.unwrap();
+    let opt = |level, name| get_int_opt(&stream, level, name);
+    assert_eq!(opt(libc::IPPROTO_TCP, libc::TCP_NOTSENT_LOWAT), 16384);
+    assert_eq!(opt(libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+    assert_eq!(opt(libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+    assert_eq!(opt(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 60);
+    assert_eq!(opt(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 10);
+    assert_eq!(opt(libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 3);
+
+    // data written before handshaking completes should go through
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_bind_address() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let opts = SocketOptions {
+        bind_addr: Some("127.0.0.2".parse().unwrap()),
+        ..Default::default()
+    };
+    let _stream = sockopt::connect(&addr, &opts).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip(), opts.bind_addr.unwrap());
+
+    let addr = "[::1]:1".parse().unwrap();
+    assert!(sockopt::connect(&addr, &opts).await.is_err());
+}
@@ -1,5 +1,10 @@
-use moproxy::proxy::socks5::handshake;
-use std::net::SocketAddr;
+mod common;
+
+use moproxy::{
+    client::{Connectable, NewClient},
+    proxy::{breaker::BreakerState, socks5::handshake, ProxyProto, ProxyServer},
+};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncWriteExt},
@@ -87,3 +92,57 @@ async fn test_socks5_ipv6() {
     let n = stream.read(&mut buf).await.unwrap();
     assert_eq!(&buf[..n], b"response");
 }
+
+/// SOCKSv5 server that replies `rep` to any request, or closes the
+/// connection before replying if `rep` is `None`.
+async fn start_server(rep: Option<u8>) -> Arc<ProxyServer> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 128];
+            stream.read_exact(&mut buf[..3]).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            stream.read(&mut buf).await.unwrap();
+            if let Some(rep) = rep {
+                stream
+                    .write_all(&[5, rep, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+    let server = common::server(addr, ProxyProto::socks5(false));
+    server.set_fail_threshold(1);
+    Arc::new(server)
+}
+
+async fn connect_via(server: &Arc<ProxyServer>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (left, _) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let dest = "192.0.2.1:80".parse().unwrap();
+    let client = NewClient::new(left.unwrap(), addr, dest, vec![server.clone()], 0);
+    assert!(client.connect_server(0).await.is_err());
+}
+
+#[tokio::test]
+async fn test_socks5_reply_error() {
+    // host unreachable
+    let server = start_server(Some(4)).await;
+    connect_via(&server).await;
+    assert_eq!(
+        server.status_snapshot().breaker.state(),
+        BreakerState::Closed
+    );
+
+    // general SOCKS server failure
+    let server = start_server(Some(1)).await;
+    connect_via(&server).await;
+    assert_eq!(server.status_snapshot().breaker.state(), BreakerState::Open);
+
+    let server = start_server(None).await;
+    connect_via(&server).await;
+    assert_eq!(server.status_snapshot().breaker.state(), BreakerState::Open);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use moproxy::{client::ConnectedClient, proxy::ProxyProto};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::delay_for,
+};
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (a, b) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (a.unwrap(), b.unwrap().0)
+}
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_pipe_tcp() {
+    let server = Arc::new(common::server_with(
+        "127.0.0.1:1".parse::<SocketAddr>().unwrap(),
+        ProxyProto::socks5(false),
+        Some("pipe"),
+        Duration::from_secs(1),
+    ));
+    let (client, left) = tcp_pair().await;
+    let (right, remote) = tcp_pair().await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let conn = ConnectedClient::new(left, right, dest, server.clone());
+    let serve = tokio::spawn(conn.serve());
+
+    // large enough to fill up pipes & socket buffers
+    let (tx, rx) = (data(16 << 20, 1), data(2 << 20, 2));
+    let echo = |mut stream: TcpStream, send: Vec<u8>, expect: Vec<u8>, delay| async move {
+        let (mut reader, mut writer) = stream.split();
+        let write = async {
+            writer.write_all(&send).await.unwrap();
+            // half close
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            // block the other side on writing
+            delay_for(delay).await;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert!(buf == expect);
+        };
+        tokio::join!(write, read);
+    };
+    tokio::join!(
+        echo(client, tx.clone(), rx.clone(), Duration::from_millis(0)),
+        echo(remote, rx.clone(), tx.clone(), Duration::from_millis(200)),
+    );
+    serve.await.unwrap().unwrap();
+
+    let traffic = server.traffic();
+    assert_eq!(traffic.tx_bytes, tx.len());
+    assert_eq!(traffic.rx_bytes, rx.len());
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use moproxy::{
+    client::{Connectable, ConnectedClient, NewClient},
+    proxy::{PipeOptions, ProxyProto, ProxyServer, Timeouts},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{delay_for, Instant},
+};
+
+/// HTTP proxy that accepts connections but never replies.
+async fn start_proxy() -> Arc<ProxyServer> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    let server = common::server_with(
+        addr,
+        ProxyProto::http(false),
+        Some("black-hole"),
+        Duration::from_secs(5),
+    );
+    Arc::new(server)
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let server = start_proxy().await;
+    server.set_timeouts(Timeouts {
+        handshake: Some(Duration::from_millis(100)),
+        ..Default::default()
+    });
+    assert_eq!(server.connect_timeout(), Duration::from_secs(5));
+    assert_eq!(server.handshake_timeout(), Duration::from_millis(100));
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let left = TcpStream::connect(addr).await.unwrap();
+    tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        delay_for(Duration::from_secs(5)).await;
+    });
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let client = NewClient::new(left, addr, dest, vec![server.clone()], addr.port());
+
+    let now = Instant::now();
+    assert!(client.connect_server(0).await.is_err());
+    assert!(now.elapsed() < Duration::from_secs(2));
+    let counts = server.status_snapshot().timeouts;
+    assert_eq!(counts.handshake, 1);
+    assert_eq!(counts.connect, 0);
+    assert_eq!(counts.response, 0);
+}
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (a, b) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (a.unwrap(), b.unwrap().0)
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let server = start_proxy().await;
+    server.set_pipe_options(PipeOptions {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    });
+    let (mut client, left) = tcp_pair().await;
+    let (right, mut remote) = tcp_pair().await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let conn = ConnectedClient::new(left, right, dest, server.clone());
+    let serve = tokio::spawn(conn.serve());
+
+    // keep it active for a while
+    let now = Instant::now();
+    for _ in 0..5 {
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        delay_for(Duration::from_millis(100)).await;
+    }
+    serve.await.unwrap().unwrap();
+    assert!(now.elapsed() > Duration::from_millis(700));
+    assert!(now.elapsed() < Duration::from_secs(2));
+    let status = server.status_snapshot();
+    assert_eq!(status.closes.idle_timeout, 1);
+    assert_eq!(status.closes.max_lifetime, 0);
+    assert_eq!(status.conn_alive, 0);
+}
+
+#[tokio::test]
+async fn test_max_lifetime() {
+    let server = start_proxy().await;
+    server.set_pipe_options(PipeOptions {
+        max_lifetime: Some(Duration::from_millis(300)),
+        ..Default::default()
+    });
+    let (mut client, left) = tcp_pair().await;
+    let (right, mut remote) = tcp_pair().await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let conn = ConnectedClient::new(left, right, dest, server.clone());
+    let serve = tokio::spawn(conn.serve());
+
+    // keep it active
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4];
+        while client.write_all(b"ping").await.is_ok() {
+            if remote.read_exact(&mut buf).await.is_err() {
+                break;
+            }
+            delay_for(Duration::from_millis(50)).await;
+        }
+    });
+    let now = Instant::now();
+    serve.await.unwrap().unwrap();
+    assert!(now.elapsed() < Duration::from_secs(1));
+    let status = server.status_snapshot();
+    assert_eq!(status.closes.max_lifetime, 1);
+    assert_eq!(status.closes.idle_timeout, 0);
+}
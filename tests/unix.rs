@@ -0,0 +1,66 @@
+#![cfg(unix)]
+mod common;
+
+use moproxy::{
+    client::ConnectedClient,
+    proxy::{ProxyProto, ServerAddr},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener},
+};
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (a, b) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (a.unwrap(), b.unwrap().0)
+}
+
+#[tokio::test]
+async fn test_unix_socks5() {
+    let path = std::env::temp_dir().join(format!("moproxy-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut listener = UnixListener::bind(&path).unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&[5, 1, 0], &buf[..3]); // ver 5, no auth
+        stream.write_all(&[5, 0]).await.unwrap(); // no auth
+        stream.read_exact(&mut buf[..10]).await.unwrap();
+        assert_eq!(&buf[..10], &[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 80])
+            .await
+            .unwrap();
+
+        // read until the client half closes, then reply
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        stream.write_all(b"response").await.unwrap();
+    });
+
+    let server = Arc::new(common::server_with(
+        ServerAddr::Unix(path.clone()),
+        ProxyProto::socks5(false),
+        Some("unix"),
+        Duration::from_secs(1),
+    ));
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let right = server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let (mut client, left) = tcp_pair().await;
+    let serve = tokio::spawn(ConnectedClient::new(left, right, dest, server.clone()).serve());
+
+    client.write_all(b"request").await.unwrap();
+    AsyncWriteExt::shutdown(&mut client).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"response");
+    serve.await.unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use moproxy::{
+    monitor::probe::Probe,
+    proxy::{sockopt::SocketOptions, ProxyProto, ProxyServer},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::broadcast,
+    time::{self, delay_for},
+};
+
+/// HTTP proxy that replies any request with 200 and then closes the
+/// connection. Connections without a request are closed once a message
+/// is sent to the returned sender.
+async fn start_proxy(accepted: Arc<AtomicUsize>) -> (ProxyServer, broadcast::Sender<()>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (close_idle, _) = broadcast::channel(1);
+    let close = close_idle.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut close = close.subscribe();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                tokio::select! {
+                    n = stream.read(&mut buf) => {
+                        let n = n.unwrap();
+                        assert!(buf[..n].starts_with(b"CONNECT "));
+                        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                    }
+                    _ = close.recv() => (),
+                }
+            });
+        }
+    });
+    let server = common::server(addr, ProxyProto::http(false));
+    (server, close_idle)
+}
+
+/// Wait until `accepted` reaches `n`.
+async fn wait_accepted(accepted: &AtomicUsize, n: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while accepted.load(Ordering::SeqCst) < n {
+        assert!(Instant::now() < deadline, "proxy not connected");
+        delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_warm_pool() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let (server, close_idle) = start_proxy(accepted.clone()).await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    server.set_warm_pool(2, Duration::from_secs(30));
+
+    server.fill_warm_pool().await;
+    assert_eq!(server.status_snapshot().warm_pool.unwrap().idle, 2);
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.idle, pool.hits, pool.misses), (1, 1, 0));
+
+    // idle connections closed by the proxy are dropped and replaced
+    wait_accepted(&accepted, 2).await;
+    close_idle.send(()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while accepted.load(Ordering::SeqCst) < 4 {
+        assert!(Instant::now() < deadline, "closed connection not dropped");
+        server.fill_warm_pool().await;
+        delay_for(Duration::from_millis(10)).await;
+    }
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.idle, pool.hits, pool.misses), (0, 3, 0));
+    assert_eq!(accepted.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_warm_pool_max_idle() {
+    time::pause();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let (server, _close_idle) = start_proxy(accepted.clone()).await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    server.set_warm_pool(1, Duration::from_secs(30));
+
+    server.fill_warm_pool().await;
+    time::advance(Duration::from_secs(31)).await;
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.hits, pool.misses), (0, 1));
+
+    server.fill_warm_pool().await;
+    time::advance(Duration::from_secs(29)).await;
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.hits, pool.misses), (1, 1));
+    time::resume();
+    wait_accepted(&accepted, 3).await;
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_warm_pool_fast_open() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let (server, _close_idle) = start_proxy(accepted.clone()).await;
+    let dest = "127.0.0.1:80".parse().unwrap();
+    server.set_warm_pool(2, Duration::from_secs(30));
+    server.set_socket_options(SocketOptions {
+        fast_open: true,
+        ..Default::default()
+    });
+
+    // sockets with fast open do not connect until written
+    assert_eq!(server.warm_pool_size(), 0);
+    server.fill_warm_pool().await;
+    server.connect(&dest, None::<&[u8]>).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.idle, pool.hits, pool.misses), (0, 0, 0));
+    wait_accepted(&accepted, 1).await;
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_warm_pool_probe() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let (server, _close_idle) = start_proxy(accepted.clone()).await;
+    server.set_warm_pool(2, Duration::from_secs(30));
+    server.fill_warm_pool().await;
+
+    // probes measure a new connection instead of a pooled one
+    let probe: Probe = "tcp 127.0.0.1:80".parse().unwrap();
+    probe.run(&server).await.unwrap();
+    let pool = server.status_snapshot().warm_pool.unwrap();
+    assert_eq!((pool.idle, pool.hits, pool.misses), (2, 0, 0));
+    wait_accepted(&accepted, 3).await;
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+}
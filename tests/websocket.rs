@@ -0,0 +1,163 @@
+#![cfg(feature = "websocket")]
+use moproxy::proxy::websocket::{handshake, WebSocketConfig};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+async fn read_masked_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[1] & 0x80, 0x80, "client frame must be masked");
+    let len = (header[1] & 0x7f) as usize;
+    assert!(len < 126);
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask).await.unwrap();
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.unwrap();
+    payload
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b ^= mask[i % 4]);
+    (header[0] & 0x0f, payload)
+}
+
+#[tokio::test]
+async fn test_websocket_tunnel() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("GET /tunnel HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: ws.example.com\r\n"));
+        assert!(request.contains("\r\nX-Token: secret\r\n"));
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let mut sha1 = Sha1::new();
+        sha1.update(key.as_bytes());
+        sha1.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            base64::encode(sha1.finalize())
+        );
+        // ping, then data follow the header in the same segment
+        let mut response = response.into_bytes();
+        response.extend_from_slice(&[0x89, 2, b'h', b'i']);
+        response.extend_from_slice(&[0x82, 8]);
+        response.extend_from_slice(b"response");
+        stream.write_all(&response).await.unwrap();
+
+        // SOCKSv5 request followed by early data
+        let (opcode, payload) = read_masked_frame(&mut stream).await;
+        assert_eq!(opcode, 0x2);
+        assert_eq!(
+            payload,
+            b"\x05\x01\x00\x01\xc0\x00\x02\x01\x01\xbbearly-payload".to_vec()
+        );
+        assert_eq!(read_masked_frame(&mut stream).await, (0xa, b"hi".to_vec()));
+        assert_eq!(
+            read_masked_frame(&mut stream).await,
+            (0x2, b"more".to_vec())
+        );
+        assert_eq!(read_masked_frame(&mut stream).await, (0x8, vec![]));
+        stream.write_all(&[0x88, 0]).await.unwrap();
+    });
+
+    let config = WebSocketConfig {
+        path: "/tunnel".into(),
+        host: None,
+        tls: false,
+        headers: vec![("X-Token".into(), "secret".into())],
+        dest_header: None,
+    };
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    let mut stream = handshake(
+        stream,
+        &config,
+        "ws.example.com",
+        &dest,
+        Some(&b"early-payload"[..]),
+    )
+    .await
+    .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+    stream.write_all(b"more").await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_websocket_close_echo() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let mut sha1 = Sha1::new();
+        sha1.update(key.as_bytes());
+        sha1.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            base64::encode(sha1.finalize())
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        let (opcode, _) = read_masked_frame(&mut stream).await;
+        assert_eq!(opcode, 0x2);
+
+        // data, then close with status 1001 (going away)
+        stream
+            .write_all(&[0x82, 3, b'b', b'y', b'e'])
+            .await
+            .unwrap();
+        stream.write_all(&[0x88, 2, 0x03, 0xe9]).await.unwrap();
+        read_masked_frame(&mut stream).await
+    });
+
+    let config = WebSocketConfig {
+        path: "/".into(),
+        host: None,
+        tls: false,
+        headers: vec![],
+        dest_header: None,
+    };
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    let mut stream = handshake(stream, &config, "ws.example.com", &dest, None)
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"bye");
+    assert_eq!(server.await.unwrap(), (0x8, vec![0x03, 0xe9]));
+    // no more data after close
+    assert!(stream.write_all(b"more").await.is_err());
+}